@@ -0,0 +1,122 @@
+//! Arrays of any length.
+//!
+//! serde only implements `Serialize` and `Deserialize` for arrays of up to 32
+//! elements, so longer ones, like a 64-byte key, need a helper. Pointing a
+//! field's `serialize_with` and `deserialize_with` at `serialize` and
+//! `deserialize` here writes a `[T; N]` as an array of N elements, the same as
+//! serde does for shorter ones, and checks that exactly N come back.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::convert::TryInto;
+use std::marker::PhantomData;
+
+use alloc::vec::Vec;
+
+use serde;
+
+struct ArrayVisitor<T, const N: usize>(PhantomData<T>);
+
+impl<T, const N: usize> serde::de::Visitor for ArrayVisitor<T, N>
+    where T: serde::Deserialize {
+    type Value = [T; N];
+
+    fn visit_seq<V>(&mut self, mut visitor: V) -> Result<[T; N], V::Error>
+        where V: serde::de::SeqVisitor {
+        let mut items = Vec::with_capacity(N);
+
+        while items.len() < N {
+            match try!(visitor.visit()) {
+                Some(item) => items.push(item),
+                None => return Err(serde::de::Error::invalid_length(items.len()))
+            }
+        }
+
+        try!(visitor.end());
+
+        match items.try_into() {
+            Ok(array) => Ok(array),
+            Err(_) => Err(serde::de::Error::invalid_length(N))
+        }
+    }
+}
+
+/// Serialize an array as a fixed-size sequence, for use with `#[serde(serialize_with)]`.
+pub fn serialize<T, S, const N: usize>(array: &[T; N], serializer: &mut S) -> Result<(), S::Error>
+    where T: serde::Serialize, S: serde::Serializer {
+    let mut state = try!(serializer.serialize_seq_fixed_size(N));
+    for item in array.iter() {
+        try!(serializer.serialize_seq_elt(&mut state, item));
+    }
+    serializer.serialize_seq_end(state)
+}
+
+/// Deserialize an array of exactly N elements, for use with `#[serde(deserialize_with)]`.
+pub fn deserialize<T, D, const N: usize>(deserializer: &mut D) -> Result<[T; N], D::Error>
+    where T: serde::Deserialize, D: serde::Deserializer {
+    deserializer.deserialize_seq_fixed_size(N, ArrayVisitor(PhantomData))
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+
+    use error::Reason;
+    use test_types::{KeyPair, Table};
+
+    fn key() -> [u8; 64] {
+        let mut key = [0; 64];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+        key
+    }
+
+    #[test]
+    fn u8_64_test() {
+        let value = KeyPair { id: 1, key: key() };
+        let bytes = ::to_bytes(&value).unwrap();
+
+        // fixmap(2), "id": 1, "key", then an array16 of 64
+        assert_eq!(&bytes[..12], &[0x82, 0xa2, 0x69, 0x64, 0x01, 0xa3, 0x6b, 0x65, 0x79,
+                                   0xdc, 0x00, 0x40]);
+
+        let decoded: KeyPair = ::from_bytes(bytes.as_slice()).unwrap();
+        assert_eq!(decoded, value);
+    }
+
+    #[test]
+    fn u32_16_test() {
+        let mut values = [0; 16];
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = 1 << (i * 2);
+        }
+
+        let value = Table { values: values };
+        let bytes = ::to_bytes(&value).unwrap();
+        assert_eq!(&bytes[..11], &[0x81, 0xa6, 0x76, 0x61, 0x6c, 0x75, 0x65, 0x73, 0xdc, 0x00, 0x10]);
+
+        let decoded: Table = ::from_bytes(bytes.as_slice()).unwrap();
+        assert_eq!(decoded, value);
+    }
+
+    // {"id": 1, "key": [0; len]}
+    fn key_pair_bytes(len: u8) -> Vec<u8> {
+        let mut bytes = vec![0x82, 0xa2, 0x69, 0x64, 0x01, 0xa3, 0x6b, 0x65, 0x79, 0xdc, 0x00, len];
+        bytes.extend(vec![0; len as usize]);
+        bytes
+    }
+
+    #[test]
+    fn length_mismatch_test() {
+        assert!(::from_bytes::<KeyPair>(key_pair_bytes(64).as_slice()).is_ok());
+
+        let result = ::from_bytes::<KeyPair>(key_pair_bytes(63).as_slice());
+        assert_eq!(result.unwrap_err().reason(), Reason::BadLength);
+
+        let result = ::from_bytes::<KeyPair>(key_pair_bytes(65).as_slice());
+        assert_eq!(result.unwrap_err().reason(), Reason::ExtraItems);
+    }
+}
@@ -0,0 +1,111 @@
+//! Bounded collections, behind the `arrayvec` feature.
+//!
+//! Without an allocator there is no `Vec` to decode an array into, but an
+//! `ArrayVec` holds up to a fixed number of elements inline. Pointing a
+//! field's `serialize_with` and `deserialize_with` at `serialize` and
+//! `deserialize` here writes an `ArrayVec<A>` as an array of its elements, and
+//! reads back any array that fits. corepack rejects a longer array with
+//! `Reason::LimitExceeded` as soon as it reads the header, before decoding any
+//! elements.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::marker::PhantomData;
+
+use arrayvec::{Array, ArrayVec};
+
+use serde;
+
+/// The tuple struct name that tells a Deserializer how many elements fit.
+pub const TOKEN: &'static str = "__corepack_ArrayVec";
+
+struct ArrayVecVisitor<A>(PhantomData<A>);
+
+impl<A> serde::de::Visitor for ArrayVecVisitor<A>
+    where A: Array, A::Item: serde::Deserialize {
+    type Value = ArrayVec<A>;
+
+    fn visit_seq<V>(&mut self, mut visitor: V) -> Result<ArrayVec<A>, V::Error>
+        where V: serde::de::SeqVisitor {
+        let mut items = ArrayVec::new();
+
+        while let Some(item) = try!(visitor.visit()) {
+            // other Deserializers don't check the length up front
+            if items.try_push(item).is_err() {
+                return Err(serde::de::Error::invalid_length(A::CAPACITY + 1));
+            }
+        }
+
+        try!(visitor.end());
+
+        Ok(items)
+    }
+}
+
+/// Serialize an ArrayVec as a sequence, for use with `#[serde(serialize_with)]`.
+pub fn serialize<A, S>(items: &ArrayVec<A>, serializer: &mut S) -> Result<(), S::Error>
+    where A: Array, A::Item: serde::Serialize, S: serde::Serializer {
+    let mut state = try!(serializer.serialize_seq(Some(items.len())));
+    for item in items.iter() {
+        try!(serializer.serialize_seq_elt(&mut state, item));
+    }
+    serializer.serialize_seq_end(state)
+}
+
+/// Deserialize an array of at most the ArrayVec's capacity, for use with
+/// `#[serde(deserialize_with)]`.
+pub fn deserialize<A, D>(deserializer: &mut D) -> Result<ArrayVec<A>, D::Error>
+    where A: Array, A::Item: serde::Deserialize, D: serde::Deserializer {
+    deserializer.deserialize_tuple_struct(TOKEN, A::CAPACITY, ArrayVecVisitor(PhantomData))
+}
+
+#[cfg(test)]
+mod test {
+    use arrayvec::ArrayVec;
+
+    use serde;
+
+    use error::{Error, Reason};
+
+    #[derive(Debug, PartialEq)]
+    struct Readings(ArrayVec<[u32; 4]>);
+
+    impl serde::Serialize for Readings {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+            where S: serde::Serializer {
+            super::serialize(&self.0, serializer)
+        }
+    }
+
+    impl serde::Deserialize for Readings {
+        fn deserialize<D>(deserializer: &mut D) -> Result<Readings, D::Error>
+            where D: serde::Deserializer {
+            super::deserialize(deserializer).map(Readings)
+        }
+    }
+
+    #[test]
+    fn fits_test() {
+        let value: Readings = ::from_bytes(&[0x93, 0x01, 0xcd, 0x01, 0x00, 0x03]).unwrap();
+        assert_eq!(&value.0[..], &[1, 256, 3]);
+        assert_eq!(::to_bytes(&value).unwrap(), &[0x93, 0x01, 0xcd, 0x01, 0x00, 0x03]);
+
+        let value: Readings = ::from_bytes(&[0x94, 0x01, 0x02, 0x03, 0x04]).unwrap();
+        assert_eq!(&value.0[..], &[1, 2, 3, 4]);
+
+        let value: Readings = ::from_bytes(&[0x90]).unwrap();
+        assert!(value.0.is_empty());
+    }
+
+    #[test]
+    fn too_long_test() {
+        let result: Result<Readings, Error> = ::from_bytes(&[0x95, 0x01, 0x02, 0x03, 0x04, 0x05]);
+        assert_eq!(result.unwrap_err().reason(), Reason::LimitExceeded);
+
+        // the elements are never read, so a long array is rejected just as fast
+        let result: Result<Readings, Error> = ::from_bytes(&[0xdd, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(result.unwrap_err().reason(), Reason::LimitExceeded);
+    }
+}
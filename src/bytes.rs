@@ -0,0 +1,201 @@
+//! Wrappers that force the bin family for byte data.
+//!
+//! serde hands a `Vec<u8>` or `&[u8]` to the Serializer as a sequence of
+//! individual `u8`s, and the Serializer cannot tell that apart from any other
+//! sequence, so a plain byte vector is written as an array of integers. That
+//! costs an extra byte for every element of 128 or more. Wrapping the data in
+//! `Bytes` or `ByteBuf`, or pointing a field's `serialize_with` and
+//! `deserialize_with` at `serialize` and `deserialize` here, writes it as bin
+//! instead.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::ops::Deref;
+
+use alloc::vec::Vec;
+
+use serde;
+
+/// A borrowed byte slice that always serializes as bin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bytes<'a>(pub &'a [u8]);
+
+/// An owned byte buffer that serializes as bin and deserializes from bin.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ByteBuf(pub Vec<u8>);
+
+struct ByteBufVisitor;
+
+impl<'a> Deref for Bytes<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl<'a> From<&'a [u8]> for Bytes<'a> {
+    fn from(bytes: &'a [u8]) -> Bytes<'a> {
+        Bytes(bytes)
+    }
+}
+
+impl<'a> serde::Serialize for Bytes<'a> {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+impl ByteBuf {
+    /// Borrow the buffer as a `Bytes`.
+    pub fn as_bytes(&self) -> Bytes {
+        Bytes(self.0.as_slice())
+    }
+}
+
+impl Deref for ByteBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+}
+
+impl From<Vec<u8>> for ByteBuf {
+    fn from(bytes: Vec<u8>) -> ByteBuf {
+        ByteBuf(bytes)
+    }
+}
+
+impl From<ByteBuf> for Vec<u8> {
+    fn from(buf: ByteBuf) -> Vec<u8> {
+        buf.0
+    }
+}
+
+impl serde::Serialize for ByteBuf {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer {
+        serializer.serialize_bytes(self.0.as_slice())
+    }
+}
+
+impl serde::de::Visitor for ByteBufVisitor {
+    type Value = ByteBuf;
+
+    fn visit_bytes<E>(&mut self, v: &[u8]) -> Result<ByteBuf, E>
+        where E: serde::de::Error {
+        Ok(ByteBuf(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(&mut self, v: Vec<u8>) -> Result<ByteBuf, E>
+        where E: serde::de::Error {
+        Ok(ByteBuf(v))
+    }
+}
+
+impl serde::Deserialize for ByteBuf {
+    fn deserialize<D>(deserializer: &mut D) -> Result<ByteBuf, D::Error>
+        where D: serde::Deserializer {
+        deserializer.deserialize_bytes(ByteBufVisitor)
+    }
+}
+
+/// Serialize a byte slice as bin, for use with `#[serde(serialize_with)]`.
+pub fn serialize<T, S>(bytes: &T, serializer: &mut S) -> Result<(), S::Error>
+    where T: AsRef<[u8]>, S: serde::Serializer {
+    serializer.serialize_bytes(bytes.as_ref())
+}
+
+/// Deserialize a byte vector from bin, for use with `#[serde(deserialize_with)]`.
+pub fn deserialize<D>(deserializer: &mut D) -> Result<Vec<u8>, D::Error>
+    where D: serde::Deserializer {
+    deserializer.deserialize_bytes(ByteBufVisitor).map(|buf| buf.0)
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+
+    use super::{Bytes, ByteBuf};
+
+    use ::test_types::{Packet, BinPacket};
+
+    #[test]
+    fn smallest_bin_test() {
+        // there is no fixbin, so even the smallest payloads take a bin8 header
+        assert_eq!(::to_bytes(Bytes(&[])).unwrap(), &[0xc4, 0x00]);
+        assert_eq!(::to_bytes(Bytes(&[0x2a])).unwrap(), &[0xc4, 0x01, 0x2a]);
+
+        let value: ByteBuf = ::from_bytes(&[0xc4, 0x01, 0x2a]).unwrap();
+        assert_eq!(value.0, &[0x2a]);
+        let value: ByteBuf = ::from_bytes(&[0xc4, 0x00]).unwrap();
+        assert!(value.0.is_empty());
+    }
+
+    #[test]
+    fn bin8_test() {
+        let data: Vec<u8> = (0..255).map(|x| x as u8).collect();
+        let bytes = ::to_bytes(Bytes(data.as_slice())).unwrap();
+
+        assert_eq!(&bytes[..2], &[0xc4, 0xff]);
+        assert_eq!(bytes.len(), 2 + 255);
+
+        let value: ByteBuf = ::from_bytes(bytes.as_slice()).unwrap();
+        assert_eq!(value.0, data);
+    }
+
+    #[test]
+    fn bin16_test() {
+        let data: Vec<u8> = (0..256).map(|x| x as u8).collect();
+        let bytes = ::to_bytes(ByteBuf(data.clone())).unwrap();
+
+        assert_eq!(&bytes[..3], &[0xc5, 0x01, 0x00]);
+        assert_eq!(bytes.len(), 3 + 256);
+
+        let value: ByteBuf = ::from_bytes(bytes.as_slice()).unwrap();
+        assert_eq!(value.0, data);
+    }
+
+    #[test]
+    fn size_comparison_test() {
+        let data: Vec<u8> = (0..255).map(|x| x as u8).collect();
+
+        // as an array, 128 of the elements need a uint8 marker
+        let array = ::to_bytes(&data).unwrap();
+        assert_eq!(&array[..3], &[0xdc, 0x00, 0xff]);
+        assert_eq!(array.len(), 3 + 127 + 128 * 2);
+
+        let bin = ::to_bytes(Bytes(data.as_slice())).unwrap();
+        assert_eq!(bin.len(), 2 + 255);
+        assert!(bin.len() < array.len());
+    }
+
+    #[test]
+    fn serialize_with_test() {
+        let data: Vec<u8> = (0..255).map(|x| x as u8).collect();
+
+        let plain = ::to_bytes(Packet { id: 1, payload: data.clone() }).unwrap();
+        let value = BinPacket { id: 1, payload: data.clone() };
+        let bin = ::to_bytes(&value).unwrap();
+
+        // fixmap(2), "id": 1, "payload", then the bin8 header where the
+        // array16 one would be
+        assert_eq!(&bin[..15], &[0x82, 0xa2, 0x69, 0x64, 0x01,
+                                 0xa7, 0x70, 0x61, 0x79, 0x6c, 0x6f, 0x61, 0x64,
+                                 0xc4, 0xff]);
+        assert_eq!(plain.len() - bin.len(), 127);
+
+        let decoded: BinPacket = ::from_bytes(bin.as_slice()).unwrap();
+        assert_eq!(decoded, value);
+    }
+
+    #[test]
+    fn str_rejected_test() {
+        let value: Result<ByteBuf, _> = ::from_bytes(&[0xa2, 0x68, 0x69]);
+        assert!(value.is_err());
+    }
+}
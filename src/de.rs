@@ -2,26 +2,215 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use collections::{String, Vec};
-
-use byteorder::{ByteOrder, BigEndian, LittleEndian};
+use alloc::boxed::Box;
+use alloc::collections::btree_set::BTreeSet;
+use alloc::string::String;
+use alloc::vec::Vec;
 
 use serde::de::value::ValueDeserializer;
 
 use serde;
 
+use bytes::Bytes;
 use defs::*;
 use error::*;
+use ser::{UnitEncoding, EnumEncoding};
+use value::Value;
 
 /// The corepack Deserializer struct. Contains a closure that should copy the
 /// next bytes availabel into the given byte buffer.
-pub struct Deserializer<F: FnMut(&mut [u8]) -> Result<(), Error>> {
-    input: F
+pub struct Deserializer<'o, F: FnMut(&mut [u8]) -> Result<(), Error>> {
+    input: F,
+    config: DeserializerConfig,
+    // bytes read while at least one RawMessage is being captured
+    record: Vec<u8>,
+    recording: usize,
+    // the most recent struct field name, picked up by the map it belongs to
+    last_key: KeyBuf,
+    // set while a map key is read, until its first str length
+    reading_key: bool,
+    // a marker read ahead by peek_type, not yet consumed
+    peeked: Option<u8>,
+    // bytes read from input so far
+    position: usize,
+    // how much of max_total_len has been used
+    total_len: usize,
+    // how many nested decodes this one is inside of
+    depth: usize,
+    observer: Option<Box<dyn FnMut(MsgpackType, usize) + 'o>>
+}
+
+/// The MessagePack type family of an encoded value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsgpackType {
+    /// A negative integer, or one encoded as signed.
+    Int,
+    /// A non-negative integer encoded as unsigned.
+    Uint,
+    /// A float32 or float64.
+    Float,
+    /// A UTF-8 string.
+    Str,
+    /// A byte array.
+    Bin,
+    /// An array of values.
+    Array,
+    /// A map of keys to values.
+    Map,
+    /// Nil.
+    Nil,
+    /// True or false.
+    Bool,
+    /// An application ext value.
+    Ext
+}
+
+/// Decodes the data of one application ext type into a Value.
+pub type ExtHandler = fn(&[u8]) -> Result<Value, Error>;
+
+// most ext types an ExtRegistry can hold handlers for
+const MAX_EXT_HANDLERS: usize = 8;
+
+// how deep handler output can go inside other handler output
+const MAX_NESTED_DEPTH: usize = 8;
+
+/// Handlers for application ext types, keyed by ext type id.
+///
+/// An ext value with a registered type is decoded by its handler, and the
+/// resulting Value is then deserialized in its place, into whatever type was
+/// asked for. Other ext values are left as they are.
+///
+/// The Value is deserialized with the same configuration as the input around
+/// it, and counts against the same `max_total_len`. Ext values inside it go
+/// through the registry again, up to a fixed depth, past which decoding fails
+/// with `Reason::LimitExceeded`. A handler returning its own type ends there.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtRegistry {
+    handlers: [Option<(i8, ExtHandler)>; MAX_EXT_HANDLERS]
+}
+
+/// Options for how a Deserializer reads its input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeserializerConfig {
+    /// Reject integers where a float is asked for, and floats where an integer
+    /// is asked for, rather than leaving the visitor to convert between them.
+    pub strict_types: bool,
+    /// Reject a FLOAT64 read into an f32 unless it narrows to exactly the same
+    /// number, rather than rounding it to the nearest f32. NaN is always
+    /// accepted. Fails with `Reason::PrecisionLoss`.
+    pub strict_float_precision: bool,
+    /// The form unit values and unit structs are expected in. Nil is always
+    /// accepted as well.
+    pub unit_encoding: UnitEncoding,
+    /// How enum variants are expected to be encoded.
+    pub enum_encoding: EnumEncoding,
+    /// Handlers for application ext types.
+    pub ext_registry: ExtRegistry,
+    /// How to read the raw family of the MessagePack format from before str and
+    /// bin were split.
+    pub legacy_raw: LegacyRaw,
+    /// What to do with a key that shows up more than once in the same map.
+    pub duplicate_keys: DuplicateKeys,
+    /// The longest str, in bytes, accepted as a map key or struct field name.
+    /// This is checked against the length prefix, before the key is read.
+    pub max_key_len: Option<usize>,
+    /// The longest ext data, in bytes, that will be read. This is checked
+    /// against the length prefix, before anything is allocated for the data.
+    pub max_ext_len: Option<usize>,
+    /// A budget for everything one decode reads into memory, summed over the
+    /// whole input: the bytes of every str, bin and ext data, struct field
+    /// names included, the elements of every array, and the keys and values of
+    /// every map. Each is charged from its length prefix before anything is
+    /// allocated for it, so many small collections can't add up to more than a
+    /// single large one could. Going over fails with `Reason::LimitExceeded`.
+    /// Skipped values aren't charged, and neither are the bytes `read_bin_to`
+    /// streams to a writer, as they are never held.
+    pub max_total_len: Option<usize>,
+    /// Read a struct field that is missing from its map as nil, if its type
+    /// takes nil, like a unit or a `PhantomData`, as well as an Option. This
+    /// matches output written with `SerializerConfig::compact_structs`. Without
+    /// it, only Option fields may be missing.
+    pub compact_structs: bool,
+    /// The name of a unit variant to decode unknown enum variants as.
+    ///
+    /// When an enum has a variant of this name, a variant index past the end of
+    /// the enum decodes as that variant instead of failing, and whatever the
+    /// unknown variant carried is skipped. This lets older readers get through
+    /// messages from writers that have added variants. Enums without a variant
+    /// of this name still reject unknown variants.
+    pub fallback_variant: Option<&'static str>
+}
+
+/// What a Deserializer does with duplicate keys in a map.
+///
+/// Keys are compared by their encoding, so `1` and `1.0` are different keys,
+/// and so are an integer written at full width and the same one written small.
+/// Keeping the first or rejecting duplicates means holding on to a copy of
+/// every key of the map being read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateKeys {
+    /// Hand every entry to the visitor, so the target decides. For the std
+    /// maps, later entries replace earlier ones. The serde derive rejects
+    /// duplicate struct fields. This is the default.
+    KeepLast,
+    /// Skip any entry whose key has already been seen in the map.
+    KeepFirst,
+    /// Fail with `Reason::DuplicateKey`.
+    Reject
+}
+
+/// How the raw family of the old MessagePack format is read.
+///
+/// Before str and bin were split, byte data of every kind was written as raw,
+/// using the markers that are now fixstr, str16 and str32. Raw data need not be
+/// UTF-8, so reading it from an old producer as str can fail. Since the markers
+/// are the same, a Deserializer can't tell the two formats apart, and reading
+/// legacy raw changes how modern str values are read as well. Struct field
+/// names are always read as str.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegacyRaw {
+    /// Read the markers as str, as the current format has them. This is the
+    /// default.
+    Off,
+    /// Hand raw values to the visitor as bytes. Targets that want a string
+    /// still get one, if the bytes are UTF-8.
+    Bytes,
+    /// Hand raw values to the visitor as strings, with any bytes that are not
+    /// UTF-8 replaced by U+FFFD.
+    Str
+}
+
+// the most elements a sequence's size hint will claim up front, so a bogus
+// length can't make the visitor preallocate more than the input could fill
+const MAX_SIZE_HINT: usize = 4096;
+
+struct SeqVisitor<'a, 'o, F: 'a + FnMut(&mut [u8]) -> Result<(), Error>> {
+    de: &'a mut Deserializer<'o, F>,
+    count: usize,
+    index: usize,
+    key: KeyBuf,
+    // the encoded keys of a map so far, when duplicates are looked for
+    seen: BTreeSet<Vec<u8>>
+}
+
+// how much of a bin read_bin_to copies at a time
+#[cfg(feature = "std")]
+const COPY_BYTES: usize = 4096;
+
+// longest struct field name that can be decoded without allocating
+const KEY_BYTES: usize = 64;
+
+// a struct field name, held on the stack
+#[derive(Clone, Copy)]
+struct KeyBuf {
+    buf: [u8; KEY_BYTES],
+    len: usize
 }
 
-struct SeqVisitor<'a, F: 'a + FnMut(&mut [u8]) -> Result<(), Error>> {
-    de: &'a mut Deserializer<F>,
-    count: usize
+// an integer read off the wire, before it is narrowed to the type asked for
+enum Integer {
+    Unsigned(u64),
+    Signed(i64)
 }
 
 struct ExtVisitor {
@@ -30,20 +219,91 @@ struct ExtVisitor {
     data: Vec<u8>
 }
 
-struct VariantVisitor<'a, F: 'a + FnMut(&mut [u8]) -> Result<(), Error>> {
-    de: &'a mut Deserializer<F>,
-    count: usize
+struct VariantVisitor<'a, 'o, F: 'a + FnMut(&mut [u8]) -> Result<(), Error>> {
+    de: &'a mut Deserializer<'o, F>,
+    count: usize,
+    // the names of the enum's variants, and the index of its fallback
+    names: &'static [&'static str],
+    fallback: Option<usize>,
+    // set when the variant read was replaced by the fallback
+    unknown: bool,
+    // set when the variant came as a map from its name to its value
+    keyed: bool,
+    // the variant read, so errors can say which one they were in
+    attempted: &'a mut Option<usize>
 }
 
-impl<'a, F: FnMut(&mut [u8]) -> Result<(), Error>> SeqVisitor<'a, F> {
-    fn new(de: &'a mut Deserializer<F>, count: usize) -> SeqVisitor<'a, F> {
+impl<'a, 'o, F: FnMut(&mut [u8]) -> Result<(), Error>> SeqVisitor<'a, 'o, F> {
+    fn new(de: &'a mut Deserializer<'o, F>, count: usize) -> SeqVisitor<'a, 'o, F> {
         SeqVisitor {
             de: de,
-            count: count
+            count: count,
+            index: 0,
+            key: KeyBuf::new(),
+            seen: BTreeSet::new()
+        }
+    }
+
+    // read keys until one that hasn't been seen, going by their encoding
+    fn next_unique_key<K>(&mut self) -> Result<Option<K>, Error>
+        where K: serde::Deserialize {
+        let encoded = loop {
+            if self.count == 0 {
+                return Ok(None);
+            }
+
+            self.count -= 1;
+            self.index += 1;
+
+            let start = self.de.begin_record();
+            self.de.reading_key = true;
+            let skipped = self.de.skip();
+            self.de.reading_key = false;
+            let encoded = self.de.end_record(start);
+            try!(skipped);
+
+            if !self.seen.contains(&encoded) {
+                break encoded;
+            }
+
+            if self.de.config.duplicate_keys == DuplicateKeys::Reject {
+                return Err(Error::new(Reason::DuplicateKey, format!("Key {} of the map", self.index / 2 + 1)));
+            }
+
+            // keeping the first, so this entry's value goes unread
+            if self.count == 0 {
+                return Err(Error::new(Reason::BadLength, format!("Key {} of the map has no value", self.index / 2 + 1)));
+            }
+
+            self.count -= 1;
+            self.index += 1;
+            try!(self.de.skip());
+        };
+
+        // decode the key from its encoding, as the input has moved past it
+        let (key, last_key) = try!(self.de.nested(&encoded, |de| {
+            let key = try!(K::deserialize(de));
+            Ok((key, de.last_key))
+        }));
+
+        self.seen.insert(encoded);
+        self.de.last_key = last_key;
+        Ok(Some(key))
+    }
+
+    fn next<T>(&mut self) -> Result<Option<T>, Error>
+        where T: serde::Deserialize {
+        if self.count == 0 {
+            return Ok(None);
         }
+
+        self.count -= 1;
+        self.index += 1;
+
+        Ok(Some(try!(T::deserialize(self.de))))
     }
 }
-impl<'a, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::de::SeqVisitor for VariantVisitor<'a, F> {
+impl<'a, 'o, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::de::SeqVisitor for VariantVisitor<'a, 'o, F> {
     type Error = Error;
 
     fn visit<T>(&mut self) -> Result<Option<T>, Error>
@@ -70,46 +330,74 @@ impl<'a, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::de::SeqVisitor for Var
     }
 }
 
-impl<'a, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::de::VariantVisitor for VariantVisitor<'a, F> {
+impl<'a, 'o, F: FnMut(&mut [u8]) -> Result<(), Error>> VariantVisitor<'a, 'o, F> {
+    // the index of the variant whose name is the str starting with marker
+    fn variant_named(&mut self, marker: u8) -> Result<usize, Error> {
+        let name = try!(self.de.read_string(marker));
+
+        match self.names.iter().position(|v| *v == name) {
+            Some(index) => Ok(index),
+            // one past the last variant, so the fallback takes its place
+            None if self.fallback.is_some() => Ok(self.names.len()),
+            None => Err(Error::new(Reason::BadVariant, format!("Unknown variant {}", name)))
+        }
+    }
+}
+
+impl<'a, 'o, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::de::VariantVisitor for VariantVisitor<'a, 'o, F> {
     type Error = Error;
 
     fn visit_variant<V>(&mut self) -> Result<V, Error> where V: serde::Deserialize {
         // not much of a choice but to reach into de here
 
-        let mut buf = [0];
-        try!(self.de.input(&mut buf));
+        let marker = try!(self.de.read_marker());
+        let named = self.de.config.enum_encoding == EnumEncoding::ExternallyTagged;
 
         // switch here because unit variants are just encoded as a usize
-        match buf[0] {
+        let variant: usize = match marker {
+            v if named && (FIXSTR.contains(&v) || v == STR8 || v == STR16 || v == STR32) => {
+                try!(self.variant_named(v))
+            }
+            v if named && (FIXMAP.contains(&v) || v == MAP16 || v == MAP32) => {
+                // give the marker back so the map's length can be read
+                self.de.peeked = Some(v);
+                let len = try!(self.de.read_map_len());
+                if len != 1 {
+                    return Err(serde::Error::invalid_length(len));
+                }
+
+                // the variant's value is the map's one value
+                self.count = 1;
+                self.keyed = true;
+
+                let marker = try!(self.de.read_marker());
+                try!(self.variant_named(marker))
+            }
             // for usizes, we have to do some hand-waiving because we can't peek
             //
             // also flatten variant indexes to a usize because bluh
-            //
-            // BUG: discriminant might not fit into usize
-            v if POS_FIXINT.contains(v) => {
-                V::deserialize(&mut (buf[0] as usize).into_deserializer())
-            }
+            v if POS_FIXINT.contains(&v) => v as usize,
             UINT8 => {
                 let mut buf = [0];
                 try!(self.de.input(&mut buf));
-                V::deserialize(&mut (buf[0] as usize).into_deserializer())
+                buf[0] as usize
             }
             UINT16 => {
                 let mut buf = [0; U16_BYTES];
                 try!(self.de.input(&mut buf));
-                V::deserialize(&mut (BigEndian::read_u16(&buf) as usize).into_deserializer())
+                u16::from_be_bytes(buf) as usize
             }
             UINT32 => {
                 let mut buf = [0; U32_BYTES];
                 try!(self.de.input(&mut buf));
-                V::deserialize(&mut (BigEndian::read_u32(&buf) as usize).into_deserializer())
+                try!(variant_index(u32::from_be_bytes(buf) as u64))
             }
             UINT64 => {
                 let mut buf = [0; U64_BYTES];
                 try!(self.de.input(&mut buf));
-                V::deserialize(&mut (BigEndian::read_u64(&buf) as usize).into_deserializer())
+                try!(variant_index(u64::from_be_bytes(buf)))
             }
-            v if FIXARRAY.contains(v) => {
+            v if FIXARRAY.contains(&v) => {
                 // minus one because we grab the first element here
                 self.count = (v & !FIXARRAY_MASK) as usize;
 
@@ -120,13 +408,12 @@ impl<'a, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::de::VariantVisitor for
 
                 self.count -= 1;
 
-                let variant: usize = try!(serde::Deserialize::deserialize(self.de));
-                V::deserialize(&mut variant.into_deserializer())
+                try!(serde::Deserialize::deserialize(self.de))
             }
             ARRAY16 => {
                 let mut buf = [0; U16_BYTES];
                 try!(self.de.input(&mut buf));
-                self.count = BigEndian::read_u16(&buf) as usize;
+                self.count = u16::from_be_bytes(buf) as usize;
 
                 if self.count == 0 {
                     return Err(serde::Error::invalid_length(0))
@@ -134,13 +421,12 @@ impl<'a, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::de::VariantVisitor for
 
                 self.count -= 1;
 
-                let variant: usize = try!(serde::Deserialize::deserialize(self.de));
-                V::deserialize(&mut variant.into_deserializer())
+                try!(serde::Deserialize::deserialize(self.de))
             }
             ARRAY32 => {
                 let mut buf = [0; U32_BYTES];
                 try!(self.de.input(&mut buf));
-                self.count = BigEndian::read_u32(&buf) as usize;
+                self.count = try!(length(u32::from_be_bytes(buf) as u64));
 
                 if self.count == 0 {
                     return Err(serde::Error::invalid_length(0))
@@ -148,17 +434,39 @@ impl<'a, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::de::VariantVisitor for
 
                 self.count -= 1;
 
-                let variant: usize = try!(serde::Deserialize::deserialize(self.de));
-                V::deserialize(&mut variant.into_deserializer())
+                try!(serde::Deserialize::deserialize(self.de))
             }
             _ => {
-                Err(serde::Error::custom("Enum variant was not a number or in a tuple"))
+                return Err(serde::Error::custom("Enum variant was not a number or in a tuple"));
             }
-        }
+        };
+
+        // a variant this enum doesn't have becomes its fallback, if it has one
+        let variant = match self.fallback {
+            Some(fallback) if variant >= self.names.len() => {
+                self.unknown = true;
+                fallback
+            }
+            _ => variant
+        };
+
+        *self.attempted = Some(variant);
+        V::deserialize(&mut variant.into_deserializer())
     }
 
     fn visit_tuple<V>(&mut self, _: usize, mut visitor: V) -> Result<V::Value, Error>
         where V: serde::de::Visitor {
+        if self.de.config.enum_encoding == EnumEncoding::Rmp || self.keyed {
+            // the elements are in an array of their own
+            if self.count == 0 {
+                return Err(serde::Error::custom("No more elements in this tuple"));
+            }
+
+            self.count -= 1;
+
+            return serde::Deserializer::deserialize_seq(self.de, visitor);
+        }
+
         // tuple variants have an extra item added to them
         visitor.visit_seq(self)
     }
@@ -185,10 +493,43 @@ impl<'a, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::de::VariantVisitor for
 
         self.count -= 1;
 
+        if self.de.config.enum_encoding == EnumEncoding::Rmp {
+            let len = try!(self.de.read_array_len());
+            if len != 1 {
+                return Err(serde::Error::invalid_length(len));
+            }
+        }
+
         T::deserialize(self.de)
     }
 
     fn visit_unit(&mut self) -> Result<(), Error> {
+        if self.unknown {
+            // whatever the unknown variant carried is thrown away
+            while self.count > 0 {
+                self.count -= 1;
+                try!(self.de.skip());
+            }
+
+            return Ok(());
+        }
+
+        // a unit variant keyed by its name has nil for a value
+        if self.keyed && self.count == 1 {
+            self.count -= 1;
+            return serde::Deserialize::deserialize(self.de);
+        }
+
+        // a bare index is accepted for unit variants in either encoding
+        if self.de.config.enum_encoding == EnumEncoding::Rmp && self.count == 1 {
+            self.count -= 1;
+
+            let len = try!(self.de.read_array_len());
+            if len != 0 {
+                return Err(serde::Error::invalid_length(len));
+            }
+        }
+
         Ok(())
     }
 }
@@ -236,18 +577,63 @@ impl serde::de::MapVisitor for ExtVisitor {
     }
 }
 
-impl<'a, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::de::SeqVisitor for SeqVisitor<'a, F> {
+fn length(len: u64) -> Result<usize, Error> {
+    length_within(len, usize::max_value() as u64)
+}
+
+// split out so narrower targets can be tested on a wide one
+fn length_within(len: u64, max: u64) -> Result<usize, Error> {
+    if len > max {
+        Err(Error::new(Reason::TooBig, format!("Length {} does not fit in usize", len)))
+    } else {
+        Ok(len as usize)
+    }
+}
+
+// the type family a marker belongs to
+fn marker_type(marker: u8) -> Result<MsgpackType, Error> {
+    let ty = match marker {
+        v if POS_FIXINT.contains(&v) => MsgpackType::Uint,
+        v if NEG_FIXINT.contains(&v) => MsgpackType::Int,
+        v if FIXMAP.contains(&v) => MsgpackType::Map,
+        v if FIXARRAY.contains(&v) => MsgpackType::Array,
+        v if FIXSTR.contains(&v) => MsgpackType::Str,
+        NIL => MsgpackType::Nil,
+        FALSE | TRUE => MsgpackType::Bool,
+        BIN8 | BIN16 | BIN32 => MsgpackType::Bin,
+        FIXEXT1 | FIXEXT2 | FIXEXT4 | FIXEXT8 | FIXEXT16 | EXT8 | EXT16 | EXT32 => MsgpackType::Ext,
+        FLOAT32 | FLOAT64 => MsgpackType::Float,
+        UINT8 | UINT16 | UINT32 | UINT64 => MsgpackType::Uint,
+        INT8 | INT16 | INT32 | INT64 => MsgpackType::Int,
+        STR8 | STR16 | STR32 => MsgpackType::Str,
+        ARRAY16 | ARRAY32 => MsgpackType::Array,
+        MAP16 | MAP32 => MsgpackType::Map,
+        v => return Err(Error::new(Reason::BadType, format!("Reserved marker {:#04x}", v)))
+    };
+
+    Ok(ty)
+}
+
+fn variant_index(index: u64) -> Result<usize, Error> {
+    variant_index_within(index, usize::max_value() as u64)
+}
+
+// split out, like length_within, so narrower targets can be tested
+fn variant_index_within(index: u64, max: u64) -> Result<usize, Error> {
+    if index > max {
+        Err(Error::new(Reason::OutOfRange, format!("Variant index {} does not fit in usize", index)))
+    } else {
+        Ok(index as usize)
+    }
+}
+
+impl<'a, 'o, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::de::SeqVisitor for SeqVisitor<'a, 'o, F> {
     type Error = Error;
 
     fn visit<T>(&mut self) -> Result<Option<T>, Error>
         where T: serde::Deserialize {
-        if self.count == 0 {
-            return Ok(None);
-        }
-
-        self.count -= 1;
-
-        Ok(Some(try!(T::deserialize(self.de))))
+        let index = self.index;
+        self.next().map_err(|e| e.at_index(index))
     }
 
     fn end(&mut self) -> Result<(), Error> {
@@ -259,40 +645,90 @@ impl<'a, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::de::SeqVisitor for Seq
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.count, Some(self.count))
+        (::std::cmp::min(self.count, MAX_SIZE_HINT), Some(self.count))
     }
 }
 
-impl<'a, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::de::MapVisitor for SeqVisitor<'a, F> {
+impl<'a, 'o, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::de::MapVisitor for SeqVisitor<'a, 'o, F> {
     type Error = Error;
 
     fn visit_key<K>(&mut self) -> Result<Option<K>, Error>
         where K: serde::Deserialize {
-        serde::de::SeqVisitor::visit(self)
+        self.de.last_key.clear();
+
+        let key = if self.de.config.duplicate_keys == DuplicateKeys::KeepLast {
+            self.de.reading_key = true;
+            let key = self.next();
+            self.de.reading_key = false;
+            try!(key)
+        } else {
+            try!(self.next_unique_key())
+        };
+
+        self.key = self.de.last_key;
+        Ok(key)
     }
 
     fn visit_value<V>(&mut self) -> Result<V, Error>
         where V: serde::Deserialize {
-        try!(serde::de::SeqVisitor::visit(self)).ok_or(Error::simple(Reason::EndOfStream))
+        match self.next() {
+            Ok(Some(value)) => Ok(value),
+            Ok(None) => Err(Error::simple(Reason::EndOfStream)),
+            Err(e) => Err(e.at_field(self.key.as_str()))
+        }
     }
 
     fn end(&mut self) -> Result<(), Error> {
         serde::de::SeqVisitor::end(self)
     }
 
+    fn missing_field<V>(&mut self, field: &'static str) -> Result<V, Error>
+        where V: serde::Deserialize {
+        if !self.de.config.compact_structs {
+            return V::deserialize(&mut MissingField(field));
+        }
+
+        // compact structs leave out fields that are nil, so a missing field is
+        // read as nil if its type takes that, like an Option or a PhantomData
+        let mut de = serde::de::value::ValueDeserializer::<Error>::into_deserializer(());
+        V::deserialize(&mut de).map_err(|_| <Error as serde::de::Error>::missing_field(field))
+    }
+
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.count / 2, Some((self.count + 1) / 2))
+        (::std::cmp::min(self.count / 2, MAX_SIZE_HINT), Some(self.count / 2 + self.count % 2))
+    }
+}
+
+// a struct field left out of its map, which is none if it is an Option and
+// missing otherwise
+struct MissingField(&'static str);
+
+impl serde::Deserializer for MissingField {
+    type Error = Error;
+
+    fn deserialize<V>(&mut self, _: V) -> Result<V::Value, Error>
+        where V: serde::de::Visitor {
+        Err(<Error as serde::de::Error>::missing_field(self.0))
+    }
+
+    fn deserialize_option<V>(&mut self, mut visitor: V) -> Result<V::Value, Error>
+        where V: serde::de::Visitor {
+        visitor.visit_none()
+    }
+
+    forward_to_deserialize! {
+        bool usize u8 u16 u32 u64 isize i8 i16 i32 i64 f32 f64 char str string unit seq seq_fixed_size bytes map
+        unit_struct newtype_struct tuple_struct struct struct_field tuple enum ignored_any
     }
 }
 
-impl<F: FnMut(&mut [u8]) -> Result<(), Error>> serde::Deserializer for Deserializer<F> {
+impl<'o, F: FnMut(&mut [u8]) -> Result<(), Error>> serde::Deserializer for Deserializer<'o, F> {
     type Error = Error;
 
     fn deserialize<V>(&mut self, visitor: V) -> Result<V::Value, Error>
         where V: serde::de::Visitor {
-        let mut buf = [0];
-        try!(self.input(&mut buf));
-        self.parse_as(visitor, buf[0])
+        let marker = try!(self.read_marker());
+        self.parse_as(visitor, marker)
     }
 
     fn deserialize_bool<V>(&mut self, visitor: V) -> Result<V::Value, Error>
@@ -300,14 +736,34 @@ impl<F: FnMut(&mut [u8]) -> Result<(), Error>> serde::Deserializer for Deseriali
         self.deserialize(visitor)
     }
 
-    fn deserialize_u64<V>(&mut self, visitor: V) -> Result<V::Value, Error>
+    fn deserialize_u64<V>(&mut self, mut visitor: V) -> Result<V::Value, Error>
         where V: serde::de::Visitor {
-        self.deserialize(visitor)
+        let marker = try!(self.read_marker());
+        try!(self.check_strict(marker, false));
+
+        // a negative value must not wrap around into a large unsigned one
+        match try!(self.parse_integer(marker)) {
+            Some(Integer::Unsigned(v)) => visitor.visit_u64(v),
+            Some(Integer::Signed(v)) if v >= 0 => visitor.visit_u64(v as u64),
+            Some(Integer::Signed(v)) => Err(Error::new(Reason::OutOfRange, format!("Negative value {} for an unsigned integer", v))),
+            None => self.parse_as(visitor, marker)
+        }
     }
 
-    fn deserialize_usize<V>(&mut self, visitor: V) -> Result<V::Value, Error>
+    fn deserialize_usize<V>(&mut self, mut visitor: V) -> Result<V::Value, Error>
         where V: serde::de::Visitor {
-        self.deserialize_u64(visitor)
+        let marker = try!(self.read_marker());
+        try!(self.check_strict(marker, false));
+
+        // a usize from a 64-bit producer might not fit in this one
+        let value = match try!(self.parse_integer(marker)) {
+            Some(Integer::Unsigned(v)) if v <= usize::max_value() as u64 => v as usize,
+            Some(Integer::Signed(v)) if v >= 0 && v as u64 <= usize::max_value() as u64 => v as usize,
+            Some(_) => return Err(Error::new(Reason::OutOfRange, "Value does not fit in usize".into())),
+            None => return self.parse_as(visitor, marker)
+        };
+
+        visitor.visit_usize(value)
     }
 
     fn deserialize_u8<V>(&mut self, visitor: V) -> Result<V::Value, Error>
@@ -327,12 +783,24 @@ impl<F: FnMut(&mut [u8]) -> Result<(), Error>> serde::Deserializer for Deseriali
 
     fn deserialize_i64<V>(&mut self, visitor: V) -> Result<V::Value, Error>
         where V: serde::de::Visitor {
-        self.deserialize(visitor)
+        let marker = try!(self.read_marker());
+        try!(self.check_strict(marker, false));
+        self.parse_as(visitor, marker)
     }
 
-    fn deserialize_isize<V>(&mut self, visitor: V) -> Result<V::Value, Error>
+    fn deserialize_isize<V>(&mut self, mut visitor: V) -> Result<V::Value, Error>
         where V: serde::de::Visitor {
-        self.deserialize_i64(visitor)
+        let marker = try!(self.read_marker());
+        try!(self.check_strict(marker, false));
+
+        let value = match try!(self.parse_integer(marker)) {
+            Some(Integer::Unsigned(v)) if v <= isize::max_value() as u64 => v as isize,
+            Some(Integer::Signed(v)) if v >= isize::min_value() as i64 && v <= isize::max_value() as i64 => v as isize,
+            Some(_) => return Err(Error::new(Reason::OutOfRange, "Value does not fit in isize".into())),
+            None => return self.parse_as(visitor, marker)
+        };
+
+        visitor.visit_isize(value)
     }
 
     fn deserialize_i8<V>(&mut self, visitor: V) -> Result<V::Value, Error>
@@ -352,12 +820,28 @@ impl<F: FnMut(&mut [u8]) -> Result<(), Error>> serde::Deserializer for Deseriali
 
     fn deserialize_f64<V>(&mut self, visitor: V) -> Result<V::Value, Error>
         where V: serde::de::Visitor {
-        self.deserialize(visitor)
+        let marker = try!(self.read_marker());
+        try!(self.check_strict(marker, true));
+        self.parse_as(visitor, marker)
     }
 
-    fn deserialize_f32<V>(&mut self, visitor: V) -> Result<V::Value, Error>
+    fn deserialize_f32<V>(&mut self, mut visitor: V) -> Result<V::Value, Error>
         where V: serde::de::Visitor {
-        self.deserialize_f64(visitor)
+        let marker = try!(self.read_marker());
+        try!(self.check_strict(marker, true));
+        if marker != FLOAT64 || !self.config.strict_float_precision {
+            return self.parse_as(visitor, marker);
+        }
+
+        let mut buf = [0; U64_BYTES];
+        try!(self.input(&mut buf));
+        let value = f64::from_be_bytes(buf);
+        let narrowed = value as f32;
+        if narrowed as f64 != value && !value.is_nan() {
+            return Err(Error::new(Reason::PrecisionLoss, format!("{} does not fit exactly in an f32", value)));
+        }
+
+        visitor.visit_f32(narrowed)
     }
 
     fn deserialize_str<V>(&mut self, visitor: V) -> Result<V::Value, Error>
@@ -375,14 +859,33 @@ impl<F: FnMut(&mut [u8]) -> Result<(), Error>> serde::Deserializer for Deseriali
         self.deserialize_str(visitor)
     }
 
-    fn deserialize_unit<V>(&mut self, visitor: V) -> Result<V::Value, Error>
+    fn deserialize_unit<V>(&mut self, mut visitor: V) -> Result<V::Value, Error>
         where V: serde::de::Visitor {
-        self.deserialize(visitor)
+        let marker = try!(self.read_marker());
+        if marker == NIL || marker == self.config.unit_encoding.marker() {
+            visitor.visit_unit()
+        } else {
+            self.parse_as(visitor, marker)
+        }
     }
 
-    fn deserialize_option<V>(&mut self, visitor: V) -> Result<V::Value, Error>
+    fn deserialize_option<V>(&mut self, mut visitor: V) -> Result<V::Value, Error>
         where V: serde::de::Visitor {
-        self.deserialize(visitor)
+        // anything but nil is the inner value, which gets decoded as its own type
+        let marker = try!(self.peek_marker());
+        if marker == NIL {
+            self.peeked = None;
+            return visitor.visit_none();
+        }
+
+        match marker {
+            FIXEXT1 | FIXEXT2 | FIXEXT4 | FIXEXT8 | FIXEXT16 | EXT8 | EXT16 | EXT32
+                if self.config.ext_registry.handlers.iter().any(|h| h.is_some()) => {
+                self.peeked = None;
+                self.visit_ext_option(visitor, marker)
+            }
+            _ => visitor.visit_some(self)
+        }
     }
 
     fn deserialize_seq<V>(&mut self, visitor: V) -> Result<V::Value, Error>
@@ -410,13 +913,49 @@ impl<F: FnMut(&mut [u8]) -> Result<(), Error>> serde::Deserializer for Deseriali
         self.deserialize_unit(visitor)
     }
 
-    fn deserialize_newtype_struct<V>(&mut self, _: &'static str, visitor: V) -> Result<V::Value, Error>
+    fn deserialize_newtype_struct<V>(&mut self, name: &'static str, mut visitor: V) -> Result<V::Value, Error>
         where V: serde::de::Visitor {
+        if name == ::raw::TOKEN {
+            let start = self.begin_record();
+            let result: Result<serde::de::impls::IgnoredAny, Error> = serde::Deserialize::deserialize(self);
+            let raw = self.end_record(start);
+            try!(result);
+            return visitor.visit_byte_buf(raw);
+        }
+
+        if name == ::value::TOKEN {
+            let marker = try!(self.read_marker());
+            match marker {
+                FIXEXT1 | FIXEXT2 | FIXEXT4 | FIXEXT8 | FIXEXT16 | EXT8 | EXT16 | EXT32 => {}
+                _ => return self.parse_as(visitor, marker)
+            }
+
+            let (ty, data) = try!(self.read_ext(marker));
+            if self.config.ext_registry.get(ty).is_some() {
+                return self.visit_ext(visitor, ty, data);
+            }
+
+            // a Value wants unhandled ext values kept whole, so hand it the
+            // type and data in a way no other MessagePack value can produce
+            let parts = try!(::to_bytes((ty, Bytes(data.as_slice()))));
+            let mut position = 0;
+            let mut de = Deserializer::new(|buf: &mut [u8]| ::read_slice(&parts, &mut position, buf));
+            return visitor.visit_newtype_struct(&mut de);
+        }
+
         self.deserialize(visitor)
     }
 
-    fn deserialize_tuple_struct<V>(&mut self, _: &'static str, len: usize, visitor: V) -> Result<V::Value, Error>
+    #[cfg_attr(not(feature = "arrayvec"), allow(unused_variables))]
+    fn deserialize_tuple_struct<V>(&mut self, name: &'static str, len: usize, visitor: V) -> Result<V::Value, Error>
         where V: serde::de::Visitor {
+        #[cfg(feature = "arrayvec")]
+        {
+            if name == ::array_vec::TOKEN {
+                return self.deserialize_bounded(len, visitor);
+            }
+        }
+
         self.deserialize_tuple(len, visitor)
     }
 
@@ -425,9 +964,32 @@ impl<F: FnMut(&mut [u8]) -> Result<(), Error>> serde::Deserializer for Deseriali
         self.deserialize_map(visitor)
     }
 
-    fn deserialize_struct_field<V>(&mut self, visitor: V) -> Result<V::Value, Error>
+    fn deserialize_struct_field<V>(&mut self, mut visitor: V) -> Result<V::Value, Error>
         where V: serde::de::Visitor {
-        self.deserialize(visitor)
+        let marker = try!(self.read_marker());
+        if !(FIXSTR.contains(&marker) || marker == STR8 || marker == STR16 || marker == STR32) {
+            return self.parse_as(visitor, marker);
+        }
+
+        let size = try!(self.str_len(marker));
+        if size > KEY_BYTES {
+            // too long to keep, so it is left out of error paths
+            let key = try!(self.read_bytes_into_string(size));
+            self.last_key.clear();
+            return visitor.visit_str(&key);
+        }
+
+        // short names are read onto the stack and matched by reference, and
+        // kept around so errors in the field's value can say where they happened
+        try!(self.charge(size));
+        let mut key = KeyBuf::new();
+        try!(self.input(&mut key.buf[..size]));
+        try!(::std::str::from_utf8(&key.buf[..size])
+             .map_err(|e| Error::new(Reason::UTF8Error, format!("{}", e))));
+        key.len = size;
+
+        self.last_key = key;
+        visitor.visit_str(key.as_str())
     }
 
     fn deserialize_tuple<V>(&mut self, len: usize, visitor: V) -> Result<V::Value, Error>
@@ -435,272 +997,802 @@ impl<F: FnMut(&mut [u8]) -> Result<(), Error>> serde::Deserializer for Deseriali
         self.deserialize_seq_fixed_size(len, visitor)
     }
 
-    fn deserialize_enum<V>(&mut self, _: &'static str, _: &'static [&'static str], mut visitor: V) -> Result<V::Value, Error>
+    fn deserialize_enum<V>(&mut self, _: &'static str, variants: &'static [&'static str], mut visitor: V) -> Result<V::Value, Error>
         where V: serde::de::EnumVisitor {
-        visitor.visit(VariantVisitor {
+        let fallback = self.config.fallback_variant
+            .and_then(|name| variants.iter().position(|v| *v == name));
+
+        let mut attempted = None;
+        let result = visitor.visit(VariantVisitor {
             de: self,
-            count: 0
+            count: 0,
+            names: variants,
+            fallback: fallback,
+            unknown: false,
+            keyed: false,
+            attempted: &mut attempted
+        });
+
+        result.map_err(|e| match attempted {
+            Some(index) => e.at_variant(index, variants.get(index).cloned()),
+            None => e
         })
     }
 
-    fn deserialize_ignored_any<V>(&mut self, visitor: V) -> Result<V::Value, Error>
+    fn deserialize_ignored_any<V>(&mut self, mut visitor: V) -> Result<V::Value, Error>
         where V: serde::de::Visitor {
-        self.deserialize(visitor)
+        try!(self.skip());
+        visitor.visit_unit()
+    }
+}
+
+impl KeyBuf {
+    const fn new() -> KeyBuf {
+        KeyBuf {
+            buf: [0; KEY_BYTES],
+            len: 0
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn as_str(&self) -> &str {
+        // only ever filled with validated UTF-8
+        ::std::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl DeserializerConfig {
+    /// Create the default configuration.
+    pub const fn new() -> DeserializerConfig {
+        DeserializerConfig {
+            strict_types: false,
+            strict_float_precision: false,
+            unit_encoding: UnitEncoding::Nil,
+            enum_encoding: EnumEncoding::Index,
+            ext_registry: ExtRegistry::new(),
+            legacy_raw: LegacyRaw::Off,
+            duplicate_keys: DuplicateKeys::KeepLast,
+            max_key_len: None,
+            max_ext_len: None,
+            max_total_len: None,
+            compact_structs: false,
+            fallback_variant: None
+        }
+    }
+
+    /// Register a handler for an ext type, replacing any it already had.
+    pub fn register_ext(&mut self, ty: i8, handler: ExtHandler) -> Result<(), Error> {
+        self.ext_registry.register(ty, handler)
+    }
+}
+
+impl ExtRegistry {
+    /// Create a registry without any handlers.
+    pub const fn new() -> ExtRegistry {
+        ExtRegistry {
+            handlers: [None; MAX_EXT_HANDLERS]
+        }
+    }
+
+    /// Register a handler for an ext type, replacing any it already had.
+    /// Fails if handlers for too many other types are already registered.
+    pub fn register(&mut self, ty: i8, handler: ExtHandler) -> Result<(), Error> {
+        let slot = self.handlers.iter().position(|h| match *h { Some((t, _)) => t == ty, None => false })
+            .or_else(|| self.handlers.iter().position(|h| h.is_none()));
+
+        if let Some(slot) = slot {
+            self.handlers[slot] = Some((ty, handler));
+            Ok(())
+        } else {
+            Err(Error::new(Reason::TooBig, format!("No room for a handler for ext type {}", ty)))
+        }
+    }
+
+    /// Get the handler for an ext type.
+    pub fn get(&self, ty: i8) -> Option<ExtHandler> {
+        self.handlers.iter()
+            .filter_map(|h| *h)
+            .find(|&(t, _)| t == ty)
+            .map(|(_, handler)| handler)
+    }
+}
+
+// two registries are equal if they have the same handlers for the same types,
+// in whichever slots
+impl PartialEq for ExtRegistry {
+    fn eq(&self, other: &ExtRegistry) -> bool {
+        let count = |r: &ExtRegistry| r.handlers.iter().filter(|h| h.is_some()).count();
+
+        count(self) == count(other) && self.handlers.iter().filter_map(|h| *h).all(|(ty, handler)| {
+            other.get(ty).map(|h| h as usize) == Some(handler as usize)
+        })
+    }
+}
+
+impl Eq for ExtRegistry {}
+
+impl Default for ExtRegistry {
+    fn default() -> ExtRegistry {
+        ExtRegistry::new()
+    }
+}
+
+impl Default for DeserializerConfig {
+    fn default() -> DeserializerConfig {
+        DeserializerConfig::new()
     }
 }
 
-impl<F: FnMut(&mut [u8]) -> Result<(), Error>> Deserializer<F> {
+impl<'o, F: FnMut(&mut [u8]) -> Result<(), Error>> Deserializer<'o, F> {
     /// Create a new Deserializer given an input function.
-    pub const fn new(input: F) -> Deserializer<F> {
+    pub const fn new(input: F) -> Deserializer<'o, F> {
+        Deserializer::with_config(input, DeserializerConfig::new())
+    }
+
+    /// Create a new Deserializer given an input function and configuration.
+    pub const fn with_config(input: F, config: DeserializerConfig) -> Deserializer<'o, F> {
         Deserializer {
-            input: input
+            input: input,
+            config: config,
+            record: Vec::new(),
+            recording: 0,
+            last_key: KeyBuf::new(),
+            reading_key: false,
+            peeked: None,
+            position: 0,
+            total_len: 0,
+            depth: 0,
+            observer: None
         }
     }
 
-    fn input(&mut self, buf: &mut [u8]) -> Result<(), Error> {
-        self.input.call_mut((buf,))
+    /// Copy the next value, which must be a bin or str, to a writer without
+    /// holding all of it in memory. Returns the number of bytes written.
+    ///
+    /// The bytes are copied in small chunks as they are read. A str is not
+    /// checked for valid UTF-8.
+    #[cfg(feature = "std")]
+    pub fn read_bin_to<W>(&mut self, writer: &mut W) -> Result<usize, Error>
+        where W: ::std::io::Write {
+        let marker = try!(self.read_marker());
+        let mut remaining = match marker {
+            BIN8 | BIN16 | BIN32 => try!(self.bin_len(marker)),
+            _ => try!(self.str_len(marker))
+        };
+        let size = remaining;
+
+        let mut buf = [0; COPY_BYTES];
+        while remaining > 0 {
+            let chunk = &mut buf[..::std::cmp::min(remaining, COPY_BYTES)];
+            try!(self.input(chunk));
+            try!(writer.write_all(chunk).map_err(|e| {
+                Error::chain(Reason::Other, "Failed to write bytes".into(), Some(Box::new(e)))
+            }));
+            remaining -= chunk.len();
+        }
+
+        Ok(size)
     }
 
-    fn parse_as<V>(&mut self, mut visitor: V, ty: u8) -> Result<V::Value, Error> 
-        where V: serde::de::Visitor {
-        match ty {
-            v if POS_FIXINT.contains(v) => {
-                visitor.visit_u8(v)
-            }
-            v if NEG_FIXINT.contains(v) => {
-                visitor.visit_i8(LittleEndian::read_i16(&[v, 0]) as i8)
-            }
-            v if FIXMAP.contains(v) => {
-                let size = (v & !FIXMAP_MASK) as usize * 2;
-                visitor.visit_map(SeqVisitor::new(self, size))
-            }
-            v if FIXARRAY.contains(v) => {
-                let size = (v & !FIXARRAY_MASK) as usize;
-                visitor.visit_seq(SeqVisitor::new(self, size))
-            }
-            v if FIXSTR.contains(v) => {
-                let mut buf = vec![0; (v & !FIXSTR_MASK) as usize];
-                try!(self.input(buf.as_mut_slice()));
-                visitor.visit_string(
-                    try!(String::from_utf8(buf)
-                         .map_err(|e| Error::new(Reason::UTF8Error, format!("{}", e)))))
-            }
-            NIL => visitor.visit_none(),
-            FALSE => visitor.visit_bool(false),
-            TRUE => visitor.visit_bool(true),
-            BIN8 => {
-                let mut buf = [0];
-                try!(self.input(&mut buf));
-                let mut buf = vec![0; buf[0] as usize];
-                try!(self.input(buf.as_mut_slice()));
-                visitor.visit_byte_buf(buf)
-            }
-            BIN16 => {
+    /// The number of bytes read from the input so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Call observer with the type and byte offset of every value as it is
+    /// read, containers and the values in them alike, in the order they appear
+    /// in the input. This is meant for tracing a decode, and doesn't change
+    /// its result. The offset of a value is the position of its marker.
+    ///
+    /// Only values read from the input itself are reported. The Value an ext
+    /// handler returns is decoded from memory in the ext's place, so what is
+    /// inside it isn't seen. Map keys checked for duplicates are reported as
+    /// they are read, and not again when they are then decoded.
+    pub fn observe<O>(&mut self, observer: O)
+        where O: FnMut(MsgpackType, usize) + 'o {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Read the header of an array and return its number of elements, leaving
+    /// the elements to be read next. Fails with `Reason::BadType` if the next
+    /// value isn't an array.
+    pub fn read_array_len(&mut self) -> Result<usize, Error> {
+        match try!(self.read_marker()) {
+            v if FIXARRAY.contains(&v) => Ok((v & !FIXARRAY_MASK) as usize),
+            ARRAY16 => {
                 let mut buf = [0; U16_BYTES];
                 try!(self.input(&mut buf));
-                let mut buf = vec![0; BigEndian::read_u16(&buf) as usize];
-                try!(self.input(buf.as_mut_slice()));
-                visitor.visit_byte_buf(buf)
+                Ok(u16::from_be_bytes(buf) as usize)
             }
-            BIN32 => {
+            ARRAY32 => {
                 let mut buf = [0; U32_BYTES];
                 try!(self.input(&mut buf));
-                let mut buf = vec![0; BigEndian::read_u32(&buf)as usize];
-                try!(self.input(buf.as_mut_slice()));
-                visitor.visit_byte_buf(buf)
-            }
-            EXT8 => {
-                let mut buf = [0];
-                try!(self.input(&mut buf));
-                let size = buf[0] as usize;
-                try!(self.input(&mut buf));
-                let ty: i8 = LittleEndian::read_i16(&[buf[0], 0]) as i8;
-                let mut buf = vec![0; size];
-                try!(self.input(buf.as_mut_slice()));
-                visitor.visit_map(ExtVisitor {
-                    state: 0,
-                    ty: ty,
-                    data: buf
-                })
+                length(u32::from_be_bytes(buf) as u64)
             }
-            EXT16 => {
+            v => Err(Error::new(Reason::BadType, format!("Expected an array, found marker {:#04x}", v)))
+        }
+    }
+
+    /// Read the header of a map and return its number of entries, leaving the
+    /// keys and values to be read next.
+    pub fn read_map_len(&mut self) -> Result<usize, Error> {
+        match try!(self.read_marker()) {
+            v if FIXMAP.contains(&v) => Ok((v & !FIXMAP_MASK) as usize),
+            MAP16 => {
                 let mut buf = [0; U16_BYTES];
                 try!(self.input(&mut buf));
-                let size = BigEndian::read_u16(&buf) as usize;
-                try!(self.input(&mut buf[..1]));
-                let ty: i8 = LittleEndian::read_i16(&[buf[0], 0]) as i8;
-                let mut buf = vec![0; size];
-                try!(self.input(buf.as_mut_slice()));
-                visitor.visit_map(ExtVisitor {
-                    state: 0,
-                    ty: ty,
-                    data: buf
-                })
+                Ok(u16::from_be_bytes(buf) as usize)
             }
-            EXT32 => {
+            MAP32 => {
                 let mut buf = [0; U32_BYTES];
                 try!(self.input(&mut buf));
-                let size = BigEndian::read_u32(&buf) as usize;
-                try!(self.input(&mut buf[..1]));
-                let ty: i8 = LittleEndian::read_i16(&[buf[0], 0]) as i8;
-                let mut buf = vec![0; size];
-                try!(self.input(buf.as_mut_slice()));
-                visitor.visit_map(ExtVisitor {
-                    state: 0,
-                    ty: ty,
-                    data: buf
-                })
+                length(u32::from_be_bytes(buf) as u64)
             }
+            v => Err(Error::new(Reason::BadType, format!("Expected a map, found marker {:#04x}", v)))
+        }
+    }
+
+    /// Read the header of a str and return its length in bytes, leaving the
+    /// bytes to be read next. They are not checked for UTF-8.
+    pub fn read_str_len(&mut self) -> Result<usize, Error> {
+        let marker = try!(self.read_marker());
+        self.str_len(marker)
+    }
+
+    /// Read the header of a bin and return its length in bytes, leaving the
+    /// bytes to be read next.
+    pub fn read_bin_len(&mut self) -> Result<usize, Error> {
+        let marker = try!(self.read_marker());
+        self.bin_len(marker)
+    }
+
+    /// Read exactly enough bytes to fill buf, as they are, such as the body
+    /// after a str or bin header. Fails with `Reason::EndOfStream` if the
+    /// input runs out first.
+    pub fn read_raw(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        // a marker that was peeked at has already been taken from the input
+        match self.peeked.take() {
+            Some(marker) => {
+                buf[0] = marker;
+                self.input(&mut buf[1..])
+            }
+            None => self.input(buf)
+        }
+    }
+
+    /// Get the type family of the next value without consuming it.
+    pub fn peek_type(&mut self) -> Result<MsgpackType, Error> {
+        marker_type(try!(self.peek_marker()))
+    }
+
+    // None if the marker is not for an integer, with nothing more read
+    fn parse_integer(&mut self, marker: u8) -> Result<Option<Integer>, Error> {
+        let value = match marker {
+            v if POS_FIXINT.contains(&v) => Integer::Unsigned(v as u64),
+            v if NEG_FIXINT.contains(&v) => Integer::Signed(v as i8 as i64),
             UINT8 => {
                 let mut buf = [0];
                 try!(self.input(&mut buf));
-                visitor.visit_u8(buf[0])
+                Integer::Unsigned(buf[0] as u64)
             }
             UINT16 => {
                 let mut buf = [0; U16_BYTES];
                 try!(self.input(&mut buf));
-                visitor.visit_u16(BigEndian::read_u16(&buf))
+                Integer::Unsigned(u16::from_be_bytes(buf) as u64)
             }
             UINT32 => {
                 let mut buf = [0; U32_BYTES];
                 try!(self.input(&mut buf));
-                visitor.visit_u32(BigEndian::read_u32(&buf))
+                Integer::Unsigned(u32::from_be_bytes(buf) as u64)
             }
             UINT64 => {
                 let mut buf = [0; U64_BYTES];
                 try!(self.input(&mut buf));
-                visitor.visit_u64(BigEndian::read_u64(&buf))
+                Integer::Unsigned(u64::from_be_bytes(buf))
             }
             INT8 => {
                 let mut buf = [0];
                 try!(self.input(&mut buf));
-                visitor.visit_i8(LittleEndian::read_i16(&[buf[0], 0]) as i8)
+                Integer::Signed(buf[0] as i8 as i64)
             }
             INT16 => {
                 let mut buf = [0; U16_BYTES];
                 try!(self.input(&mut buf));
-                visitor.visit_i16(BigEndian::read_i16(&buf))
+                Integer::Signed(i16::from_be_bytes(buf) as i64)
             }
             INT32 => {
                 let mut buf = [0; U32_BYTES];
                 try!(self.input(&mut buf));
-                visitor.visit_i32(BigEndian::read_i32(&buf))
+                Integer::Signed(i32::from_be_bytes(buf) as i64)
             }
             INT64 => {
                 let mut buf = [0; U64_BYTES];
                 try!(self.input(&mut buf));
-                visitor.visit_i64(BigEndian::read_i64(&buf))
+                Integer::Signed(i64::from_be_bytes(buf))
             }
-            FIXEXT1 => {
+            _ => return Ok(None)
+        };
+
+        Ok(Some(value))
+    }
+
+    fn read_ext(&mut self, marker: u8) -> Result<(i8, Vec<u8>), Error> {
+        let size = try!(self.read_ext_len(marker));
+
+        let mut buf = [0];
+        try!(self.input(&mut buf));
+        let ty = buf[0] as i8;
+
+        try!(self.charge(size));
+        let mut data = vec![0; size];
+        try!(self.input(data.as_mut_slice()));
+
+        Ok((ty, data))
+    }
+
+    // count len against max_total_len, before it is allocated
+    fn charge(&mut self, len: usize) -> Result<(), Error> {
+        self.total_len = self.total_len.saturating_add(len);
+
+        match self.config.max_total_len {
+            Some(max) if self.total_len > max => {
+                Err(Error::new(Reason::LimitExceeded, format!("{} of a total of {} allowed", self.total_len, max)))
+            }
+            _ => Ok(())
+        }
+    }
+
+    // the size of an ext's data, not counting its type
+    fn read_ext_len(&mut self, marker: u8) -> Result<usize, Error> {
+        let size = match marker {
+            FIXEXT1 => 1,
+            FIXEXT2 => 2,
+            FIXEXT4 => 4,
+            FIXEXT8 => 8,
+            FIXEXT16 => 16,
+            EXT8 => {
                 let mut buf = [0];
                 try!(self.input(&mut buf));
-                let ty: i8 = LittleEndian::read_i16(&[buf[0], 0]) as i8;
-                let mut buf = vec![0];
-                try!(self.input(buf.as_mut_slice()));
-                visitor.visit_map(ExtVisitor {
-                    state: 0,
-                    ty: ty,
-                    data: buf
-                })
+                buf[0] as usize
             }
-            FIXEXT2 => {
-                let mut buf = [0];
+            EXT16 => {
+                let mut buf = [0; U16_BYTES];
                 try!(self.input(&mut buf));
-                let ty: i8 = LittleEndian::read_i16(&[buf[0], 0]) as i8;
-                let mut buf = vec![0; 2];
-                try!(self.input(buf.as_mut_slice()));
-                visitor.visit_map(ExtVisitor {
-                    state: 0,
-                    ty: ty,
-                    data: buf
-                })
+                u16::from_be_bytes(buf) as usize
+            }
+            EXT32 => {
+                let mut buf = [0; U32_BYTES];
+                try!(self.input(&mut buf));
+                try!(length(u32::from_be_bytes(buf) as u64))
             }
-            FIXEXT4 => {
+            _ => return Err(Error::simple(Reason::BadType))
+        };
+
+        match self.config.max_ext_len {
+            Some(max) if size > max => {
+                return Err(Error::new(Reason::LimitExceeded, format!("Ext data of {} bytes", size)));
+            }
+            _ => {}
+        }
+
+        Ok(size)
+    }
+
+    fn visit_ext<V>(&mut self, mut visitor: V, ty: i8, data: Vec<u8>) -> Result<V::Value, Error>
+        where V: serde::de::Visitor {
+        if let Some(handler) = self.config.ext_registry.get(ty) {
+            // decode the handler's Value as though it had been there instead
+            let value = try!(handler(data.as_slice()));
+            let bytes = try!(::to_bytes(&value));
+            return self.nested(bytes.as_slice(), |de| serde::Deserializer::deserialize(de, visitor));
+        }
+
+        visitor.visit_map(ExtVisitor {
+            state: 0,
+            ty: ty,
+            data: data
+        })
+    }
+
+    // an ext value where an Option was asked for, which is none if a handler
+    // turns it into nil
+    fn visit_ext_option<V>(&mut self, visitor: V, marker: u8) -> Result<V::Value, Error>
+        where V: serde::de::Visitor {
+        let (ty, data) = try!(self.read_ext(marker));
+        if let Some(handler) = self.config.ext_registry.get(ty) {
+            let bytes = try!(::to_bytes(&try!(handler(data.as_slice()))));
+            return self.nested(bytes.as_slice(), |de| serde::Deserializer::deserialize_option(de, visitor));
+        }
+
+        // some other ext, put back together for the inner value to read, its
+        // data having been charged for already
+        self.total_len = self.total_len.saturating_sub(data.len());
+        let bytes = try!(::to_bytes(&Value::Ext(ty, data)));
+        self.nested(bytes.as_slice(), |de| visitor.visit_some(de))
+    }
+
+    // decode from an encoded value held in memory, in place of the input, with
+    // the same configuration and against the same max_total_len budget
+    fn nested<T, G>(&mut self, bytes: &[u8], decode: G) -> Result<T, Error>
+        where G: FnOnce(&mut Deserializer<'static, &mut dyn FnMut(&mut [u8]) -> Result<(), Error>>) -> Result<T, Error> {
+        if self.depth >= MAX_NESTED_DEPTH {
+            return Err(Error::new(Reason::LimitExceeded, format!("Nested more than {} deep", MAX_NESTED_DEPTH)));
+        }
+
+        let mut position = 0;
+        let mut input = |buf: &mut [u8]| ::read_slice(bytes, &mut position, buf);
+        let mut de = Deserializer::with_config(&mut input as &mut dyn FnMut(&mut [u8]) -> Result<(), Error>, self.config);
+        de.total_len = self.total_len;
+        de.depth = self.depth + 1;
+
+        let result = decode(&mut de);
+        self.total_len = de.total_len;
+        result
+    }
+
+    // an array of at most capacity elements, checked before any are read
+    #[cfg(feature = "arrayvec")]
+    fn deserialize_bounded<V>(&mut self, capacity: usize, mut visitor: V) -> Result<V::Value, Error>
+        where V: serde::de::Visitor {
+        let size = try!(self.read_array_len());
+        if size > capacity {
+            return Err(Error::new(Reason::LimitExceeded,
+                                  format!("Array of {} elements is over the capacity of {}", size, capacity)));
+        }
+        try!(self.charge(size));
+
+        visitor.visit_seq(SeqVisitor::new(self, size))
+    }
+
+    fn visit_str<V>(&mut self, mut visitor: V, marker: u8) -> Result<V::Value, Error>
+        where V: serde::de::Visitor {
+        // str8 came with the str/bin split, so it was never a raw
+        if marker == STR8 || self.config.legacy_raw == LegacyRaw::Off {
+            return visitor.visit_string(try!(self.read_string(marker)));
+        }
+
+        let size = try!(self.str_len(marker));
+        try!(self.charge(size));
+        let mut buf = vec![0; size];
+        try!(self.input(buf.as_mut_slice()));
+
+        if self.config.legacy_raw == LegacyRaw::Bytes {
+            visitor.visit_byte_buf(buf)
+        } else {
+            visitor.visit_string(String::from_utf8_lossy(&buf).into_owned())
+        }
+    }
+
+    fn read_string(&mut self, marker: u8) -> Result<String, Error> {
+        let size = try!(self.str_len(marker));
+        self.read_bytes_into_string(size)
+    }
+
+    fn read_bytes_into_string(&mut self, size: usize) -> Result<String, Error> {
+        try!(self.charge(size));
+        let mut buf = vec![0; size];
+        try!(self.input(buf.as_mut_slice()));
+        String::from_utf8(buf).map_err(|e| Error::new(Reason::UTF8Error, format!("{}", e)))
+    }
+
+    fn str_len(&mut self, marker: u8) -> Result<usize, Error> {
+        let size = match marker {
+            v if FIXSTR.contains(&v) => (v & !FIXSTR_MASK) as usize,
+            STR8 => {
                 let mut buf = [0];
                 try!(self.input(&mut buf));
-                let ty: i8 = LittleEndian::read_i16(&[buf[0], 0]) as i8;
-                let mut buf = vec![0; 4];
-                try!(self.input(buf.as_mut_slice()));
-                visitor.visit_map(ExtVisitor {
-                    state: 0,
-                    ty: ty,
-                    data: buf
-                })
+                buf[0] as usize
+            }
+            STR16 => {
+                let mut buf = [0; U16_BYTES];
+                try!(self.input(&mut buf));
+                u16::from_be_bytes(buf) as usize
+            }
+            STR32 => {
+                let mut buf = [0; U32_BYTES];
+                try!(self.input(&mut buf));
+                try!(length(u32::from_be_bytes(buf) as u64))
+            }
+            v => return Err(Error::new(Reason::BadType, format!("Expected a str, found marker {:#04x}", v)))
+        };
+
+        if self.reading_key {
+            self.reading_key = false;
+
+            match self.config.max_key_len {
+                Some(max) if size > max => {
+                    return Err(Error::new(Reason::LimitExceeded, format!("Map key of {} bytes", size)));
+                }
+                _ => {}
             }
-            FIXEXT8 => {
+        }
+
+        Ok(size)
+    }
+
+    fn bin_len(&mut self, marker: u8) -> Result<usize, Error> {
+        let size = match marker {
+            BIN8 => {
                 let mut buf = [0];
                 try!(self.input(&mut buf));
-                let ty: i8 = LittleEndian::read_i16(&[buf[0], 0]) as i8;
-                let mut buf = vec![0; 8];
+                buf[0] as usize
+            }
+            BIN16 => {
+                let mut buf = [0; U16_BYTES];
+                try!(self.input(&mut buf));
+                u16::from_be_bytes(buf) as usize
+            }
+            BIN32 => {
+                let mut buf = [0; U32_BYTES];
+                try!(self.input(&mut buf));
+                try!(length(u32::from_be_bytes(buf) as u64))
+            }
+            v => return Err(Error::new(Reason::BadType, format!("Expected a bin, found marker {:#04x}", v)))
+        };
+
+        Ok(size)
+    }
+
+    // read past one complete value
+    //
+    // nothing is decoded or allocated, and containers are walked with a count
+    // of the values left rather than by recursing, so deep nesting is fine
+    pub(crate) fn skip(&mut self) -> Result<(), Error> {
+        let mut remaining: u64 = 1;
+
+        while remaining > 0 {
+            remaining -= 1;
+
+            let marker = try!(self.read_marker());
+            let size = match marker {
+                v if POS_FIXINT.contains(&v) || NEG_FIXINT.contains(&v) => 0,
+                NIL | FALSE | TRUE => 0,
+                v if FIXMAP.contains(&v) => {
+                    remaining += (v & !FIXMAP_MASK) as u64 * 2;
+                    0
+                }
+                v if FIXARRAY.contains(&v) => {
+                    remaining += (v & !FIXARRAY_MASK) as u64;
+                    0
+                }
+                UINT8 | INT8 => 1,
+                UINT16 | INT16 => U16_BYTES,
+                UINT32 | INT32 | FLOAT32 => U32_BYTES,
+                UINT64 | INT64 | FLOAT64 => U64_BYTES,
+                ARRAY16 | MAP16 => {
+                    let mut buf = [0; U16_BYTES];
+                    try!(self.input(&mut buf));
+                    let count = u16::from_be_bytes(buf) as u64;
+                    remaining += if marker == MAP16 { count * 2 } else { count };
+                    0
+                }
+                ARRAY32 | MAP32 => {
+                    let mut buf = [0; U32_BYTES];
+                    try!(self.input(&mut buf));
+                    let count = u32::from_be_bytes(buf) as u64;
+                    remaining = remaining.saturating_add(if marker == MAP32 { count * 2 } else { count });
+                    0
+                }
+                BIN8 | BIN16 | BIN32 => try!(self.bin_len(marker)),
+                FIXEXT1 | FIXEXT2 | FIXEXT4 | FIXEXT8 | FIXEXT16 | EXT8 | EXT16 | EXT32 => {
+                    // plus the type
+                    try!(self.read_ext_len(marker)) + 1
+                }
+                v if FIXSTR.contains(&v) || v == STR8 || v == STR16 || v == STR32 => try!(self.str_len(v)),
+                v => return Err(Error::new(Reason::BadType, format!("Reserved marker {:#04x}", v)))
+            };
+
+            try!(self.discard(size));
+        }
+
+        Ok(())
+    }
+
+    // read and throw away size bytes
+    fn discard(&mut self, mut size: usize) -> Result<(), Error> {
+        let mut buf = [0; KEY_BYTES];
+
+        while size > 0 {
+            let chunk = ::std::cmp::min(size, KEY_BYTES);
+            try!(self.input(&mut buf[..chunk]));
+            size -= chunk;
+        }
+
+        Ok(())
+    }
+
+    fn read_marker(&mut self) -> Result<u8, Error> {
+        if let Some(marker) = self.peeked.take() {
+            return Ok(marker);
+        }
+
+        let offset = self.position;
+        let mut buf = [0];
+        try!(self.input(&mut buf));
+
+        if let Some(ref mut observer) = self.observer {
+            if let Ok(ty) = marker_type(buf[0]) {
+                observer(ty, offset);
+            }
+        }
+
+        Ok(buf[0])
+    }
+
+    fn peek_marker(&mut self) -> Result<u8, Error> {
+        let marker = try!(self.read_marker());
+        self.peeked = Some(marker);
+        Ok(marker)
+    }
+
+    fn check_strict(&self, marker: u8, float: bool) -> Result<(), Error> {
+        if !self.config.strict_types || (marker == FLOAT32 || marker == FLOAT64) == float {
+            return Ok(());
+        }
+
+        if float {
+            Err(Error::new(Reason::BadType, format!("Expected a float, found marker {:#04x}", marker)))
+        } else {
+            Err(Error::new(Reason::BadType, "Expected an integer, found a float".into()))
+        }
+    }
+
+    fn input(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        try!((self.input)(buf));
+        self.position += buf.len();
+
+        if self.recording > 0 {
+            self.record.extend_from_slice(buf);
+        }
+
+        Ok(())
+    }
+
+    fn begin_record(&mut self) -> usize {
+        self.recording += 1;
+
+        // a peeked marker is the start of the value being captured
+        match self.peeked {
+            Some(marker) if self.recording == 1 => {
+                self.record.push(marker);
+                self.record.len() - 1
+            }
+            // already recorded by an outer capture
+            Some(_) => self.record.len() - 1,
+            None => self.record.len()
+        }
+    }
+
+    fn end_record(&mut self, start: usize) -> Vec<u8> {
+        self.recording -= 1;
+
+        if self.recording == 0 {
+            // outermost capture, so the whole buffer is ours
+            ::std::mem::replace(&mut self.record, Vec::new())
+        } else {
+            self.record[start..].to_vec()
+        }
+    }
+
+    fn parse_as<V>(&mut self, mut visitor: V, ty: u8) -> Result<V::Value, Error> 
+        where V: serde::de::Visitor {
+        match ty {
+            v if POS_FIXINT.contains(&v) => {
+                visitor.visit_u8(v)
+            }
+            v if NEG_FIXINT.contains(&v) => {
+                visitor.visit_i8(v as i8)
+            }
+            v if FIXMAP.contains(&v) => {
+                let size = (v & !FIXMAP_MASK) as usize * 2;
+                try!(self.charge(size));
+                visitor.visit_map(SeqVisitor::new(self, size))
+            }
+            v if FIXARRAY.contains(&v) => {
+                let size = (v & !FIXARRAY_MASK) as usize;
+                try!(self.charge(size));
+                visitor.visit_seq(SeqVisitor::new(self, size))
+            }
+            v if FIXSTR.contains(&v) => self.visit_str(visitor, v),
+            NIL => visitor.visit_none(),
+            FALSE => visitor.visit_bool(false),
+            TRUE => visitor.visit_bool(true),
+            BIN8 | BIN16 | BIN32 => {
+                let size = try!(self.bin_len(ty));
+                try!(self.charge(size));
+                let mut buf = vec![0; size];
                 try!(self.input(buf.as_mut_slice()));
-                visitor.visit_map(ExtVisitor {
-                    state: 0,
-                    ty: ty,
-                    data: buf
-                })
+                visitor.visit_byte_buf(buf)
+            }
+            FIXEXT1 | FIXEXT2 | FIXEXT4 | FIXEXT8 | FIXEXT16 | EXT8 | EXT16 | EXT32 => {
+                let (ext_ty, data) = try!(self.read_ext(ty));
+                self.visit_ext(visitor, ext_ty, data)
+            }
+            FLOAT32 => {
+                let mut buf = [0; U32_BYTES];
+                try!(self.input(&mut buf));
+                visitor.visit_f32(f32::from_be_bytes(buf))
+            }
+            FLOAT64 => {
+                let mut buf = [0; U64_BYTES];
+                try!(self.input(&mut buf));
+                visitor.visit_f64(f64::from_be_bytes(buf))
             }
-            FIXEXT16 => {
+            UINT8 => {
                 let mut buf = [0];
                 try!(self.input(&mut buf));
-                let ty: i8 = LittleEndian::read_i16(&[buf[0], 0]) as i8;
-                let mut buf = vec![0; 16];
-                try!(self.input(buf.as_mut_slice()));
-                visitor.visit_map(ExtVisitor {
-                    state: 0,
-                    ty: ty,
-                    data: buf
-                })
+                visitor.visit_u8(buf[0])
             }
-            STR8 => {
+            UINT16 => {
+                let mut buf = [0; U16_BYTES];
+                try!(self.input(&mut buf));
+                visitor.visit_u16(u16::from_be_bytes(buf))
+            }
+            UINT32 => {
+                let mut buf = [0; U32_BYTES];
+                try!(self.input(&mut buf));
+                visitor.visit_u32(u32::from_be_bytes(buf))
+            }
+            UINT64 => {
+                let mut buf = [0; U64_BYTES];
+                try!(self.input(&mut buf));
+                visitor.visit_u64(u64::from_be_bytes(buf))
+            }
+            INT8 => {
                 let mut buf = [0];
                 try!(self.input(&mut buf));
-                let mut buf = vec![0; buf[0] as usize];
-                try!(self.input(buf.as_mut_slice()));
-                visitor.visit_string(
-                    try!(String::from_utf8(buf)
-                         .map_err(|e| Error::new(Reason::UTF8Error, format!("{}", e)))))
+                visitor.visit_i8(buf[0] as i8)
             }
-            STR16 => {
+            INT16 => {
                 let mut buf = [0; U16_BYTES];
                 try!(self.input(&mut buf));
-                let mut buf = vec![0; BigEndian::read_u16(&buf) as usize];
-                try!(self.input(buf.as_mut_slice()));
-                visitor.visit_string(
-                    try!(String::from_utf8(buf)
-                         .map_err(|e| Error::new(Reason::UTF8Error, format!("{}", e)))))
+                visitor.visit_i16(i16::from_be_bytes(buf))
             }
-            STR32 => {
+            INT32 => {
                 let mut buf = [0; U32_BYTES];
                 try!(self.input(&mut buf));
-                let mut buf = vec![0; BigEndian::read_u32(&buf) as usize];
-                try!(self.input(buf.as_mut_slice()));
-                visitor.visit_string(
-                    try!(String::from_utf8(buf)
-                         .map_err(|e| Error::new(Reason::UTF8Error, format!("{}", e)))))
+                visitor.visit_i32(i32::from_be_bytes(buf))
             }
+            INT64 => {
+                let mut buf = [0; U64_BYTES];
+                try!(self.input(&mut buf));
+                visitor.visit_i64(i64::from_be_bytes(buf))
+            }
+            STR8 | STR16 | STR32 => self.visit_str(visitor, ty),
             ARRAY16 => {
                 let mut buf = [0; U16_BYTES];
                 try!(self.input(&mut buf));
-                let size = BigEndian::read_u16(&buf);
+                let size = u16::from_be_bytes(buf);
+                try!(self.charge(size as usize));
                 visitor.visit_seq(SeqVisitor::new(self, size as usize))
             }
             ARRAY32 => {
                 let mut buf = [0; U32_BYTES];
                 try!(self.input(&mut buf));
-                let size = BigEndian::read_u32(&buf);
-                visitor.visit_seq(SeqVisitor::new(self, size as usize))
+                let size = try!(length(u32::from_be_bytes(buf) as u64));
+                try!(self.charge(size));
+                visitor.visit_seq(SeqVisitor::new(self, size))
             }
             MAP16 => {
                 let mut buf = [0; U16_BYTES];
                 try!(self.input(&mut buf));
-                let size = BigEndian::read_u16(&buf);
-                visitor.visit_map(SeqVisitor::new(self, size as usize * 2))
+                let size = try!(length(u16::from_be_bytes(buf) as u64 * 2));
+                try!(self.charge(size));
+                visitor.visit_map(SeqVisitor::new(self, size))
             }
             MAP32 => {
-                let mut buf = [0; U16_BYTES];
+                let mut buf = [0; U32_BYTES];
                 try!(self.input(&mut buf));
-                let size = BigEndian::read_u32(&buf);
-                visitor.visit_map(SeqVisitor::new(self, size as usize * 2))
+                let size = try!(length(u32::from_be_bytes(buf) as u64 * 2));
+                try!(self.charge(size));
+                visitor.visit_map(SeqVisitor::new(self, size))
             }
             _ => {
                 Err(Error::simple(Reason::BadType))
@@ -711,8 +1803,18 @@ impl<F: FnMut(&mut [u8]) -> Result<(), Error>> Deserializer<F> {
 
 #[cfg(test)]
 mod test {
-    use collections::{String, Vec};
-    use collections::btree_map::BTreeMap;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use alloc::collections::btree_map::BTreeMap;
+
+    use serde::Deserialize;
+
+    use error::{Error, Reason};
+    use super::{DeserializerConfig, length, length_within, variant_index_within};
+
+    fn from_bytes_strict<V: Deserialize>(bytes: &[u8]) -> Result<V, Error> {
+        ::from_bytes_with_config(bytes, DeserializerConfig { strict_types: true, ..DeserializerConfig::new() })
+    }
 
     #[test]
     fn positive_fixint_test() {
@@ -732,6 +1834,677 @@ mod test {
         assert_eq!(value, 154);
     }
 
+    #[test]
+    fn float32_nan_test() {
+        let value: f32 = ::from_bytes(&[0xca, 0x7f, 0xc0, 0x00, 0x00]).unwrap();
+        assert!(value.is_nan());
+        assert_eq!(value.to_bits(), 0x7fc0_0000);
+
+        // narrower NaNs stay NaN when widened
+        let value: f64 = ::from_bytes(&[0xca, 0xff, 0xc0, 0x00, 0x00]).unwrap();
+        assert!(value.is_nan());
+        assert!(value.is_sign_negative());
+    }
+
+    #[test]
+    fn float64_nan_test() {
+        let value: f64 = ::from_bytes(&[0xcb, 0x7f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]).unwrap();
+        assert!(value.is_nan());
+        assert!(value.is_sign_positive());
+
+        let value: f64 = ::from_bytes(&[0xcb, 0xff, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]).unwrap();
+        assert!(value.is_nan());
+        assert!(value.is_sign_negative());
+
+        // the quiet bit is clear here, and must stay clear
+        let value: f64 = ::from_bytes(&[0xcb, 0x7f, 0xf0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]).unwrap();
+        assert!(value.is_nan());
+        assert_eq!(value.to_bits(), 0x7ff0_0000_0000_0001);
+    }
+
+    #[test]
+    fn strict_types_test() {
+        // lenient by default
+        let value: f64 = ::from_bytes(&[0x05]).unwrap();
+        assert_eq!(value, 5.0);
+        let value: f32 = ::from_bytes(&[0xd0, 0xd6]).unwrap();
+        assert_eq!(value, -42.0);
+
+        assert_eq!(from_bytes_strict::<f64>(&[0x05]).unwrap_err().reason(), Reason::BadType);
+        assert_eq!(from_bytes_strict::<f32>(&[0xd0, 0xd6]).unwrap_err().reason(), Reason::BadType);
+        assert_eq!(from_bytes_strict::<i32>(&[0xcb, 0x40, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+                   .unwrap_err().reason(), Reason::BadType);
+        assert_eq!(from_bytes_strict::<u8>(&[0xca, 0x40, 0xa0, 0x00, 0x00]).unwrap_err().reason(), Reason::BadType);
+
+        // matching markers still decode
+        assert_eq!(from_bytes_strict::<f64>(&[0xcb, 0x40, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]).unwrap(), 5.0);
+        assert_eq!(from_bytes_strict::<f32>(&[0xca, 0x40, 0xa0, 0x00, 0x00]).unwrap(), 5.0);
+        assert_eq!(from_bytes_strict::<i32>(&[0xd0, 0xd6]).unwrap(), -42);
+        assert_eq!(from_bytes_strict::<u8>(&[0x05]).unwrap(), 5);
+    }
+
+    #[test]
+    fn strict_float_precision_test() {
+        let config = DeserializerConfig { strict_float_precision: true, ..DeserializerConfig::new() };
+        let tenth = [0xcb, 0x3f, 0xb9, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a];
+
+        // lenient by default, rounding to the nearest f32
+        let value: f32 = ::from_bytes(&tenth).unwrap();
+        assert_eq!(value, 0.1f32);
+
+        let result: Result<f32, Error> = ::from_bytes_with_config(&tenth, config);
+        assert_eq!(result.unwrap_err().reason(), Reason::PrecisionLoss);
+
+        // values an f32 holds exactly still decode, and so does NaN
+        let value: f32 = ::from_bytes_with_config(&[0xcb, 0x3f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], config).unwrap();
+        assert_eq!(value, 1.5);
+        let value: f32 = ::from_bytes_with_config(&[0xcb, 0x7f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], config).unwrap();
+        assert!(value.is_nan());
+
+        // an f64 target takes the full precision
+        let value: f64 = ::from_bytes_with_config(&tenth, config).unwrap();
+        assert_eq!(value, 0.1);
+    }
+
+    #[test]
+    fn usize_test() {
+        let value: usize = ::from_bytes(&[0xce, 0xff, 0xff, 0xff, 0xff]).unwrap();
+        assert_eq!(value, u32::max_value() as usize);
+        let value: usize = ::from_bytes(&[0xd0, 0x05]).unwrap();
+        assert_eq!(value, 5);
+        let value: isize = ::from_bytes(&[0xd0, 0xfb]).unwrap();
+        assert_eq!(value, -5);
+
+        let value: Result<usize, Error> = ::from_bytes(&[0xff]);
+        assert_eq!(value.unwrap_err().reason(), Reason::OutOfRange);
+        let value: Result<isize, Error> = ::from_bytes(&[0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(value.unwrap_err().reason(), Reason::OutOfRange);
+    }
+
+    #[test]
+    fn negative_unsigned_test() {
+        let value: Result<u32, Error> = ::from_bytes(&[0xff]);
+        assert_eq!(value.unwrap_err().reason(), Reason::OutOfRange);
+        let value: i32 = ::from_bytes(&[0xff]).unwrap();
+        assert_eq!(value, -1);
+
+        let value: Result<u8, Error> = ::from_bytes(&[0xd0, 0x80]);
+        assert_eq!(value.unwrap_err().reason(), Reason::OutOfRange);
+        let value: Result<u16, Error> = ::from_bytes(&[0xd1, 0xff, 0xfe]);
+        assert_eq!(value.unwrap_err().reason(), Reason::OutOfRange);
+        let value: Result<u64, Error> = ::from_bytes(&[0xd3, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(value.unwrap_err().reason(), Reason::OutOfRange);
+
+        // signed encodings of non-negative values are fine
+        let value: u32 = ::from_bytes(&[0xd1, 0x01, 0x00]).unwrap();
+        assert_eq!(value, 256);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "32")]
+    fn usize_32_test() {
+        // written by a 64-bit producer, too big for a 32-bit usize or isize
+        let bytes = [0xcf, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+        let value: Result<usize, Error> = ::from_bytes(&bytes);
+        assert_eq!(value.unwrap_err().reason(), Reason::OutOfRange);
+        let value: Result<isize, Error> = ::from_bytes(&bytes);
+        assert_eq!(value.unwrap_err().reason(), Reason::OutOfRange);
+
+        let value: Result<isize, Error> = ::from_bytes(&[0xd3, 0xff, 0xff, 0xff, 0xfe, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(value.unwrap_err().reason(), Reason::OutOfRange);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn usize_64_test() {
+        let bytes = [0xcf, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+        let value: usize = ::from_bytes(&bytes).unwrap();
+        assert_eq!(value, 1 << 32);
+        let value: isize = ::from_bytes(&bytes).unwrap();
+        assert_eq!(value, 1 << 32);
+    }
+
+    #[test]
+    fn length_test() {
+        assert_eq!(length(u32::max_value() as u64 * 2).map_err(|e| e.reason()),
+                   if cfg!(target_pointer_width = "64") { Ok(u32::max_value() as usize * 2) } else { Err(Reason::TooBig) });
+        assert_eq!(length(u64::max_value()).map_err(|e| e.reason()),
+                   if cfg!(target_pointer_width = "64") { Ok(usize::max_value()) } else { Err(Reason::TooBig) });
+    }
+
+    #[test]
+    fn length_32_test() {
+        // what a 32-bit target sees for the largest lengths on the wire
+        let max = u32::max_value() as u64;
+        assert_eq!(length_within(0xffff, max).unwrap(), 0xffff);
+        assert_eq!(length_within(max, max).unwrap(), u32::max_value() as usize);
+
+        // map32 counts keys and values, so its largest length is double
+        assert_eq!(length_within(max * 2, max).unwrap_err().reason(), Reason::TooBig);
+        assert_eq!(length_within(max + 1, max).unwrap_err().reason(), Reason::TooBig);
+    }
+
+    #[test]
+    fn variant_index_test() {
+        use test_types::T;
+
+        // uint64 index 1, which is T::B
+        let value: T = ::from_bytes(&[0xcf, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]).unwrap();
+        assert_eq!(value, T::B);
+
+        // one past what a 32-bit usize holds must not wrap around to T::B
+        let bytes = [0xcf, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01];
+        assert!(::from_bytes::<T>(&bytes).is_err());
+
+        // which is what a 32-bit target sees for it
+        let max = u32::max_value() as u64;
+        assert_eq!(variant_index_within(max, max).unwrap(), u32::max_value() as usize);
+        assert_eq!(variant_index_within(max + 2, max).unwrap_err().reason(), Reason::OutOfRange);
+    }
+
+    #[test]
+    fn array32_max_length_test() {
+        // claims u32::MAX elements but holds only one, so must fail cleanly
+        // rather than preallocating for all of them
+        let value: Result<Vec<u8>, Error> = ::from_bytes(&[0xdd, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        assert_eq!(value.unwrap_err().reason(), Reason::EndOfStream);
+    }
+
+    #[test]
+    fn map32_max_length_test() {
+        let value: Result<BTreeMap<u8, u8>, Error> = ::from_bytes(&[0xdf, 0xff, 0xff, 0xff, 0xff, 0x01, 0x02]);
+        let expected = if cfg!(target_pointer_width = "64") { Reason::EndOfStream } else { Reason::TooBig };
+        assert_eq!(value.unwrap_err().reason(), expected);
+    }
+
+    #[test]
+    fn max_key_len_test() {
+        let config = DeserializerConfig { max_key_len: Some(4), ..DeserializerConfig::new() };
+
+        // {"abcd": "a long value is fine"}
+        let mut bytes = vec![0x81, 0xa4, 0x61, 0x62, 0x63, 0x64, 0xb4];
+        bytes.extend_from_slice(b"a long value is fine");
+        let map: BTreeMap<String, String> = ::from_bytes_with_config(bytes.as_slice(), config).unwrap();
+        assert_eq!(map["abcd"], "a long value is fine");
+
+        // {"abcde": "v"}
+        let bytes = [0x81, 0xa5, 0x61, 0x62, 0x63, 0x64, 0x65, 0xa1, 0x76];
+        let result: Result<BTreeMap<String, String>, Error> = ::from_bytes_with_config(&bytes, config);
+        assert_eq!(result.unwrap_err().reason(), Reason::LimitExceeded);
+
+        // non-str keys don't carry the check over to their values
+        let bytes = [0x81, 0x01, 0xa5, 0x61, 0x62, 0x63, 0x64, 0x65];
+        let map: BTreeMap<u8, String> = ::from_bytes_with_config(&bytes, config).unwrap();
+        assert_eq!(map[&1], "abcde");
+    }
+
+    #[test]
+    fn read_len_test() {
+        use serde::Serialize;
+
+        use super::Deserializer;
+
+        let mut bytes = vec![];
+
+        {
+            let mut ser = ::Serializer::new(|buf: &[u8]| {
+                bytes.extend_from_slice(buf);
+                Ok(())
+            });
+
+            ser.write_array_len(2).unwrap();
+            ser.write_map_len(20).unwrap();
+            for i in 0..20u8 {
+                i.serialize(&mut ser).unwrap();
+                (i * 2).serialize(&mut ser).unwrap();
+            }
+            ser.write_str_len(40).unwrap();
+            ser.write_raw(&[0x61; 40]).unwrap();
+            ser.write_bin_len(3).unwrap();
+            ser.write_raw(&[1, 2, 3]).unwrap();
+        }
+
+        let mut position = 0;
+        let mut de = Deserializer::new(|buf: &mut [u8]| ::read_slice(&bytes, &mut position, buf));
+
+        assert_eq!(de.read_array_len().unwrap(), 2);
+        assert_eq!(de.read_map_len().unwrap(), 20);
+        for i in 0..20u8 {
+            assert_eq!(u8::deserialize(&mut de).unwrap(), i);
+            assert_eq!(u8::deserialize(&mut de).unwrap(), i * 2);
+        }
+
+        let mut body = [0; 40];
+        assert_eq!(de.read_str_len().unwrap(), 40);
+        de.read_raw(&mut body).unwrap();
+        assert_eq!(&body[..], &[0x61; 40][..]);
+
+        let mut body = [0; 3];
+        assert_eq!(de.read_bin_len().unwrap(), 3);
+        de.read_raw(&mut body).unwrap();
+        assert_eq!(body, [1, 2, 3]);
+
+        // a header of the wrong type
+        let mut position = 0;
+        let mut de = Deserializer::new(|buf: &mut [u8]| ::read_slice(&[0xa1, 0x61], &mut position, buf));
+        assert_eq!(de.read_array_len().unwrap_err().reason(), Reason::BadType);
+    }
+
+    #[test]
+    fn read_raw_test() {
+        use super::{Deserializer, MsgpackType};
+
+        // "hello", then 3 bytes that aren't a value at all
+        let bytes = [0xa5, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x01, 0x02, 0x03];
+        let mut position = 0;
+        let mut de = Deserializer::new(|buf: &mut [u8]| ::read_slice(&bytes, &mut position, buf));
+
+        let mut body = [0; 5];
+        assert_eq!(de.read_str_len().unwrap(), 5);
+        de.read_raw(&mut body).unwrap();
+        assert_eq!(&body, b"hello");
+
+        // a peeked byte is handed back first
+        assert_eq!(de.peek_type().unwrap(), MsgpackType::Uint);
+        let mut rest = [0; 2];
+        de.read_raw(&mut rest).unwrap();
+        assert_eq!(rest, [0x01, 0x02]);
+        assert_eq!(de.position(), 8);
+
+        let mut more = [0; 2];
+        assert_eq!(de.read_raw(&mut more).unwrap_err().reason(), Reason::EndOfStream);
+    }
+
+    #[test]
+    fn option_peek_test() {
+        use super::Deserializer;
+
+        // Some([1, 2, 3]), None, Some(array16 of 300 zeros), Some("a")
+        let mut bytes = vec![0x93, 0x01, 0x02, 0x03, 0xc0, 0xdc, 0x01, 0x2c];
+        bytes.extend(vec![0x00; 300]);
+        bytes.extend(&[0xa1, 0x61]);
+
+        let mut position = 0;
+        let mut de = Deserializer::new(|buf: &mut [u8]| ::read_slice(&bytes, &mut position, buf));
+
+        // the inner value must see its own marker, or the array header is lost
+        assert_eq!(Option::<Vec<u32>>::deserialize(&mut de).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(de.position(), 4);
+        assert_eq!(Option::<Vec<u32>>::deserialize(&mut de).unwrap(), None);
+        assert_eq!(de.position(), 5);
+        assert_eq!(Option::<Vec<u32>>::deserialize(&mut de).unwrap(), Some(vec![0; 300]));
+        assert_eq!(Option::<String>::deserialize(&mut de).unwrap(), Some("a".into()));
+        assert_eq!(de.position(), bytes.len());
+    }
+
+    // peeking, skipping and the position are the Deserializer's, whatever the
+    // input function reads from
+    fn check_source<'o, F>(mut de: super::Deserializer<'o, F>)
+        where F: FnMut(&mut [u8]) -> Result<(), Error> {
+        use super::MsgpackType;
+
+        assert_eq!(de.peek_type().unwrap(), MsgpackType::Array);
+        assert_eq!(de.position(), 1);
+        assert_eq!(de.read_array_len().unwrap(), 2);
+        assert_eq!(u16::deserialize(&mut de).unwrap(), 300);
+        assert_eq!(de.position(), 4);
+
+        assert_eq!(de.peek_type().unwrap(), MsgpackType::Str);
+        de.skip().unwrap();
+        assert_eq!(de.position(), 7);
+
+        assert_eq!(u8::deserialize(&mut de).unwrap(), 7);
+        assert_eq!(de.position(), 8);
+        assert_eq!(de.peek_type().unwrap_err().reason(), Reason::EndOfStream);
+    }
+
+    #[test]
+    fn source_test() {
+        use std::io::{Cursor, Read};
+
+        use super::Deserializer;
+
+        // [300, "hi"], 7
+        let bytes = [0x92, 0xcd, 0x01, 0x2c, 0xa2, 0x68, 0x69, 0x07];
+
+        let mut position = 0;
+        check_source(Deserializer::new(|buf: &mut [u8]| ::read_slice(&bytes, &mut position, buf)));
+
+        let mut iter = bytes.iter().cloned();
+        check_source(Deserializer::new(|buf: &mut [u8]| ::read_iter(&mut iter, buf)));
+
+        let mut reader = Cursor::new(&bytes[..]);
+        check_source(Deserializer::new(|buf: &mut [u8]| {
+            reader.read_exact(buf).map_err(|_| Error::simple(Reason::EndOfStream))
+        }));
+    }
+
+    #[test]
+    fn peek_type_test() {
+        use serde::de::impls::IgnoredAny;
+
+        use super::{Deserializer, MsgpackType};
+
+        // 1, -1, 1.5, "a", bin [1], [nil], {1: 2}, nil, false, ext 1 [0]
+        let bytes = [0x01, 0xff, 0xca, 0x3f, 0xc0, 0x00, 0x00, 0xa1, 0x61, 0xc4, 0x01, 0x01,
+                     0x91, 0xc0, 0x81, 0x01, 0x02, 0xc0, 0xc2, 0xd4, 0x01, 0x00];
+        let mut position = 0;
+        let mut de = Deserializer::new(|buf: &mut [u8]| ::read_slice(&bytes, &mut position, buf));
+
+        assert_eq!(de.peek_type().unwrap(), MsgpackType::Uint);
+        // peeking again doesn't move past it
+        assert_eq!(de.peek_type().unwrap(), MsgpackType::Uint);
+        assert_eq!(u8::deserialize(&mut de).unwrap(), 1);
+
+        assert_eq!(de.peek_type().unwrap(), MsgpackType::Int);
+        assert_eq!(i8::deserialize(&mut de).unwrap(), -1);
+
+        assert_eq!(de.peek_type().unwrap(), MsgpackType::Float);
+        assert_eq!(f32::deserialize(&mut de).unwrap(), 1.5);
+
+        assert_eq!(de.peek_type().unwrap(), MsgpackType::Str);
+        assert_eq!(String::deserialize(&mut de).unwrap(), "a");
+
+        assert_eq!(de.peek_type().unwrap(), MsgpackType::Bin);
+        assert_eq!(::bytes::deserialize(&mut de).unwrap(), &[1]);
+
+        assert_eq!(de.peek_type().unwrap(), MsgpackType::Array);
+        assert_eq!(Vec::<Option<u8>>::deserialize(&mut de).unwrap(), &[None]);
+
+        assert_eq!(de.peek_type().unwrap(), MsgpackType::Map);
+        assert_eq!(BTreeMap::<u8, u8>::deserialize(&mut de).unwrap()[&1], 2);
+
+        assert_eq!(de.peek_type().unwrap(), MsgpackType::Nil);
+        <()>::deserialize(&mut de).unwrap();
+
+        assert_eq!(de.peek_type().unwrap(), MsgpackType::Bool);
+        assert!(!bool::deserialize(&mut de).unwrap());
+
+        assert_eq!(de.peek_type().unwrap(), MsgpackType::Ext);
+        IgnoredAny::deserialize(&mut de).unwrap();
+
+        assert_eq!(de.peek_type().unwrap_err().reason(), Reason::EndOfStream);
+
+        let mut position = 0;
+        let mut de = Deserializer::new(|buf: &mut [u8]| ::read_slice(&[0xc1], &mut position, buf));
+        assert_eq!(de.peek_type().unwrap_err().reason(), Reason::BadType);
+    }
+
+    #[test]
+    fn legacy_raw_test() {
+        use bytes::ByteBuf;
+        use super::LegacyRaw;
+
+        // raw16 of [0x01, 0xff, 0x02], which is not UTF-8
+        let bytes = [0xda, 0x00, 0x03, 0x01, 0xff, 0x02];
+
+        let config = DeserializerConfig { legacy_raw: LegacyRaw::Bytes, ..DeserializerConfig::new() };
+        let value: ByteBuf = ::from_bytes_with_config(&bytes, config).unwrap();
+        assert_eq!(value.0, &[0x01, 0xff, 0x02]);
+
+        // fixraw, and still a string where one is wanted
+        let value: String = ::from_bytes_with_config(&[0xa2, 0x68, 0x69], config).unwrap();
+        assert_eq!(value, "hi");
+
+        let config = DeserializerConfig { legacy_raw: LegacyRaw::Str, ..DeserializerConfig::new() };
+        let value: String = ::from_bytes_with_config(&bytes, config).unwrap();
+        assert_eq!(value, "\u{1}\u{fffd}\u{2}");
+
+        // the modern reading fails either way
+        let value: Result<ByteBuf, Error> = ::from_bytes(&bytes);
+        assert!(value.is_err());
+        let value: Result<String, Error> = ::from_bytes(&bytes);
+        assert_eq!(value.unwrap_err().reason(), Reason::UTF8Error);
+    }
+
+    #[test]
+    fn duplicate_keys_test() {
+        use super::DuplicateKeys;
+
+        // {"a": 1, "b": 2, "a": 3}
+        let bytes = [0x83, 0xa1, 0x61, 0x01, 0xa1, 0x62, 0x02, 0xa1, 0x61, 0x03];
+
+        let map: BTreeMap<String, u8> = ::from_bytes(&bytes).unwrap();
+        assert_eq!((map["a"], map["b"]), (3, 2));
+
+        let config = DeserializerConfig { duplicate_keys: DuplicateKeys::KeepFirst, ..DeserializerConfig::new() };
+        let map: BTreeMap<String, u8> = ::from_bytes_with_config(&bytes, config).unwrap();
+        assert_eq!((map["a"], map["b"]), (1, 2));
+        assert_eq!(map.len(), 2);
+
+        let config = DeserializerConfig { duplicate_keys: DuplicateKeys::Reject, ..DeserializerConfig::new() };
+        let result: Result<BTreeMap<String, u8>, Error> = ::from_bytes_with_config(&bytes, config);
+        assert_eq!(result.unwrap_err().reason(), Reason::DuplicateKey);
+
+        // keys are only compared within their own map
+        let bytes = [0x92, 0x81, 0xa1, 0x61, 0x01, 0x81, 0xa1, 0x61, 0x02];
+        let maps: Vec<BTreeMap<String, u8>> = ::from_bytes_with_config(&bytes, config).unwrap();
+        assert_eq!((maps[0]["a"], maps[1]["a"]), (1, 2));
+
+        // plenty of distinct keys, which are looked up rather than scanned
+        let large: BTreeMap<u32, u8> = (0..50000).map(|k| (k, 0)).collect();
+        let bytes = ::to_bytes(&large).unwrap();
+        let map: BTreeMap<u32, u8> = ::from_bytes_with_config(&bytes, config).unwrap();
+        assert_eq!(map, large);
+    }
+
+    #[test]
+    fn duplicate_keys_config_test() {
+        use super::DuplicateKeys;
+
+        // {"ab": 1, "ab": 2}, charged 4 for the entries and 2 for the one key
+        // that is decoded
+        let bytes = [0x82, 0xa2, 0x61, 0x62, 0x01, 0xa2, 0x61, 0x62, 0x02];
+        let config = DeserializerConfig { duplicate_keys: DuplicateKeys::KeepFirst, max_total_len: Some(6), ..DeserializerConfig::new() };
+        let map: BTreeMap<String, u8> = ::from_bytes_with_config(&bytes, config).unwrap();
+        assert_eq!(map["ab"], 1);
+
+        let config = DeserializerConfig { max_total_len: Some(5), ..config };
+        let result: Result<BTreeMap<String, u8>, Error> = ::from_bytes_with_config(&bytes, config);
+        assert_eq!(result.unwrap_err().reason(), Reason::LimitExceeded);
+
+        // keys are decoded under the same strictness as everything else
+        let bytes = [0x81, 0x01, 0x02];
+        let config = DeserializerConfig { duplicate_keys: DuplicateKeys::Reject, strict_types: true, ..DeserializerConfig::new() };
+        let result: Result<BTreeMap<u8, u8>, Error> = ::from_bytes_with_config(&bytes, config);
+        assert_eq!(result.unwrap().get(&1), Some(&2));
+        let result: Result<BTreeMap<String, u8>, Error> = ::from_bytes_with_config(&bytes, config);
+        assert!(result.is_err());
+        let bytes = [0x81, 0xca, 0x3f, 0xc0, 0x00, 0x00, 0x02];
+        let result: Result<BTreeMap<u8, u8>, Error> = ::from_bytes_with_config(&bytes, config);
+        assert_eq!(result.unwrap_err().reason(), Reason::BadType);
+    }
+
+    #[test]
+    fn nested_map_test() {
+        use test_types::{Nested, Leaf};
+
+        let expected = Nested { a: Leaf { b: 1 }, c: 2 };
+
+        // {"a": {"b": 1}, "c": 2}
+        let value: Nested = ::from_bytes(&[0x82, 0xa1, 0x61, 0x81, 0xa1, 0x62, 0x01, 0xa1, 0x63, 0x02]).unwrap();
+        assert_eq!(value, expected);
+
+        // {"c": 2, "a": {"b": 1}}
+        let value: Nested = ::from_bytes(&[0x82, 0xa1, 0x63, 0x02, 0xa1, 0x61, 0x81, 0xa1, 0x62, 0x01]).unwrap();
+        assert_eq!(value, expected);
+
+        // {"a": {"x": {"y": [1, 2]}, "b": 1}, "z": {"w": 3}, "c": 2}, with
+        // unknown fields holding collections of their own
+        let value: Nested = ::from_bytes(&[0x83,
+                                           0xa1, 0x61, 0x82,
+                                           0xa1, 0x78, 0x81, 0xa1, 0x79, 0x92, 0x01, 0x02,
+                                           0xa1, 0x62, 0x01,
+                                           0xa1, 0x7a, 0x81, 0xa1, 0x77, 0x03,
+                                           0xa1, 0x63, 0x02]).unwrap();
+        assert_eq!(value, expected);
+
+        let value: BTreeMap<String, BTreeMap<String, u8>> = ::from_bytes(&[0x82,
+                                                                            0xa1, 0x61, 0x81, 0xa1, 0x62, 0x01,
+                                                                            0xa1, 0x63, 0x81, 0xa1, 0x64, 0x02]).unwrap();
+        assert_eq!(value["a"]["b"], 1);
+        assert_eq!(value["c"]["d"], 2);
+    }
+
+    #[test]
+    fn ignored_any_test() {
+        use serde::de::impls::IgnoredAny;
+
+        // [1, [2, {"x": 3.5}], "three", bin [4], 5]
+        let bytes = [0x95, 0x01,
+                     0x92, 0x02, 0x81, 0xa1, 0x78, 0xcb, 0x40, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0xa5, 0x74, 0x68, 0x72, 0x65, 0x65,
+                     0xc4, 0x01, 0x04,
+                     0x05];
+
+        let (a, _, c, _, e): (u8, IgnoredAny, String, IgnoredAny, u8) = ::from_bytes(&bytes).unwrap();
+        assert_eq!((a, c.as_str(), e), (1, "three", 5));
+
+        // ext and wide values are skipped whole too
+        let bytes = [0x93, 0xc7, 0x02, 0x01, 0xaa, 0xbb, 0xcf, 0, 0, 0, 0, 0, 0, 0, 1, 0x2a];
+        let (_, _, c): (IgnoredAny, IgnoredAny, u8) = ::from_bytes(&bytes).unwrap();
+        assert_eq!(c, 42);
+
+        // deep nesting doesn't recurse
+        let mut bytes = vec![0x92];
+        bytes.extend(vec![0x91; 100000]);
+        bytes.extend(&[0xc0, 0x07]);
+        let (_, b): (IgnoredAny, u8) = ::from_bytes(&bytes).unwrap();
+        assert_eq!(b, 7);
+
+        let result: Result<IgnoredAny, Error> = ::from_bytes(&[0x92, 0x01]);
+        assert_eq!(result.unwrap_err().reason(), Reason::EndOfStream);
+    }
+
+    #[test]
+    fn max_total_len_test() {
+        // 100 arrays of 10 elements, each one small on its own
+        let value = vec![vec![0u8; 10]; 100];
+        let bytes = ::to_bytes(&value).unwrap();
+
+        // the outer array's 100, plus 1000 inside
+        let config = DeserializerConfig { max_total_len: Some(1100), ..DeserializerConfig::new() };
+        assert_eq!(::from_bytes_with_config::<Vec<Vec<u8>>>(&bytes, config).unwrap(), value);
+
+        let config = DeserializerConfig { max_total_len: Some(1099), ..DeserializerConfig::new() };
+        let result: Result<Vec<Vec<u8>>, Error> = ::from_bytes_with_config(&bytes, config);
+        assert_eq!(result.unwrap_err().reason(), Reason::LimitExceeded);
+
+        // maps and strs count toward the same total: a key and a value, then
+        // 3 and 5 bytes
+        let config = DeserializerConfig { max_total_len: Some(10), ..DeserializerConfig::new() };
+        let bytes = [0x81, 0xa3, 0x6b, 0x65, 0x79, 0xa5, 0x76, 0x61, 0x6c, 0x75, 0x65];
+        assert!(::from_bytes_with_config::<BTreeMap<String, String>>(&bytes, config).is_ok());
+        let config = DeserializerConfig { max_total_len: Some(9), ..config };
+        let result: Result<BTreeMap<String, String>, Error> = ::from_bytes_with_config(&bytes, config);
+        assert_eq!(result.unwrap_err().reason(), Reason::LimitExceeded);
+
+        // so do struct field names: the field and its value, then the name
+        {
+            use test_types::Leaf;
+
+            let bytes = [0x81, 0xa1, 0x62, 0x01];
+            let config = DeserializerConfig { max_total_len: Some(3), ..DeserializerConfig::new() };
+            assert_eq!(::from_bytes_with_config::<Leaf>(&bytes, config).unwrap(), Leaf { b: 1 });
+            let config = DeserializerConfig { max_total_len: Some(2), ..config };
+            let result: Result<Leaf, Error> = ::from_bytes_with_config(&bytes, config);
+            assert_eq!(result.unwrap_err().reason(), Reason::LimitExceeded);
+        }
+
+        // and arrays read into a fixed capacity
+        #[cfg(feature = "arrayvec")]
+        {
+            use arrayvec::ArrayVec;
+
+            let bytes = [0x93, 0x01, 0x02, 0x03];
+            let decode = |max| {
+                let config = DeserializerConfig { max_total_len: Some(max), ..DeserializerConfig::new() };
+                let mut position = 0;
+                let mut de = super::Deserializer::with_config(|buf: &mut [u8]| ::read_slice(&bytes, &mut position, buf),
+                                                              config);
+                ::array_vec::deserialize::<[u8; 4], _>(&mut de)
+            };
+            assert_eq!(decode(3).unwrap().as_slice(), &[1, 2, 3]);
+            assert_eq!(decode(2).unwrap_err().reason(), Reason::LimitExceeded);
+        }
+
+        // a huge length fails before anything is allocated for it
+        let config = DeserializerConfig { max_total_len: Some(1 << 20), ..DeserializerConfig::new() };
+        let result: Result<Vec<u8>, Error> = ::from_bytes_with_config(&[0xdd, 0xff, 0xff, 0xff, 0xff], config);
+        assert_eq!(result.unwrap_err().reason(), Reason::LimitExceeded);
+    }
+
+    #[test]
+    fn max_ext_len_test() {
+        use value::Value;
+
+        let config = DeserializerConfig { max_ext_len: Some(4), ..DeserializerConfig::new() };
+
+        let value: Value = ::from_bytes_with_config(&[0xd6, 0x01, 0x00, 0x00, 0x00, 0x00], config).unwrap();
+        assert_eq!(value, Value::Ext(1, vec![0; 4]));
+
+        // fails on the length alone, before the type or data are read
+        let result: Result<Value, Error> = ::from_bytes_with_config(&[0xc9, 0xff, 0xff, 0xff, 0xff], config);
+        assert_eq!(result.unwrap_err().reason(), Reason::LimitExceeded);
+
+        // fixext lengths are held to it too
+        let result: Result<Value, Error> = ::from_bytes_with_config(&[0xd7, 0x01], config);
+        assert_eq!(result.unwrap_err().reason(), Reason::LimitExceeded);
+
+        // without a limit, the missing data is what fails
+        let result: Result<Value, Error> = ::from_bytes(&[0xc9, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(result.unwrap_err().reason(), Reason::EndOfStream);
+    }
+
+    #[test]
+    fn observe_test() {
+        use value::Value;
+        use super::{Deserializer, MsgpackType};
+
+        // {"a": [1, -2], "b": "x"}
+        let bytes = [0x82, 0xa1, 0x61, 0x92, 0x01, 0xff, 0xa1, 0x62, 0xa1, 0x78];
+        let mut position = 0;
+        let mut trace = Vec::new();
+        {
+            let mut de = Deserializer::new(|buf: &mut [u8]| ::read_slice(&bytes, &mut position, buf));
+            de.observe(|ty, offset| trace.push((ty, offset)));
+
+            let value = Value::deserialize(&mut de).unwrap();
+            assert_eq!(value, ::from_bytes::<Value>(&bytes).unwrap());
+            assert_eq!(de.position(), bytes.len());
+        }
+
+        assert_eq!(trace, &[(MsgpackType::Map, 0),
+                            (MsgpackType::Str, 1),
+                            (MsgpackType::Array, 3),
+                            (MsgpackType::Uint, 4),
+                            (MsgpackType::Int, 5),
+                            (MsgpackType::Str, 6),
+                            (MsgpackType::Str, 8)]);
+    }
+
+    // input that never runs out, for a Deserializer built at compile time
+    fn ones(buf: &mut [u8]) -> Result<(), Error> {
+        for byte in buf.iter_mut() {
+            *byte = 0x01;
+        }
+        Ok(())
+    }
+
+    const ONES: super::Deserializer<'static, fn(&mut [u8]) -> Result<(), Error>> = super::Deserializer::with_config(
+        ones as fn(&mut [u8]) -> Result<(), Error>,
+        DeserializerConfig { strict_types: true, ..DeserializerConfig::new() });
+
+    #[test]
+    fn const_new_test() {
+        let mut de = ONES;
+        assert_eq!(u8::deserialize(&mut de).unwrap(), 1);
+        assert_eq!(u64::deserialize(&mut de).unwrap(), 1);
+
+        // the config came along too
+        assert!(f32::deserialize(&mut de).is_err());
+    }
+
     #[test]
     fn fixstr_test() {
         let value: String = ::from_bytes(&[0xac, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x20,
@@ -782,5 +2555,31 @@ mod test {
         assert_eq!(map.remove(&format!("three")), Some(3));
         assert!(map.is_empty());
     }
-}
 
+    #[cfg(feature = "std")]
+    #[test]
+    fn read_bin_to_test() {
+        use bytes::Bytes;
+        use super::Deserializer;
+
+        let data: Vec<u8> = (0..1 << 20).map(|x| x as u8).collect();
+        let bytes = ::to_bytes((Bytes(data.as_slice()), "tail")).unwrap();
+        assert_eq!(&bytes[..6], &[0x92, 0xc6, 0x00, 0x10, 0x00, 0x00]);
+
+        // start past the array header
+        let mut position = 1;
+        let mut de = Deserializer::new(|buf: &mut [u8]| ::read_slice(bytes.as_slice(), &mut position, buf));
+
+        let mut out = Vec::new();
+        assert_eq!(de.read_bin_to(&mut out).unwrap(), 1 << 20);
+        assert!(out == data);
+
+        // the stream is left at the next value
+        let tail = String::deserialize(&mut de).unwrap();
+        assert_eq!(tail, "tail");
+
+        let mut out = Vec::new();
+        let result = de.read_bin_to(&mut out);
+        assert_eq!(result.unwrap_err().reason(), Reason::EndOfStream);
+    }
+}
@@ -0,0 +1,155 @@
+//! Exact decimals, written as an ext value.
+//!
+//! MessagePack has no decimal type, and an f64 can't hold most decimal
+//! fractions exactly. A `Decimal` is written as an ext value whose type is
+//! chosen by the application, as the const parameter, and whose data is a
+//! scale byte followed by the mantissa as a big-endian two's complement
+//! integer of one to eight bytes, as few as it fits in. The value is the
+//! mantissa divided by ten to the power of the scale, so 12345.6789 is a
+//! mantissa of 123456789 with a scale of 4.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::fmt;
+
+use alloc::vec::Vec;
+
+use serde;
+
+use defs::U64_BYTES;
+use value::Value;
+
+/// A decimal number, written as an ext value of type TY.
+///
+/// Equality compares the mantissa and scale, so 1.5 and 1.50 are not equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Decimal<const TY: i8> {
+    /// The digits of the number, with its sign.
+    pub mantissa: i64,
+    /// How many of the digits come after the decimal point.
+    pub scale: u8
+}
+
+impl<const TY: i8> Decimal<TY> {
+    /// Create a decimal of mantissa divided by ten to the power of scale.
+    pub const fn new(mantissa: i64, scale: u8) -> Decimal<TY> {
+        Decimal {
+            mantissa: mantissa,
+            scale: scale
+        }
+    }
+
+    fn to_ext(&self) -> Vec<u8> {
+        // the fewest bytes that keep the sign
+        let mut len = 1;
+        while len < U64_BYTES && (self.mantissa << (64 - len * 8)) >> (64 - len * 8) != self.mantissa {
+            len += 1;
+        }
+
+        let mut data = Vec::with_capacity(len + 1);
+        data.push(self.scale);
+        data.extend_from_slice(&self.mantissa.to_be_bytes()[U64_BYTES - len..]);
+        data
+    }
+
+    fn from_ext(data: &[u8]) -> Option<Decimal<TY>> {
+        if data.len() < 2 || data.len() > U64_BYTES + 1 {
+            return None;
+        }
+
+        let mut mantissa = if data[1] & 0x80 != 0 { -1 } else { 0 };
+        for &byte in &data[1..] {
+            mantissa = mantissa << 8 | byte as i64;
+        }
+
+        Some(Decimal::new(mantissa, data[0]))
+    }
+}
+
+impl<const TY: i8> fmt::Display for Decimal<TY> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        let digits = format!("{:01$}", self.mantissa.unsigned_abs(), self.scale as usize + 1);
+        let point = digits.len() - self.scale as usize;
+
+        if self.scale == 0 {
+            write!(f, "{}{}", sign, digits)
+        } else {
+            write!(f, "{}{}.{}", sign, &digits[..point], &digits[point..])
+        }
+    }
+}
+
+impl<const TY: i8> serde::Serialize for Decimal<TY> {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer {
+        serde::Serialize::serialize(&Value::Ext(TY, self.to_ext()), serializer)
+    }
+}
+
+impl<const TY: i8> serde::Deserialize for Decimal<TY> {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Decimal<TY>, D::Error>
+        where D: serde::Deserializer {
+        match try!(<Value as serde::Deserialize>::deserialize(deserializer)) {
+            Value::Ext(ty, ref data) if ty == TY => Decimal::from_ext(data).ok_or_else(|| {
+                serde::de::Error::invalid_value(&format!("Decimal data of {} bytes", data.len()))
+            }),
+            Value::Ext(ty, _) => Err(serde::de::Error::invalid_value(&format!("Ext type {} is not a decimal", ty))),
+            _ => Err(serde::de::Error::invalid_value("Decimals must be an ext value"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::{Error, Reason};
+
+    use super::Decimal;
+
+    type Money = Decimal<3>;
+
+    fn round_trip(value: Money, expected: &[u8]) {
+        let bytes = ::to_bytes(&value).unwrap();
+        assert_eq!(bytes, expected);
+
+        let actual: Money = ::from_bytes(&bytes).unwrap();
+        assert_eq!(actual, value);
+    }
+
+    #[test]
+    fn round_trip_test() {
+        round_trip(Money::new(123456789, 4), &[0xc7, 0x05, 0x03, 0x04, 0x07, 0x5b, 0xcd, 0x15]);
+        round_trip(Money::new(-123456789, 4), &[0xc7, 0x05, 0x03, 0x04, 0xf8, 0xa4, 0x32, 0xeb]);
+        round_trip(Money::new(5, 1), &[0xd5, 0x03, 0x01, 0x05]);
+        round_trip(Money::new(-128, 2), &[0xd5, 0x03, 0x02, 0x80]);
+        round_trip(Money::new(128, 2), &[0xc7, 0x03, 0x03, 0x02, 0x00, 0x80]);
+        round_trip(Money::new(i64::min_value(), 18),
+                   &[0xc7, 0x09, 0x03, 0x12, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        round_trip(Money::new(i64::max_value(), 0),
+                   &[0xc7, 0x09, 0x03, 0x00, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn display_test() {
+        assert_eq!(format!("{}", Money::new(123456789, 4)), "12345.6789");
+        assert_eq!(format!("{}", Money::new(-123456789, 4)), "-12345.6789");
+        assert_eq!(format!("{}", Money::new(-5, 3)), "-0.005");
+        assert_eq!(format!("{}", Money::new(42, 0)), "42");
+    }
+
+    #[test]
+    fn wrong_ext_test() {
+        // ext type 4 is something else
+        let result: Result<Money, Error> = ::from_bytes(&[0xd5, 0x04, 0x01, 0x05]);
+        assert_eq!(result.unwrap_err().reason(), Reason::BadValue);
+
+        // no mantissa
+        let result: Result<Money, Error> = ::from_bytes(&[0xd4, 0x03, 0x01]);
+        assert_eq!(result.unwrap_err().reason(), Reason::BadValue);
+
+        let result: Result<Money, Error> = ::from_bytes(&[0xcb, 0x3f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(result.unwrap_err().reason(), Reason::BadValue);
+    }
+}
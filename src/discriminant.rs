@@ -0,0 +1,90 @@
+//! Writing unit enums by their discriminant instead of their variant index.
+//!
+//! serde hands the Serializer a variant's index, its position in the enum,
+//! and never the discriminant, so `enum E { A = 10, B = 20 }` is written as 0
+//! and 1 rather than 10 and 20. A protocol that puts discriminants on the wire
+//! needs the enum to say how to convert to and from them, by implementing
+//! `Discriminant`. Pointing a field's `serialize_with` and `deserialize_with`
+//! at `serialize` and `deserialize` here then writes the discriminant as an
+//! integer, and reads it back.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use serde;
+
+/// A fieldless enum that converts to and from its discriminant.
+///
+/// `discriminant` is usually just `*self as i64`, and `from_discriminant` a
+/// match over the variants.
+pub trait Discriminant: Sized {
+    /// The discriminant of this variant.
+    fn discriminant(&self) -> i64;
+
+    /// The variant with this discriminant, if there is one.
+    fn from_discriminant(discriminant: i64) -> Option<Self>;
+}
+
+/// Serialize an enum as its discriminant, for use with `#[serde(serialize_with)]`.
+pub fn serialize<T, S>(value: &T, serializer: &mut S) -> Result<(), S::Error>
+    where T: Discriminant, S: serde::Serializer {
+    serializer.serialize_i64(value.discriminant())
+}
+
+/// Deserialize an enum from its discriminant, for use with `#[serde(deserialize_with)]`.
+pub fn deserialize<T, D>(deserializer: &mut D) -> Result<T, D::Error>
+    where T: Discriminant, D: serde::Deserializer {
+    let discriminant: i64 = try!(serde::Deserialize::deserialize(deserializer));
+
+    T::from_discriminant(discriminant).ok_or_else(|| {
+        serde::de::Error::invalid_value(&format!("No variant with discriminant {}", discriminant))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use error::Reason;
+    use test_types::{Priority, Job};
+
+    use super::Discriminant;
+
+    impl Discriminant for Priority {
+        fn discriminant(&self) -> i64 {
+            *self as i64
+        }
+
+        fn from_discriminant(discriminant: i64) -> Option<Priority> {
+            match discriminant {
+                10 => Some(Priority::Low),
+                20 => Some(Priority::High),
+                -1 => Some(Priority::Never),
+                _ => None
+            }
+        }
+    }
+
+    #[test]
+    fn discriminant_test() {
+        // {"id": 1, "priority": 20}
+        let bytes = [0x82, 0xa2, 0x69, 0x64, 0x01,
+                     0xa8, 0x70, 0x72, 0x69, 0x6f, 0x72, 0x69, 0x74, 0x79, 0x14];
+        let value = Job { id: 1, priority: Priority::High };
+
+        assert_eq!(::to_bytes(&value).unwrap(), &bytes);
+        assert_eq!(::from_bytes::<Job>(&bytes).unwrap(), value);
+
+        let value = Job { id: 1, priority: Priority::Never };
+        let decoded: Job = ::from_bytes(::to_bytes(&value).unwrap().as_slice()).unwrap();
+        assert_eq!(decoded, value);
+    }
+
+    #[test]
+    fn index_test() {
+        // the variant index, as the derive would write it, is not a discriminant
+        let bytes = [0x82, 0xa2, 0x69, 0x64, 0x01,
+                     0xa8, 0x70, 0x72, 0x69, 0x6f, 0x72, 0x69, 0x74, 0x79, 0x01];
+        let result = ::from_bytes::<Job>(&bytes);
+        assert_eq!(result.unwrap_err().reason(), Reason::BadValue);
+    }
+}
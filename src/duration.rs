@@ -0,0 +1,95 @@
+//! Durations as a plain count of seconds or milliseconds.
+//!
+//! serde writes a `Duration` as a struct of seconds and nanoseconds, but many
+//! protocols carry a timeout or interval as a single integer. Pointing a
+//! field's `serialize_with` and `deserialize_with` at the functions in
+//! `as_secs` or `as_millis` writes it as a uint of that unit instead. Any
+//! finer part of the duration is dropped when it is written.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+/// A `Duration` as a whole number of seconds.
+pub mod as_secs {
+    use std::time::Duration;
+
+    use serde;
+
+    /// Serialize a Duration as seconds, for use with `#[serde(serialize_with)]`.
+    pub fn serialize<S>(duration: &Duration, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer {
+        serializer.serialize_u64(duration.as_secs())
+    }
+
+    /// Deserialize a Duration from seconds, for use with `#[serde(deserialize_with)]`.
+    pub fn deserialize<D>(deserializer: &mut D) -> Result<Duration, D::Error>
+        where D: serde::Deserializer {
+        let secs: u64 = try!(serde::Deserialize::deserialize(deserializer));
+        Ok(Duration::from_secs(secs))
+    }
+}
+
+/// A `Duration` as a whole number of milliseconds.
+pub mod as_millis {
+    use std::time::Duration;
+
+    use serde;
+
+    /// Serialize a Duration as milliseconds, for use with
+    /// `#[serde(serialize_with)]`. Fails if that many milliseconds don't fit
+    /// in a u64.
+    pub fn serialize<S>(duration: &Duration, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer {
+        let millis = duration.as_millis();
+        if millis > u64::max_value() as u128 {
+            return Err(serde::ser::Error::invalid_value(&format!("Duration of {} ms does not fit in a u64", millis)));
+        }
+
+        serializer.serialize_u64(millis as u64)
+    }
+
+    /// Deserialize a Duration from milliseconds, for use with `#[serde(deserialize_with)]`.
+    pub fn deserialize<D>(deserializer: &mut D) -> Result<Duration, D::Error>
+        where D: serde::Deserializer {
+        let millis: u64 = try!(serde::Deserialize::deserialize(deserializer));
+        Ok(Duration::from_millis(millis))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use error::Reason;
+    use test_types::Timeout;
+
+    #[test]
+    fn round_trip_test() {
+        // {"after": 1500, "every": 30}
+        let bytes = [0x82, 0xa5, 0x61, 0x66, 0x74, 0x65, 0x72, 0xcd, 0x05, 0xdc,
+                     0xa5, 0x65, 0x76, 0x65, 0x72, 0x79, 0x1e];
+        let value = Timeout { after: Duration::from_millis(1500), every: Duration::from_secs(30) };
+
+        assert_eq!(::to_bytes(&value).unwrap(), &bytes);
+        assert_eq!(::from_bytes::<Timeout>(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn truncate_test() {
+        let value = Timeout { after: Duration::new(1, 2_999_999), every: Duration::new(30, 999_999_999) };
+        let decoded: Timeout = ::from_bytes(::to_bytes(&value).unwrap().as_slice()).unwrap();
+        assert_eq!(decoded, Timeout { after: Duration::from_millis(1002), every: Duration::from_secs(30) });
+    }
+
+    #[test]
+    fn overflow_test() {
+        let value = Timeout { after: Duration::from_secs(u64::max_value()), every: Duration::from_secs(1) };
+        assert_eq!(::to_bytes(&value).unwrap_err().reason(), Reason::BadValue);
+
+        // a negative count is no duration at all
+        let bytes = [0x82, 0xa5, 0x61, 0x66, 0x74, 0x65, 0x72, 0xff,
+                     0xa5, 0x65, 0x76, 0x65, 0x72, 0x79, 0x1e];
+        assert_eq!(::from_bytes::<Timeout>(&bytes).unwrap_err().reason(), Reason::OutOfRange);
+    }
+}
@@ -0,0 +1,117 @@
+//! Serializing values behind trait objects.
+//!
+//! serde's `Serialize` has a generic method, so there is no
+//! `Box<Serialize>`, and a plugin system can't keep a list of values of
+//! different types to encode together. Any `Serialize` type is also an
+//! `ErasedSerialize`, which is object safe. A `Box<dyn ErasedSerialize>`, or a
+//! `Vec` of them, can then be serialized like any other value:
+//!
+//! ```ignore
+//! let values: Vec<Box<dyn ErasedSerialize>> = vec![Box::new(1), Box::new("two")];
+//! let bytes = corepack::to_bytes(&values)?;
+//! ```
+//!
+//! Serialized that way, each value is encoded on its own with the default
+//! `SerializerConfig`, and its bytes copied into the output, as `Serialize`
+//! gives it no way to reach the config of the outer Serializer. Outside of
+//! corepack an erased value serializes as a newtype around its encoded bytes,
+//! like a `RawMessage`.
+//!
+//! `Serializer::serialize_erased` writes an erased value with the Serializer's
+//! own config instead, straight into its output:
+//!
+//! ```ignore
+//! ser.write_array_len(values.len())?;
+//! for value in values.iter() {
+//!     ser.serialize_erased(&**value)?;
+//! }
+//! ```
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::vec::Vec;
+
+use serde;
+
+use bytes::Bytes;
+use error::Error;
+use ser::Serializer;
+
+/// An object safe stand-in for `Serialize`, implemented for every type that
+/// implements it.
+pub trait ErasedSerialize {
+    /// Serialize this value with a corepack Serializer.
+    fn erased_serialize(&self, serializer: &mut Serializer<&mut dyn FnMut(&[u8]) -> Result<(), Error>>) -> Result<(), Error>;
+}
+
+impl<T: serde::Serialize> ErasedSerialize for T {
+    fn erased_serialize(&self, serializer: &mut Serializer<&mut dyn FnMut(&[u8]) -> Result<(), Error>>) -> Result<(), Error> {
+        serde::Serialize::serialize(self, serializer)
+    }
+}
+
+impl<'a> serde::Serialize for dyn ErasedSerialize + 'a {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer {
+        let mut bytes = Vec::new();
+        let result = {
+            let mut output = |buf: &[u8]| {
+                bytes.extend_from_slice(buf);
+                Ok(())
+            };
+            let mut ser = Serializer::new(&mut output as &mut dyn FnMut(&[u8]) -> Result<(), Error>);
+            self.erased_serialize(&mut ser)
+        };
+        try!(result.map_err(|e| serde::ser::Error::custom(format!("{}", e))));
+
+        serializer.serialize_newtype_struct(::raw::TOKEN, Bytes(bytes.as_slice()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::boxed::Box;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use ser::{Serializer, SerializerConfig, UnitEncoding};
+    use test_types::Unit;
+
+    use super::ErasedSerialize;
+
+    #[test]
+    fn vec_test() {
+        let values: Vec<Box<dyn ErasedSerialize>> = vec![Box::new(1u8), Box::new("two"), Box::new(vec![3u16, 4]), Box::new(Unit)];
+        let bytes = ::to_bytes(&values).unwrap();
+        assert_eq!(bytes, &[0x94, 0x01, 0xa3, 0x74, 0x77, 0x6f, 0x92, 0x03, 0x04, 0xc0]);
+
+        let value: (u8, String, Vec<u16>, Unit) = ::from_bytes(&bytes).unwrap();
+        assert_eq!(value, (1, "two".into(), vec![3, 4], Unit));
+    }
+
+    #[test]
+    fn reference_test() {
+        let value = vec![5u32, 6];
+        let erased: &dyn ErasedSerialize = &value;
+        assert_eq!(::to_bytes(erased).unwrap(), ::to_bytes(&value).unwrap());
+    }
+
+    #[test]
+    fn config_test() {
+        let values: Vec<Box<dyn ErasedSerialize>> = vec![Box::new(Unit), Box::new(vec![Unit])];
+        let config = SerializerConfig { unit_encoding: UnitEncoding::EmptyArray, ..SerializerConfig::new() };
+
+        // through Serialize, the default config
+        assert_eq!(::to_bytes_with_config(&values, config).unwrap(), &[0x92, 0xc0, 0x91, 0xc0]);
+
+        // through serialize_erased, the Serializer's own
+        let mut ser = Serializer::to_vec(config);
+        ser.write_array_len(values.len()).unwrap();
+        for value in values.iter() {
+            ser.serialize_erased(&**value).unwrap();
+        }
+        assert_eq!(ser.into_vec(), &[0x92, 0x90, 0x91, 0x90]);
+    }
+}
@@ -6,14 +6,14 @@
 
 use std::fmt::Display;
 
-use collections::String;
+use alloc::string::String;
 
 use alloc::boxed::Box;
 
 use std::fmt;
 
 /// Reasons that parsing or encoding might fail in corepack.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Reason {
     /// Container or sequence was too big to serialize.
     TooBig,
@@ -48,6 +48,33 @@ pub enum Reason {
     /// Error decoding UTF8 string.
     UTF8Error,
 
+    /// Integer did not fit in the type it was decoded into.
+    OutOfRange,
+
+    /// A frame's checksum did not match its payload.
+    ChecksumMismatch,
+
+    /// Input went over a limit set in the configuration.
+    LimitExceeded,
+
+    /// A frame held fewer bytes than its header said it would.
+    FrameTooShort,
+
+    /// A map held the same key more than once.
+    DuplicateKey,
+
+    /// Input ended partway through a value that more input would complete.
+    NeedMore,
+
+    /// Output did not decode again when checked.
+    SelfCheckFailed,
+
+    /// Output did not fit in a fixed-capacity buffer.
+    BufferOverflow,
+
+    /// A float did not fit exactly in the type it was decoded into.
+    PrecisionLoss,
+
     /// Some other error that does not fit into the above.
     Other,
 }
@@ -57,15 +84,18 @@ pub enum Reason {
 pub struct Error {
     reason: Reason,
     detail: String,
+    // where in the value the error happened, built up as it is returned
+    path: String,
     #[cfg(not(feature = "std"))]
     cause: Option<Box<::serde::error::Error>>,
     #[cfg(feature = "std")]
     cause: Option<Box<::std::error::Error>>
 }
 
-impl Display for Error {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        let name = match self.reason {
+impl Reason {
+    /// A short description of the reason, the same as its Display form.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
             Reason::TooBig => "Overflowing value",
             Reason::ExtraItems => "More items that expected",
             Reason::BadValue => "Invalid value",
@@ -77,13 +107,41 @@ impl Display for Error {
             Reason::NoField => "Missing field",
             Reason::DupField => "Duplicate field",
             Reason::UTF8Error => "UTF-8 encoding error",
+            Reason::OutOfRange => "Integer out of range",
+            Reason::ChecksumMismatch => "Checksum mismatch",
+            Reason::LimitExceeded => "Limit exceeded",
+            Reason::FrameTooShort => "Frame too short",
+            Reason::DuplicateKey => "Duplicate key",
+            Reason::NeedMore => "More input needed",
+            Reason::SelfCheckFailed => "Self-check failed",
+            Reason::BufferOverflow => "Buffer overflow",
+            Reason::PrecisionLoss => "Float precision lost",
             Reason::Other => "Other error"
-        };
+        }
+    }
+}
+
+impl Display for Reason {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+// formatting only writes into the formatter, so it works without an allocator
+impl Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.reason.as_str();
 
         if !self.detail.is_empty() {
-            write!(fmt, "{}: {}", name, self.detail)
+            try!(write!(fmt, "{}: {}", name, self.detail));
         } else {
-            write!(fmt, "{}", name)
+            try!(write!(fmt, "{}", name));
+        }
+
+        if !self.path.is_empty() {
+            write!(fmt, " at {}", self.path())
+        } else {
+            Ok(())
         }
     }
 }
@@ -95,6 +153,7 @@ impl Error {
         Error {
             reason: reason,
             detail: detail,
+            path: String::new(),
             cause: cause
         }
     }
@@ -105,6 +164,7 @@ impl Error {
         Error {
             reason: reason,
             detail: detail,
+            path: String::new(),
             cause: cause
         }
     }
@@ -119,6 +179,44 @@ impl Error {
     pub fn simple(reason: Reason) -> Error {
         Error::new(reason, String::new())
     }
+
+    /// Get the reason for this error.
+    pub fn reason(&self) -> Reason {
+        self.reason
+    }
+
+    /// Get the path to the value that failed to deserialize, made of struct
+    /// field names and sequence indexes, like `config.servers[2].port`. Enum
+    /// variants show their index and name, like `<variant 2 C>`. Empty if the
+    /// error was not inside a struct, sequence or enum.
+    pub fn path(&self) -> &str {
+        if self.path.starts_with('.') {
+            &self.path[1..]
+        } else {
+            self.path.as_str()
+        }
+    }
+
+    pub(crate) fn at_index(mut self, index: usize) -> Error {
+        self.path.insert_str(0, &format!("[{}]", index));
+        self
+    }
+
+    pub(crate) fn at_variant(mut self, index: usize, name: Option<&str>) -> Error {
+        match name {
+            Some(name) => self.path.insert_str(0, &format!("<variant {} {}>", index, name)),
+            None => self.path.insert_str(0, &format!("<variant {}>", index))
+        }
+        self
+    }
+
+    pub(crate) fn at_field(mut self, field: &str) -> Error {
+        if !field.is_empty() {
+            self.path.insert_str(0, field);
+            self.path.insert(0, '.');
+        }
+        self
+    }
 }
 
 #[cfg(not(feature = "std"))]
@@ -198,3 +296,50 @@ impl ::serde::de::Error for Error {
         Error::new(Reason::DupField, field.into())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fmt::{self, Write};
+
+    use super::{Error, Reason};
+
+    // a fixed buffer, like an embedded logger might write into
+    struct FixedBuf {
+        buf: [u8; 64],
+        len: usize
+    }
+
+    impl Write for FixedBuf {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            if end > self.buf.len() {
+                return Err(fmt::Error);
+            }
+
+            self.buf[self.len..end].clone_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    impl FixedBuf {
+        fn as_str(&self) -> &str {
+            ::std::str::from_utf8(&self.buf[..self.len]).unwrap()
+        }
+    }
+
+    #[test]
+    fn display_no_alloc_test() {
+        let error = Error::new(Reason::BadLength, "3".into()).at_index(2).at_field("items");
+        let mut out = FixedBuf { buf: [0; 64], len: 0 };
+
+        let (result, allocations) = ::test_alloc::count(|| write!(out, "{}", error));
+        result.unwrap();
+        assert_eq!(allocations, 0);
+        assert_eq!(out.as_str(), "Invalid length: 3 at items[2]");
+
+        let mut out = FixedBuf { buf: [0; 64], len: 0 };
+        write!(out, "{}", Reason::EndOfStream).unwrap();
+        assert_eq!(out.as_str(), "End of stream");
+    }
+}
@@ -0,0 +1,96 @@
+//! Integers written at their full width.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::ops::Deref;
+
+use serde;
+
+/// Newtype struct name that the Serializer treats as a request to write the
+/// integer inside at its full width.
+pub const TOKEN: &'static str = "__corepack_Fixed";
+
+/// An integer that always serializes in the format matching its type, however
+/// small its value: a `Fixed<u32>` is always a uint32, and a `Fixed<i16>` always
+/// an int16. This suits fixed-layout protocols where a field must always take
+/// the same number of bytes. `usize` and `isize` are written as 64 bits.
+/// Any other value inside a Fixed is written as usual, and so is everything
+/// in it.
+///
+/// Any integer format is accepted when deserializing. Outside of corepack a
+/// Fixed serializes just like the integer inside it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Fixed<T>(pub T);
+
+impl<T> Deref for Fixed<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> From<T> for Fixed<T> {
+    fn from(value: T) -> Fixed<T> {
+        Fixed(value)
+    }
+}
+
+impl<T: serde::Serialize> serde::Serialize for Fixed<T> {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer {
+        serializer.serialize_newtype_struct(TOKEN, &self.0)
+    }
+}
+
+impl<T: serde::Deserialize> serde::Deserialize for Fixed<T> {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Fixed<T>, D::Error>
+        where D: serde::Deserializer {
+        T::deserialize(deserializer).map(Fixed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+
+    use super::Fixed;
+
+    #[test]
+    fn fixed_u32_test() {
+        assert_eq!(::to_bytes(Fixed(5u32)).unwrap(), &[0xce, 0x00, 0x00, 0x00, 0x05]);
+        assert_eq!(::to_bytes(5u32).unwrap(), &[0x05]);
+
+        let value: Fixed<u32> = ::from_bytes(&[0xce, 0x00, 0x00, 0x00, 0x05]).unwrap();
+        assert_eq!(value, Fixed(5));
+        let value: Fixed<u32> = ::from_bytes(&[0x05]).unwrap();
+        assert_eq!(value, Fixed(5));
+    }
+
+    #[test]
+    fn fixed_widths_test() {
+        assert_eq!(::to_bytes(Fixed(5u8)).unwrap(), &[0xcc, 0x05]);
+        assert_eq!(::to_bytes(Fixed(5u16)).unwrap(), &[0xcd, 0x00, 0x05]);
+        assert_eq!(::to_bytes(Fixed(5u64)).unwrap(), &[0xcf, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05]);
+        assert_eq!(::to_bytes(Fixed(-1i8)).unwrap(), &[0xd0, 0xff]);
+        assert_eq!(::to_bytes(Fixed(-1i16)).unwrap(), &[0xd1, 0xff, 0xff]);
+        assert_eq!(::to_bytes(Fixed(5i32)).unwrap(), &[0xd2, 0x00, 0x00, 0x00, 0x05]);
+        assert_eq!(::to_bytes(Fixed(5i64)).unwrap(), &[0xd3, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05]);
+    }
+
+    #[test]
+    fn fixed_only_applies_once_test() {
+        // only the Fixed value is widened, not anything serialized after it
+        let value: Vec<Fixed<u16>> = vec![Fixed(1), Fixed(2)];
+        assert_eq!(::to_bytes(&value).unwrap(), &[0x92, 0xcd, 0x00, 0x01, 0xcd, 0x00, 0x02]);
+
+        assert_eq!(::to_bytes((Fixed(1u16), 2u16)).unwrap(), &[0x92, 0xcd, 0x00, 0x01, 0x02]);
+
+        // nor past a value that isn't an integer
+        assert_eq!(::to_bytes((Fixed(true), 2u16)).unwrap(), &[0x92, 0xc3, 0x02]);
+        assert_eq!(::to_bytes(Fixed(vec![1u16, 2])).unwrap(), &[0x92, 0x01, 0x02]);
+        assert_eq!(::to_bytes(Fixed(Some(1u16))).unwrap(), &[0xcd, 0x00, 0x01]);
+    }
+}
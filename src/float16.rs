@@ -0,0 +1,88 @@
+//! Half-precision floats, behind the `half` feature.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::ops::Deref;
+
+use half::f16;
+
+use serde;
+
+/// An `f16` that serializes as its 16 raw bits.
+///
+/// MessagePack has no half-precision float, so the bits are written as a uint16,
+/// always at full width. Every bit pattern comes back exactly as it went in,
+/// including infinities and the payloads of NaNs.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub struct Half(pub f16);
+
+impl Deref for Half {
+    type Target = f16;
+
+    fn deref(&self) -> &f16 {
+        &self.0
+    }
+}
+
+impl From<f16> for Half {
+    fn from(value: f16) -> Half {
+        Half(value)
+    }
+}
+
+impl serde::Serialize for Half {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer {
+        serializer.serialize_newtype_struct(::fixed::TOKEN, self.0.to_bits())
+    }
+}
+
+impl serde::Deserialize for Half {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Half, D::Error>
+        where D: serde::Deserializer {
+        let bits: u16 = try!(serde::Deserialize::deserialize(deserializer));
+        Ok(Half(f16::from_bits(bits)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use half::f16;
+
+    use super::Half;
+
+    fn round_trip(bits: u16) {
+        let bytes = ::to_bytes(Half(f16::from_bits(bits))).unwrap();
+        assert_eq!(bytes, &[0xcd, (bits >> 8) as u8, bits as u8]);
+
+        let value: Half = ::from_bytes(bytes.as_slice()).unwrap();
+        assert_eq!(value.0.to_bits(), bits);
+    }
+
+    #[test]
+    fn half_test() {
+        round_trip(0x0000); // 0.0
+        round_trip(0x8000); // -0.0
+        round_trip(0x3c00); // 1.0
+        round_trip(0xc000); // -2.0
+        round_trip(0x0001); // smallest subnormal
+        round_trip(0x7bff); // 65504, the largest finite value
+    }
+
+    #[test]
+    fn half_special_test() {
+        round_trip(0x7c00); // inf
+        round_trip(0xfc00); // -inf
+        round_trip(0x7e00); // NaN
+        round_trip(0x7e01); // NaN with a payload
+        round_trip(0xfd55); // signalling NaN
+    }
+
+    #[test]
+    fn half_value_test() {
+        let value: Half = ::from_bytes(&[0xcd, 0x3e, 0x00]).unwrap();
+        assert_eq!(f32::from(value.0), 1.5);
+    }
+}
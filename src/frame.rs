@@ -0,0 +1,206 @@
+//! Length and checksum framing for messages sent over unreliable links, and
+//! plain length prefixes for message-oriented transports.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::vec::Vec;
+
+use serde;
+
+use defs::*;
+use error::{Error, Reason};
+
+// bytes of framing around the payload: the length before it, the CRC after
+const FRAME_BYTES: usize = U32_BYTES * 2;
+
+/// The CRC-32 (IEEE) of some bytes, as used by zlib and Ethernet.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            // reflected polynomial
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+// the big-endian u32 at the front of bytes, which must have room for it
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0; U32_BYTES];
+    buf.copy_from_slice(&bytes[..U32_BYTES]);
+    u32::from_be_bytes(buf)
+}
+
+/// Serialize V into a frame: the payload's length as a big-endian u32, the
+/// payload, then the payload's CRC-32 as a big-endian u32.
+pub fn to_framed_bytes<V>(value: V) -> Result<Vec<u8>, Error>
+    where V: serde::Serialize {
+    let payload = try!(::to_bytes(value));
+    if payload.len() > u32::max_value() as usize {
+        return Err(Error::simple(Reason::TooBig));
+    }
+
+    let mut frame = vec![0; payload.len() + FRAME_BYTES];
+    frame[..U32_BYTES].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame[U32_BYTES..U32_BYTES + payload.len()].clone_from_slice(&payload);
+    frame[U32_BYTES + payload.len()..].copy_from_slice(&crc32(&payload).to_be_bytes());
+
+    Ok(frame)
+}
+
+/// Parse V out of a frame written by `to_framed_bytes`, checking its length
+/// and checksum first.
+///
+/// Fails with `Reason::FrameTooShort` if the frame has been cut off, and with
+/// `Reason::BadLength` if there is more to it than its length says.
+pub fn from_framed_bytes<V>(bytes: &[u8]) -> Result<V, Error>
+    where V: serde::Deserialize {
+    if bytes.len() < FRAME_BYTES {
+        return Err(Error::new(Reason::FrameTooShort, format!("No room for a length and CRC in {} bytes", bytes.len())));
+    }
+
+    let len = read_u32(bytes) as u64;
+    let held = (bytes.len() - FRAME_BYTES) as u64;
+    if len != held {
+        let reason = if len > held { Reason::FrameTooShort } else { Reason::BadLength };
+        return Err(Error::new(reason, format!("Frame holds {} bytes, but says {}", held, len)));
+    }
+
+    let payload = &bytes[U32_BYTES..bytes.len() - U32_BYTES];
+    let expected = read_u32(&bytes[bytes.len() - U32_BYTES..]);
+    if crc32(payload) != expected {
+        return Err(Error::simple(Reason::ChecksumMismatch));
+    }
+
+    ::from_bytes(payload)
+}
+
+/// Serialize V with its length in front, as a big-endian u32.
+pub fn to_length_prefixed_bytes<V>(value: V) -> Result<Vec<u8>, Error>
+    where V: serde::Serialize {
+    let payload = try!(::to_bytes(value));
+    if payload.len() > u32::max_value() as usize {
+        return Err(Error::simple(Reason::TooBig));
+    }
+
+    let mut frame = vec![0; U32_BYTES];
+    frame.copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+
+    Ok(frame)
+}
+
+/// Parse V out of the front of bytes written by `to_length_prefixed_bytes`,
+/// and return it along with the size of the whole frame.
+///
+/// Anything after the frame is left alone, so a buffer of several frames can be
+/// read by advancing past each one in turn. The value must take up the whole
+/// payload.
+pub fn from_length_prefixed_bytes<V>(bytes: &[u8]) -> Result<(V, usize), Error>
+    where V: serde::Deserialize {
+    if bytes.len() < U32_BYTES {
+        return Err(Error::new(Reason::FrameTooShort, format!("No room for a length in {} bytes", bytes.len())));
+    }
+
+    let len = read_u32(bytes) as u64;
+    if len > (bytes.len() - U32_BYTES) as u64 {
+        return Err(Error::new(Reason::FrameTooShort,
+                              format!("Frame holds {} bytes, but says {}", bytes.len() - U32_BYTES, len)));
+    }
+
+    let end = U32_BYTES + len as usize;
+    let (value, used) = try!(::from_slice(&bytes[U32_BYTES..end]));
+    if used as u64 != len {
+        return Err(Error::new(Reason::BadLength, format!("Value took {} of {} bytes", used, len)));
+    }
+
+    Ok((value, end))
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::String;
+
+    use error::Reason;
+    use super::{crc32, to_framed_bytes, from_framed_bytes};
+    use super::{to_length_prefixed_bytes, from_length_prefixed_bytes};
+
+    #[test]
+    fn crc32_test() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let value = String::from("hello");
+        let frame = to_framed_bytes(&value).unwrap();
+
+        assert_eq!(&frame[..4], &[0x00, 0x00, 0x00, 0x06]);
+        assert_eq!(&frame[4..10], &[0xa5, 0x68, 0x65, 0x6c, 0x6c, 0x6f]);
+        assert_eq!(frame.len(), 14);
+
+        let actual: String = from_framed_bytes(&frame).unwrap();
+        assert_eq!(actual, value);
+    }
+
+    #[test]
+    fn corrupted_test() {
+        let mut frame = to_framed_bytes("hello").unwrap();
+        frame[5] ^= 0x01;
+
+        let result: Result<String, _> = from_framed_bytes(&frame);
+        assert_eq!(result.unwrap_err().reason(), Reason::ChecksumMismatch);
+    }
+
+    #[test]
+    fn bad_length_test() {
+        let mut frame = to_framed_bytes("hello").unwrap();
+
+        let result: Result<String, _> = from_framed_bytes(&frame[..frame.len() - 1]);
+        assert_eq!(result.unwrap_err().reason(), Reason::FrameTooShort);
+
+        let result: Result<String, _> = from_framed_bytes(&frame[..3]);
+        assert_eq!(result.unwrap_err().reason(), Reason::FrameTooShort);
+
+        // a stray byte after the frame
+        frame.push(0x00);
+        let result: Result<String, _> = from_framed_bytes(&frame);
+        assert_eq!(result.unwrap_err().reason(), Reason::BadLength);
+    }
+
+    #[test]
+    fn length_prefixed_test() {
+        let mut bytes = to_length_prefixed_bytes("hello").unwrap();
+        assert_eq!(bytes, &[0x00, 0x00, 0x00, 0x06, 0xa5, 0x68, 0x65, 0x6c, 0x6c, 0x6f]);
+
+        bytes.extend(to_length_prefixed_bytes(7u8).unwrap());
+
+        let (first, used): (String, usize) = from_length_prefixed_bytes(&bytes).unwrap();
+        assert_eq!((first.as_str(), used), ("hello", 10));
+
+        let (second, used): (u8, usize) = from_length_prefixed_bytes(&bytes[10..]).unwrap();
+        assert_eq!((second, used), (7, 5));
+    }
+
+    #[test]
+    fn length_prefixed_truncated_test() {
+        let bytes = to_length_prefixed_bytes("hello").unwrap();
+
+        let result = from_length_prefixed_bytes::<String>(&bytes[..bytes.len() - 1]);
+        assert_eq!(result.unwrap_err().reason(), Reason::FrameTooShort);
+
+        let result = from_length_prefixed_bytes::<String>(&bytes[..2]);
+        assert_eq!(result.unwrap_err().reason(), Reason::FrameTooShort);
+
+        // a length that doesn't match the value inside
+        let result = from_length_prefixed_bytes::<u8>(&[0x00, 0x00, 0x00, 0x02, 0x07, 0x07]);
+        assert_eq!(result.unwrap_err().reason(), Reason::BadLength);
+    }
+}
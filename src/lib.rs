@@ -1,89 +1,278 @@
 //! corepack is a no_std support for messagepack in serde.
+//!
+//! # Self-describing targets
+//!
+//! `Deserializer::deserialize` dispatches on the marker byte of the next value,
+//! so self-describing types such as `serde_json::Value` can be decoded straight
+//! from MessagePack. nil becomes `Null`, bool becomes `Bool`, integers and
+//! floats become `Number`, str becomes `String`, arrays become `Array` and maps
+//! become `Object`.
+//!
+//! bin and ext have no JSON equivalent. corepack does not guess an encoding for
+//! them: bin is handed to the visitor as a byte buffer, and ext as a map of
+//! `{"type": i8, "data": bin}`. Visitors without byte support, like the one
+//! behind `serde_json::Value`, reject such documents with a `BadType` error, so
+//! convert bin payloads to arrays or base64 strings before bridging to JSON.
+//!
+//! corepack's own `Value` keeps ext values whole as `Value::Ext`. Handlers
+//! registered with `DeserializerConfig::register_ext` turn an application's
+//! ext types into ordinary values instead, for any target type.
+//!
+//! # Internally tagged enums
+//!
+//! Enums discriminated by a field inside the payload, like serde's
+//! `#[serde(tag = "type")]`, are not supported. The serde derive this crate
+//! builds against has no `tag` attribute, and no content-buffering
+//! `Deserialize` impls for it to lean on. The deserializer is not what is
+//! missing: every marker is already reachable through `deserialize`, so a map
+//! such as `{"type": "Ping", "seq": 1}` can be decoded into a struct holding the
+//! tag and dispatched on by hand.
+//!
+//! # Memory-mapped input
+//!
+//! A memory map such as `memmap2::Mmap` derefs to `&[u8]`, so `from_bytes` and
+//! `from_slice` read from it directly. With the `mmap` feature, `from_mmap`
+//! does the same for a `memmap2::Mmap`. Nothing is read ahead of the value
+//! being decoded, so only the pages it covers are touched.
+//!
+//! Decoding is not zero-copy, though: the serde this crate builds against has
+//! no lifetime on `Deserialize` for a `&str` field to borrow from the input
+//! through, so every str and bin is copied out into an owned `String` or
+//! `Vec<u8>`.
+//!
+//! # Human-readable representations
+//!
+//! The serde this crate builds against has no `is_human_readable` flag, so
+//! there is nothing for corepack to report or for types to branch on. Types
+//! like `std::net::Ipv4Addr` always serialize through their string form, as
+//! they would to JSON. Where the compact form matters, serialize the raw parts
+//! instead, such as `Bytes(&addr.octets())` for an address. The compact form
+//! newer serde versions write for these types, such as an array of four octets
+//! for an `Ipv4Addr`, doesn't decode as them either.
+//!
+//! The rest of `std::net` is the same: `IpAddr`, `Ipv6Addr` and the socket
+//! addresses are strs, with the port written into the str rather than as an
+//! integer. A `SocketAddrV6` keeps what its string form shows, so its scope
+//! survives but its flowinfo comes back as zero.
+//!
+//! The same goes for crates like uuid and chrono. Their impls for this serde
+//! write the `Display` form with `serialize_str`, as there is no `collect_str`
+//! yet, so a `Uuid` is encoded as a 36 byte str rather than 16 bytes of bin, and
+//! decodes from that str again. There is no switch that would turn on a binary
+//! form for them; wrap the value and serialize `Bytes(uuid.as_bytes())` where
+//! the 16 byte form is wanted.
 //
 // This Source Code Form is subject to the terms of the Mozilla Public License,
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-#![feature(inclusive_range)]
-#![feature(inclusive_range_syntax)]
-#![feature(fn_traits)]
-#![feature(unboxed_closures)]
-#![feature(collections)]
-#![feature(alloc)]
-#![feature(range_contains)]
-#![feature(const_fn)]
-#![feature(box_syntax)]
 #![allow(overflowing_literals)]
 // always test with libstd turned on
 #![cfg_attr(all(not(feature = "std"), not(test)), no_std)]
 #[cfg(all(not(feature = "std"), not(test)))]
 extern crate core as std;
+#[macro_use]
 extern crate serde;
-extern crate byteorder;
+#[cfg(feature = "half")]
+extern crate half;
+#[cfg(feature = "arrayvec")]
+extern crate arrayvec;
+#[cfg(feature = "heapless")]
+extern crate heapless;
+#[cfg(feature = "mmap")]
+extern crate memmap2;
 #[macro_use]
-extern crate collections;
 extern crate alloc;
+#[cfg(test)]
+extern crate serde_json;
+#[cfg(test)]
+extern crate rmp_serde;
+#[cfg(test)]
+extern crate uuid;
 
-use collections::Vec;
+use alloc::vec::Vec;
 
-pub use ser::Serializer;
-pub use de::Deserializer;
+pub use ser::{Serializer, SerializerConfig, UnitEncoding, EnumEncoding, ArrayBuilder, MapBuilder, serialize_seq_from_iter, serialize_map_from_iter};
+pub use de::{Deserializer, DeserializerConfig, ExtRegistry, ExtHandler, MsgpackType, LegacyRaw, DuplicateKeys};
+pub use bytes::{Bytes, ByteBuf};
+pub use raw::RawMessage;
+pub use fixed::Fixed;
+pub use value::Value;
+#[cfg(feature = "half")]
+pub use float16::Half;
+pub use ring::RingDeserializer;
+pub use frame::{to_framed_bytes, from_framed_bytes, to_length_prefixed_bytes, from_length_prefixed_bytes};
 
 pub mod error;
+pub mod bytes;
+pub mod option;
+pub mod array;
+pub mod discriminant;
+pub mod markers;
+pub mod erased;
+pub mod decimal;
+pub mod duration;
+#[cfg(feature = "arrayvec")]
+pub mod array_vec;
+
+mod raw;
+mod fixed;
+mod value;
+#[cfg(feature = "half")]
+mod float16;
+mod frame;
+mod ring;
 
 mod defs;
 mod ser;
 mod de;
 
+#[cfg(test)]
+mod test_alloc;
+#[cfg(test)]
+mod spec_vectors;
+
 // include serde generated code
 include!(concat!(env!("OUT_DIR"), "/serde_types.rs"));
 
 /// Parse V out of a byte stream.
 pub fn from_iter<I, V>(mut iter: I) -> Result<V, error::Error>
     where I: Iterator<Item=u8>, V: serde::Deserialize {
-    let mut de = Deserializer::new(|buf: &mut [u8]| {
-        for i in 0..buf.len() {
-            if let Some(byte) = iter.next() {
-                buf[i] = byte;
-            } else {
-                return Err(error::Error::simple(error::Reason::EndOfStream));
-            }
-        }
-
-        Ok(())
-    });
+    let mut de = Deserializer::new(|buf: &mut [u8]| read_iter(&mut iter, buf));
 
     V::deserialize(&mut de)
 }
 
 /// Parse V out of a slice of bytes.
 pub fn from_bytes<V>(bytes: &[u8]) -> Result<V, error::Error>
+    where V: serde::Deserialize {
+    from_bytes_with_config(bytes, DeserializerConfig::new())
+}
+
+/// Parse V out of a slice of bytes, with the given Deserializer configuration.
+pub fn from_bytes_with_config<V>(bytes: &[u8], config: DeserializerConfig) -> Result<V, error::Error>
+    where V: serde::Deserialize {
+    let mut position: usize = 0;
+
+    let mut de = Deserializer::with_config(|buf: &mut [u8]| {
+        read_slice(bytes, &mut position, buf)
+    }, config);
+
+    V::deserialize(&mut de)
+}
+
+/// Parse V out of a memory-mapped file, reading it in place. Strs and bins are
+/// still copied out, as with `from_bytes`.
+#[cfg(feature = "mmap")]
+pub fn from_mmap<V>(map: &memmap2::Mmap) -> Result<V, error::Error>
+    where V: serde::Deserialize {
+    from_bytes(&map[..])
+}
+
+/// Parse V out of a slice of bytes, or return `V::default()` if there are no
+/// bytes at all.
+///
+/// Only empty input gets the default. Input that is there but doesn't decode
+/// still fails, so a truncated config isn't silently replaced.
+pub fn from_bytes_or_default<V>(bytes: &[u8]) -> Result<V, error::Error>
+    where V: serde::Deserialize + Default {
+    if bytes.is_empty() {
+        return Ok(V::default());
+    }
+
+    from_bytes(bytes)
+}
+
+/// Parse V out of the front of a slice of bytes, and return it along with the
+/// number of bytes it took up.
+///
+/// Anything after the value is left unread, so several values laid end to end
+/// can be parsed by advancing the slice by the returned count each time.
+pub fn from_slice<V>(bytes: &[u8]) -> Result<(V, usize), error::Error>
     where V: serde::Deserialize {
     let mut position: usize = 0;
 
     let mut de = Deserializer::new(|buf: &mut [u8]| {
-        if position + buf.len() > bytes.len() {
-            Err(error::Error::simple(error::Reason::EndOfStream))
-        } else {
-            let len = buf.len();
-            buf.clone_from_slice(&bytes[position..position + len]);
-            position += buf.len();
-            Ok(())
-        }
+        read_slice(bytes, &mut position, buf)
     });
 
-    V::deserialize(&mut de)
+    let value = try!(V::deserialize(&mut de));
+    Ok((value, de.position()))
+}
+
+fn read_slice(bytes: &[u8], position: &mut usize, buf: &mut [u8]) -> Result<(), error::Error> {
+    // checked, so a huge read can't wrap around and pass the bounds check
+    let end = match position.checked_add(buf.len()) {
+        Some(end) if end <= bytes.len() => end,
+        _ => return Err(error::Error::simple(error::Reason::EndOfStream))
+    };
+
+    buf.clone_from_slice(&bytes[*position..end]);
+    *position = end;
+    Ok(())
+}
+
+fn read_iter<I>(iter: &mut I, buf: &mut [u8]) -> Result<(), error::Error>
+    where I: Iterator<Item=u8> {
+    for byte in buf.iter_mut() {
+        match iter.next() {
+            Some(next) => *byte = next,
+            None => return Err(error::Error::simple(error::Reason::EndOfStream))
+        }
+    }
+
+    Ok(())
 }
 
 /// Serialize V into a byte buffer.
+///
+/// The Serializer writes straight into the returned buffer. The headers of
+/// unknown-length sequences and maps are kept aside until the outermost of them
+/// ends, then spliced in with one pass over the bytes after them, so nesting
+/// doesn't copy anything more than once.
 pub fn to_bytes<V>(value: V) -> Result<Vec<u8>, error::Error>
     where V: serde::Serialize {
-    let mut bytes = vec![];
+    to_bytes_with_config(value, SerializerConfig::new())
+}
+
+/// Serialize V into a byte buffer, with the given Serializer configuration.
+pub fn to_bytes_with_config<V>(value: V, config: SerializerConfig) -> Result<Vec<u8>, error::Error>
+    where V: serde::Serialize {
+    let mut ser = Serializer::to_vec(config);
+
+    try!(value.serialize(&mut ser));
+
+    Ok(ser.into_vec())
+}
+
+/// Serialize V into a byte buffer like `to_bytes_with_config`, then decode the
+/// output again, failing with `Reason::SelfCheckFailed` if it isn't exactly
+/// one valid value.
+///
+/// This is for catching encoder bugs in testing, and roughly doubles the cost
+/// of serializing.
+pub fn to_bytes_checked<V>(value: V, config: SerializerConfig) -> Result<Vec<u8>, error::Error>
+    where V: serde::Serialize {
+    let bytes = try!(to_bytes_with_config(value, config));
+    try!(self_check(bytes.as_slice()));
+
+    Ok(bytes)
+}
+
+/// Serialize V into a heapless Vec of at most N bytes, behind the `heapless`
+/// feature.
+///
+/// Nothing is allocated for the output, though sequences and maps of unknown
+/// length are still buffered until their length is known. Fails with
+/// `Reason::BufferOverflow` if the output is longer than N.
+#[cfg(feature = "heapless")]
+pub fn to_heapless<V, const N: usize>(value: V) -> Result<heapless::Vec<u8, N>, error::Error>
+    where V: serde::Serialize {
+    let mut bytes = heapless::Vec::new();
 
     {
-        let mut ser = Serializer::new(|buf| {
-            bytes.extend_from_slice(buf);
-            Ok(())
+        let mut ser = Serializer::new(|buf: &[u8]| {
+            bytes.extend_from_slice(buf).map_err(|_| error::Error::simple(error::Reason::BufferOverflow))
         });
 
         try!(value.serialize(&mut ser));
@@ -92,12 +281,33 @@ pub fn to_bytes<V>(value: V) -> Result<Vec<u8>, error::Error>
     Ok(bytes)
 }
 
+// make sure bytes hold exactly one value that decodes
+fn self_check(bytes: &[u8]) -> Result<(), error::Error> {
+    match from_slice::<Value>(bytes) {
+        Ok((_, used)) if used == bytes.len() => Ok(()),
+        Ok((_, used)) => Err(error::Error::new(error::Reason::SelfCheckFailed,
+                                               format!("{} bytes left over after the value", bytes.len() - used))),
+        Err(e) => Err(error::Error::new(error::Reason::SelfCheckFailed, format!("Output does not decode: {}", e)))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use serde::{Serialize, Deserialize};
+    use std::borrow::Cow;
+    use std::collections::HashSet;
     use std::fmt::Debug;
+    use std::marker::PhantomData;
+    use std::rc::Rc;
+    use std::sync::Arc;
 
-    use ::test_types::T;
+    use alloc::collections::BTreeSet;
+
+    use ::{Serializer, SerializerConfig, DeserializerConfig, UnitEncoding, EnumEncoding, Bytes, ByteBuf, RawMessage};
+
+    use ::error::{Error, Reason};
+
+    use ::test_types::{T, Outer, Inner, Unit, Sample, Settings, Envelope, TypedEnvelope, Event, OldEvent, Wide, Fields, Tagged, Renamed, Annotated};
     // #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
     // enum T {
     //     A(usize),
@@ -120,14 +330,84 @@ mod test {
         test_through(format!("Hello World!"))
     }
 
+    #[test]
+    fn test_tuple() {
+        test_through((1u8, "hi".to_string(), 2.5f64, true));
+
+        assert_eq!(::to_bytes((1u8, "hi", 2.5f64, true)).unwrap(),
+                   &[0x94, 0x01, 0xa2, 0x68, 0x69, 0xcb, 0x40, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xc3]);
+    }
+
+    #[test]
+    fn test_nested_tuple() {
+        test_through(((1u8, 2u8), 3u8));
+
+        assert_eq!(::to_bytes(((1u8, 2u8), 3u8)).unwrap(), &[0x92, 0x92, 0x01, 0x02, 0x03]);
+    }
+
+    #[test]
+    fn test_tuple_wrong_length() {
+        let value: Result<(u8, u8), _> = ::from_bytes(&[0x93, 0x01, 0x02, 0x03]);
+        assert_eq!(value.unwrap_err().reason(), Reason::ExtraItems);
+    }
+
     #[test]
     fn test_enum() {
         test_through(T::B)
     }
 
+    #[test]
+    fn test_enum_nested_struct_variant() {
+        test_through(Outer::Wrap { inner: Inner::Leaf { a: -1, b: "x".into() }, n: 2 });
+        test_through(Outer::Wrap { inner: Inner::Nested { t: T::D { a: 3, b: "y".into() } }, n: 4 });
+        test_through(Outer::Wrap { inner: Inner::Unit, n: 5 });
+        test_through(Outer::Pair(Inner::Leaf { a: 1, b: "a".into() }, Inner::Nested { t: T::B }));
+        test_through(vec![Outer::Empty, Outer::Wrap { inner: Inner::Unit, n: 6 }, Outer::Empty]);
+
+        assert_eq!(::to_bytes(Outer::Wrap { inner: Inner::Leaf { a: -1, b: "x".into() }, n: 2 }).unwrap(),
+                   &[0x92, 0x00, 0x82,
+                     0xa5, 0x69, 0x6e, 0x6e, 0x65, 0x72,
+                     0x92, 0x00, 0x82, 0xa1, 0x61, 0xff, 0xa1, 0x62, 0xa1, 0x78,
+                     0xa1, 0x6e, 0x02]);
+    }
+
+    #[test]
+    fn test_enum_nested_struct_variant_rmp() {
+        let value = Outer::Wrap { inner: Inner::Nested { t: T::D { a: 3, b: "y".into() } }, n: 4 };
+
+        let config = SerializerConfig { enum_encoding: EnumEncoding::Rmp, ..SerializerConfig::new() };
+        let bytes = ::to_bytes_with_config(&value, config).unwrap();
+        assert_eq!(bytes, &[0x92, 0x00, 0x92,
+                            0x92, 0x01, 0x91,
+                            0x92, 0x03, 0x92, 0x03, 0xa1, 0x79,
+                            0x04]);
+
+        let config = DeserializerConfig { enum_encoding: EnumEncoding::Rmp, ..DeserializerConfig::new() };
+        let actual: Outer = ::from_bytes_with_config(bytes.as_slice(), config).unwrap();
+        assert_eq!(actual, value);
+
+        assert_eq!(rmp_to_vec(&value), bytes);
+        assert_eq!(rmp_from_slice::<Outer>(&bytes), value);
+    }
+
+    #[test]
+    fn test_result() {
+        test_through(Ok::<u32, String>(5));
+        test_through(Err::<u32, String>("fail".into()));
+
+        assert_eq!(::to_bytes(Ok::<u32, String>(5)).unwrap(), &[0x92, 0x00, 0x05]);
+        assert_eq!(::to_bytes(Err::<u32, String>("fail".into())).unwrap(),
+                   &[0x92, 0x01, 0xa4, 0x66, 0x61, 0x69, 0x6c]);
+    }
+
     #[test]
     fn test_enum_newtype() {
-        test_through(T::A(42))
+        test_through(T::A(42));
+        test_through(Ok::<Vec<u8>, ()>(vec![1, 2]));
+
+        // [index, value], not [index, [value]]
+        assert_eq!(::to_bytes(T::A(42)).unwrap(), &[0x92, 0x00, 0x2a]);
+        assert_eq!(::to_bytes(Ok::<Vec<u8>, ()>(vec![1, 2])).unwrap(), &[0x92, 0x00, 0x92, 0x01, 0x02]);
     }
 
     #[test]
@@ -135,8 +415,727 @@ mod test {
         test_through(T::C(-3, 22))
     }
 
+    #[test]
+    fn test_enum_struct_arity() {
+        // always [index, map of fields]
+        assert_eq!(::to_bytes(Fields::Zero {}).unwrap(), &[0x92, 0x00, 0x80]);
+        test_through(Fields::Zero {});
+
+        assert_eq!(::to_bytes(Fields::One { a: 7 }).unwrap(), &[0x92, 0x01, 0x81, 0xa1, 0x61, 0x07]);
+        test_through(Fields::One { a: 7 });
+
+        let three = Fields::Three { a: 7, b: "x".into(), c: true };
+        assert_eq!(::to_bytes(&three).unwrap(), &[0x92, 0x02, 0x83,
+                                                  0xa1, 0x61, 0x07,
+                                                  0xa1, 0x62, 0xa1, 0x78,
+                                                  0xa1, 0x63, 0xc3]);
+        test_through(three);
+    }
+
+    #[test]
+    fn test_enum_wide_tuple() {
+        let eight = Wide::Eight(1, 2, 3, 4, 5, 6, 7, 8);
+        assert_eq!(::to_bytes(&eight).unwrap(), &[0x99, 0x00, 1, 2, 3, 4, 5, 6, 7, 8]);
+        test_through(eight);
+
+        // the index makes 15 elements, the most a fixarray holds
+        let fourteen = Wide::Fourteen(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14);
+        let bytes = ::to_bytes(&fourteen).unwrap();
+        assert_eq!(&bytes[..2], &[0x9f, 0x01]);
+        assert_eq!(bytes.len(), 1 + 15);
+        test_through(fourteen);
+
+        // and 16, which needs an array16
+        let fifteen = Wide::Fifteen(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+        let bytes = ::to_bytes(&fifteen).unwrap();
+        assert_eq!(&bytes[..4], &[0xdc, 0x00, 0x10, 0x02]);
+        assert_eq!(bytes.len(), 3 + 16);
+        test_through(fifteen);
+    }
+
     #[test]
     fn test_enum_struct() {
         test_through(T::D { a: 9001, b: "Hello world!".into() })
     }
+
+    fn rmp_to_vec<V: Serialize>(value: &V) -> Vec<u8> {
+        let mut bytes = vec![];
+        value.serialize(&mut ::rmp_serde::Serializer::new(&mut bytes)).expect("rmp-serde failed to serialize");
+        bytes
+    }
+
+    fn rmp_from_slice<V: Deserialize>(bytes: &[u8]) -> V {
+        V::deserialize(&mut ::rmp_serde::Deserializer::new(bytes)).expect("rmp-serde failed to deserialize")
+    }
+
+    // checked against rmp-serde in both directions, as well as the fixture
+    fn test_rmp_enum(value: T, expected: &[u8]) {
+        let config = SerializerConfig { enum_encoding: EnumEncoding::Rmp, ..SerializerConfig::new() };
+        let bytes = ::to_bytes_with_config(&value, config).unwrap();
+        assert_eq!(bytes, expected);
+        assert_eq!(rmp_from_slice::<T>(&bytes), value);
+
+        let config = DeserializerConfig { enum_encoding: EnumEncoding::Rmp, ..DeserializerConfig::new() };
+        let written = rmp_to_vec(&value);
+        assert_eq!(written, expected);
+        let actual: T = ::from_bytes_with_config(written.as_slice(), config).unwrap();
+        assert_eq!(actual, value);
+    }
+
+    #[test]
+    fn test_rmp_enum_unit() {
+        test_rmp_enum(T::B, &[0x92, 0x01, 0x90]);
+
+        // the index encoding differs
+        assert_eq!(::to_bytes(T::B).unwrap(), &[0x01]);
+    }
+
+    #[test]
+    fn test_rmp_enum_newtype() {
+        test_rmp_enum(T::A(42), &[0x92, 0x00, 0x91, 0x2a]);
+        assert_eq!(::to_bytes(T::A(42)).unwrap(), &[0x92, 0x00, 0x2a]);
+    }
+
+    #[test]
+    fn test_rmp_enum_tuple() {
+        test_rmp_enum(T::C(-3, 22), &[0x92, 0x02, 0x92, 0xfd, 0x16]);
+        assert_eq!(::to_bytes(T::C(-3, 22)).unwrap(), &[0x93, 0x02, 0xfd, 0x16]);
+    }
+
+    #[test]
+    fn test_rmp_enum_struct() {
+        test_rmp_enum(T::D { a: 1, b: "x".into() }, &[0x92, 0x03, 0x92, 0x01, 0xa1, 0x78]);
+        assert_eq!(::to_bytes(T::D { a: 1, b: "x".into() }).unwrap(),
+                   &[0x92, 0x03, 0x82, 0xa1, 0x61, 0x01, 0xa1, 0x62, 0xa1, 0x78]);
+    }
+
+    #[test]
+    fn test_enum_fallback_variant() {
+        let config = DeserializerConfig { fallback_variant: Some("Unknown"), ..DeserializerConfig::new() };
+
+        let bytes = ::to_bytes((Event::Stop, Event::Pause(7), Event::Resize { w: 640, h: 480 }, 5u8)).unwrap();
+        let value: (OldEvent, OldEvent, OldEvent, u8) = ::from_bytes_with_config(bytes.as_slice(), config).unwrap();
+        assert_eq!(value, (OldEvent::Stop, OldEvent::Unknown, OldEvent::Unknown, 5));
+
+        // a bare index past the end
+        let value: OldEvent = ::from_bytes_with_config(&[0x2a], config).unwrap();
+        assert_eq!(value, OldEvent::Unknown);
+
+        let config = DeserializerConfig { enum_encoding: EnumEncoding::Rmp, ..config };
+        let bytes = ::to_bytes_with_config(Event::Pause(7), SerializerConfig {
+            enum_encoding: EnumEncoding::Rmp,
+            ..SerializerConfig::new()
+        }).unwrap();
+        let value: OldEvent = ::from_bytes_with_config(bytes.as_slice(), config).unwrap();
+        assert_eq!(value, OldEvent::Unknown);
+
+        // without a fallback, or with one the enum doesn't have, it still fails
+        assert!(::from_bytes::<OldEvent>(&[0x03]).is_err());
+        let config = DeserializerConfig { fallback_variant: Some("Other"), ..DeserializerConfig::new() };
+        assert!(::from_bytes_with_config::<OldEvent>(&[0x03], config).is_err());
+    }
+
+    fn test_tagged_enum(value: T, expected: &[u8]) {
+        let config = SerializerConfig { enum_encoding: EnumEncoding::ExternallyTagged, ..SerializerConfig::new() };
+        let bytes = ::to_bytes_with_config(&value, config).unwrap();
+        assert_eq!(bytes, expected);
+
+        let config = DeserializerConfig { enum_encoding: EnumEncoding::ExternallyTagged, ..DeserializerConfig::new() };
+        let actual: T = ::from_bytes_with_config(bytes.as_slice(), config).unwrap();
+        assert_eq!(actual, value);
+    }
+
+    #[test]
+    fn test_tagged_enum_variants() {
+        // "B"
+        test_tagged_enum(T::B, &[0xa1, 0x42]);
+        // {"A": 42}
+        test_tagged_enum(T::A(42), &[0x81, 0xa1, 0x41, 0x2a]);
+        // {"C": [-3, 22]}
+        test_tagged_enum(T::C(-3, 22), &[0x81, 0xa1, 0x43, 0x92, 0xfd, 0x16]);
+        // {"D": {"a": 1, "b": "x"}}
+        test_tagged_enum(T::D { a: 1, b: "x".into() },
+                         &[0x81, 0xa1, 0x44, 0x82, 0xa1, 0x61, 0x01, 0xa1, 0x62, 0xa1, 0x78]);
+    }
+
+    #[test]
+    fn test_tagged_enum_decode() {
+        let config = DeserializerConfig { enum_encoding: EnumEncoding::ExternallyTagged, ..DeserializerConfig::new() };
+
+        // a unit variant keyed by name, with nil for its value
+        let value: T = ::from_bytes_with_config(&[0x81, 0xa1, 0x42, 0xc0], config).unwrap();
+        assert_eq!(value, T::B);
+
+        // a name the enum doesn't have
+        let result: Result<T, Error> = ::from_bytes_with_config(&[0xa1, 0x45], config);
+        assert_eq!(result.unwrap_err().reason(), Reason::BadVariant);
+
+        // more than one key
+        let result: Result<T, Error> = ::from_bytes_with_config(&[0x82, 0xa1, 0x41, 0x01, 0xa1, 0x41, 0x02], config);
+        assert_eq!(result.unwrap_err().reason(), Reason::BadLength);
+
+        // unknown names are taken by the fallback, and their values skipped
+        let config = DeserializerConfig { fallback_variant: Some("Unknown"), ..config };
+        let bytes = ::to_bytes_with_config((Event::Pause(7), Event::Resize { w: 640, h: 480 }, 5u8), SerializerConfig {
+            enum_encoding: EnumEncoding::ExternallyTagged,
+            ..SerializerConfig::new()
+        }).unwrap();
+        let value: (OldEvent, OldEvent, u8) = ::from_bytes_with_config(bytes.as_slice(), config).unwrap();
+        assert_eq!(value, (OldEvent::Unknown, OldEvent::Unknown, 5));
+    }
+
+    #[test]
+    fn test_btree_set() {
+        let set: BTreeSet<u32> = vec![3, 1, 200, 70000].into_iter().collect();
+        test_through(set.clone());
+
+        // in order, with the length known up front
+        assert_eq!(::to_bytes(&set).unwrap(),
+                   &[0x94, 0x01, 0x03, 0xcc, 0xc8, 0xce, 0x00, 0x01, 0x11, 0x70]);
+
+        // duplicates collapse on the way in
+        let value: BTreeSet<u32> = ::from_bytes(&[0x93, 0x02, 0x01, 0x02]).unwrap();
+        assert_eq!(value, vec![1, 2].into_iter().collect());
+    }
+
+    #[test]
+    fn test_hash_set() {
+        let set: HashSet<String> = vec!["a".to_string(), "bc".to_string(), "".to_string()].into_iter().collect();
+        test_through(set);
+
+        let value: HashSet<String> = ::from_bytes(&[0x93, 0xa1, 0x61, 0xa1, 0x62, 0xa1, 0x61]).unwrap();
+        assert_eq!(value, vec!["a".to_string(), "b".to_string()].into_iter().collect());
+    }
+
+    #[test]
+    fn test_cow_str() {
+        test_through::<Cow<str>>(Cow::Borrowed("Hello World!"));
+        test_through::<Cow<str>>(Cow::Owned(format!("Hello World!")));
+    }
+
+    #[test]
+    fn test_cow_str_borrowed_no_alloc() {
+        let value: Cow<str> = Cow::Borrowed("Hello World!");
+        let mut buf = [0; 16];
+        let mut len = 0;
+
+        let (result, allocations) = ::test_alloc::count(|| {
+            let mut ser = Serializer::new(|bytes: &[u8]| {
+                buf[len..len + bytes.len()].clone_from_slice(bytes);
+                len += bytes.len();
+                Ok(())
+            });
+
+            value.serialize(&mut ser)
+        });
+
+        result.unwrap();
+        assert_eq!(allocations, 0);
+        assert_eq!(&buf[..len], &[0xac, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x20,
+                                  0x57, 0x6f, 0x72, 0x6c, 0x64, 0x21]);
+    }
+
+    #[test]
+    fn test_struct_no_alloc() {
+        let expected = Sample { id: 7, ratio: 0.5, tag: [1, 2, 3, 4], ok: true };
+        let bytes = ::to_bytes(&expected).unwrap();
+
+        let (result, allocations) = ::test_alloc::count(|| ::from_bytes::<Sample>(bytes.as_slice()));
+
+        assert_eq!(result.unwrap(), expected);
+        assert_eq!(allocations, 0);
+    }
+
+    #[test]
+    fn test_cow_bytes() {
+        // serde sees a Cow<[u8]> as a sequence of integers, so wrap it to get bin
+        let value: Cow<[u8]> = Cow::Borrowed(&[1, 2, 3]);
+        assert_eq!(::to_bytes(&value).unwrap(), &[0x93, 0x01, 0x02, 0x03]);
+
+        let bytes = ::to_bytes(Bytes(&*value)).unwrap();
+        assert_eq!(bytes, &[0xc4, 0x03, 0x01, 0x02, 0x03]);
+
+        let decoded: ByteBuf = ::from_bytes(&bytes).unwrap();
+        let decoded: Cow<[u8]> = Cow::Owned(decoded.into());
+        assert_eq!(decoded, value);
+    }
+
+    #[test]
+    fn test_cow_bytes_borrowed_no_alloc() {
+        let value: Cow<[u8]> = Cow::Borrowed(&[1, 2, 3]);
+        let mut buf = [0; 8];
+        let mut len = 0;
+
+        let (result, allocations) = ::test_alloc::count(|| {
+            let mut ser = Serializer::new(|bytes: &[u8]| {
+                buf[len..len + bytes.len()].clone_from_slice(bytes);
+                len += bytes.len();
+                Ok(())
+            });
+
+            Bytes(&*value).serialize(&mut ser)
+        });
+
+        result.unwrap();
+        assert_eq!(allocations, 0);
+        assert_eq!(&buf[..len], &[0xc4, 0x03, 0x01, 0x02, 0x03]);
+    }
+
+    #[test]
+    fn test_raw_message() {
+        let typed = TypedEnvelope { kind: "d".into(), body: T::D { a: 9001, b: "Hello world!".into() } };
+        let bytes = ::to_bytes(&typed).unwrap();
+
+        let envelope: Envelope = ::from_bytes(&bytes).unwrap();
+        assert_eq!(envelope.kind, "d");
+        assert_eq!(envelope.body.as_bytes(), ::to_bytes(&typed.body).unwrap().as_slice());
+        assert_eq!(envelope.body.decode::<T>().unwrap(), typed.body);
+
+        // forwarding the message must not change a byte
+        assert_eq!(::to_bytes(&envelope).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_raw_message_passthrough() {
+        let items: Vec<String> = (0..100).map(|i| format!("item {}", i)).collect();
+        let bytes = ::to_bytes(&("list", &items)).unwrap();
+
+        let (full, full_allocations) = ::test_alloc::count(|| {
+            let decoded: (String, Vec<String>) = ::from_bytes(&bytes).unwrap();
+            ::to_bytes(&decoded).unwrap()
+        });
+
+        let (passed, passed_allocations) = ::test_alloc::count(|| {
+            let decoded: (String, RawMessage) = ::from_bytes(&bytes).unwrap();
+            ::to_bytes(&decoded).unwrap()
+        });
+
+        assert_eq!(full, bytes);
+        assert_eq!(passed, bytes);
+        assert!(passed_allocations < full_allocations);
+    }
+
+    #[test]
+    fn test_raw_message_nested() {
+        let raw: RawMessage = ::from_bytes(&[0x92, 0x81, 0xa1, 0x6b, 0x93, 0x01, 0x02, 0x03, 0xc0]).unwrap();
+        assert_eq!(raw.as_bytes(), &[0x92, 0x81, 0xa1, 0x6b, 0x93, 0x01, 0x02, 0x03, 0xc0]);
+    }
+
+    #[test]
+    fn test_json_value() {
+        let value: ::serde_json::Value = ::from_bytes(&[0x85,
+                                                       0xa3, 0x73, 0x74, 0x72,
+                                                       0xa5, 0x68, 0x65, 0x6c, 0x6c, 0x6f,
+                                                       0xa3, 0x69, 0x6e, 0x74,
+                                                       0xd0, 0xd6,
+                                                       0xa5, 0x66, 0x6c, 0x6f, 0x61, 0x74,
+                                                       0xcb, 0x3f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                                                       0xa4, 0x6c, 0x69, 0x73, 0x74,
+                                                       0x93, 0x01, 0xc3, 0xc0,
+                                                       0xa3, 0x6d, 0x61, 0x70,
+                                                       0x81, 0xa1, 0x6b, 0xc2]).unwrap();
+
+        let expected: ::serde_json::Value = ::serde_json::from_str(
+            r#"{"str": "hello", "int": -42, "float": 1.5, "list": [1, true, null], "map": {"k": false}}"#)
+            .unwrap();
+
+        assert_eq!(value, expected);
+    }
+
+    #[test]
+    fn test_from_slice() {
+        let mut bytes = ::to_bytes(Sample { id: 7, ratio: 0.5, tag: *b"abcd", ok: true }).unwrap();
+        let first = bytes.len();
+        bytes.extend(::to_bytes(("second", 2u8)).unwrap());
+
+        let (sample, used): (Sample, usize) = ::from_slice(&bytes).unwrap();
+        assert_eq!(sample.id, 7);
+        assert_eq!(used, first);
+
+        let (second, used): ((String, u8), usize) = ::from_slice(&bytes[first..]).unwrap();
+        assert_eq!(second, ("second".to_string(), 2));
+        assert_eq!(first + used, bytes.len());
+
+        let result = ::from_slice::<u8>(&bytes[bytes.len()..]);
+        assert_eq!(result.unwrap_err().reason(), Reason::EndOfStream);
+    }
+
+    #[test]
+    fn test_read_slice_overflow() {
+        let bytes = [1, 2, 3];
+        let mut buf = [0; 2];
+
+        let mut position = 1;
+        ::read_slice(&bytes, &mut position, &mut buf).unwrap();
+        assert_eq!(buf, [2, 3]);
+        assert_eq!(position, 3);
+
+        assert_eq!(::read_slice(&bytes, &mut position, &mut buf).unwrap_err().reason(), Reason::EndOfStream);
+
+        // position + buf.len() would wrap around to 1
+        let mut position = usize::max_value();
+        assert_eq!(::read_slice(&bytes, &mut position, &mut buf).unwrap_err().reason(), Reason::EndOfStream);
+        assert_eq!(position, usize::max_value());
+    }
+
+    #[test]
+    #[cfg(feature = "heapless")]
+    fn test_heapless() {
+        let value = Sample { id: 7, ratio: 0.5, tag: *b"abcd", ok: true };
+        let bytes: ::heapless::Vec<u8, 64> = ::to_heapless(&value).unwrap();
+        assert_eq!(&bytes[..], ::to_bytes(&value).unwrap().as_slice());
+
+        let actual: Sample = ::from_bytes(&bytes).unwrap();
+        assert_eq!(actual, value);
+
+        // 40 bytes of str, plus its header
+        let long = "0123456789012345678901234567890123456789";
+        let result: Result<::heapless::Vec<u8, 32>, Error> = ::to_heapless(long);
+        assert_eq!(result.unwrap_err().reason(), Reason::BufferOverflow);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_ipv4_addr() {
+        use std::net::Ipv4Addr;
+
+        // always the string form, with no is_human_readable to say otherwise
+        let addr = Ipv4Addr::new(127, 0, 0, 1);
+        assert_eq!(::to_bytes(&addr).unwrap(), &[0xa9, 0x31, 0x32, 0x37, 0x2e, 0x30, 0x2e, 0x30, 0x2e, 0x31]);
+        test_through(addr);
+
+        assert_eq!(::to_bytes(Bytes(&addr.octets())).unwrap(), &[0xc4, 0x04, 0x7f, 0x00, 0x00, 0x01]);
+
+        // what a newer serde writes for it when not human readable
+        let result: Result<Ipv4Addr, Error> = ::from_bytes(&[0x94, 0x7f, 0x00, 0x00, 0x01]);
+        assert_eq!(result.unwrap_err().reason(), Reason::BadType);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_net_types() {
+        use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+        let v6 = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+        assert_eq!(::to_bytes(&v6).unwrap(), &[0xa7, 0x66, 0x65, 0x38, 0x30, 0x3a, 0x3a, 0x31]);
+        test_through(v6);
+
+        test_through(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        test_through(IpAddr::V6(v6));
+
+        // the port is part of the str
+        let socket = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 8080));
+        assert_eq!(::to_bytes(&socket).unwrap(), &[0xad,
+                                                   0x31, 0x30, 0x2e, 0x30, 0x2e, 0x30, 0x2e, 0x31,
+                                                   0x3a, 0x38, 0x30, 0x38, 0x30]);
+        test_through(socket);
+        test_through(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 53));
+
+        // flowinfo isn't in the str form, so it is lost
+        let addr = SocketAddrV6::new(v6, 443, 7, 3);
+        let bytes = ::to_bytes(&addr).unwrap();
+        assert_eq!(bytes, ::to_bytes(format!("{}", addr)).unwrap());
+
+        let actual: SocketAddrV6 = ::from_bytes(&bytes).unwrap();
+        assert_eq!((*actual.ip(), actual.port(), actual.flowinfo()), (v6, 443, 0));
+        assert_eq!(format!("{}", actual), format!("{}", addr));
+
+        test_through(SocketAddr::V6(SocketAddrV6::new(v6, 443, 0, 0)));
+    }
+
+    #[test]
+    fn test_uuid() {
+        use uuid::Uuid;
+
+        // the hyphenated str, a str8 as it is too long for a fixstr
+        let uuid = Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+        let bytes = ::to_bytes(&uuid).unwrap();
+        assert_eq!(&bytes[..3], &[0xd9, 0x24, 0x36]);
+        assert_eq!(bytes, ::to_bytes("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap());
+        test_through(uuid);
+
+        // the 16 bytes have to be asked for
+        let bytes = ::to_bytes(Bytes(&uuid.as_bytes()[..])).unwrap();
+        assert_eq!(&bytes[..3], &[0xc4, 0x10, 0x67]);
+        assert_eq!(bytes.len(), 2 + 16);
+
+        let value: ByteBuf = ::from_bytes(bytes.as_slice()).unwrap();
+        assert_eq!(Uuid::from_bytes(value.0.as_slice()).unwrap(), uuid);
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn test_from_mmap() {
+        use std::fs::{self, File};
+        use std::io::Write;
+
+        use ::test_types::Listing;
+
+        let value = Listing { version: 3, kind: "list".into(), items: (0..1000).collect() };
+        let path = ::std::env::temp_dir().join(format!("corepack-mmap-{}.msgpack", ::std::process::id()));
+        File::create(&path).unwrap().write_all(&::to_bytes(&value).unwrap()).unwrap();
+
+        let map = unsafe { ::memmap2::Mmap::map(&File::open(&path).unwrap()).unwrap() };
+        let actual: Listing = ::from_mmap(&map).unwrap();
+        drop(map);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(actual, value);
+    }
+
+    #[test]
+    fn test_error_path() {
+        let value: ::serde_json::Value = ::serde_json::from_str(
+            r#"{"config": {"servers": [{"host": "a", "port": 80},
+                                       {"host": "b", "port": 81},
+                                       {"host": "c", "port": "eighty-two"}]}}"#)
+            .unwrap();
+        let bytes = ::to_bytes(&value).unwrap();
+
+        let err = ::from_bytes::<Settings>(bytes.as_slice()).unwrap_err();
+        assert_eq!(err.reason(), Reason::BadType);
+        assert_eq!(err.path(), "config.servers[2].port");
+        assert!(format!("{}", err).ends_with(" at config.servers[2].port"));
+
+        // errors outside of any struct or sequence have no path
+        let err = ::from_bytes::<u16>(&[0xc3]).unwrap_err();
+        assert_eq!(err.path(), "");
+    }
+
+    #[test]
+    fn test_from_bytes_or_default() {
+        assert_eq!(::from_bytes_or_default::<Vec<u32>>(&[]).unwrap(), Vec::<u32>::new());
+        assert_eq!(::from_bytes_or_default::<Option<u8>>(&[]).unwrap(), None);
+        assert_eq!(::from_bytes_or_default::<Vec<u32>>(&[0x92, 0x01, 0x02]).unwrap(), vec![1, 2]);
+
+        // a value that is cut short, or of the wrong type, still fails
+        let err = ::from_bytes_or_default::<Vec<u32>>(&[0x92, 0x01]).unwrap_err();
+        assert_eq!(err.reason(), Reason::EndOfStream);
+        let err = ::from_bytes_or_default::<Vec<u32>>(&[0xa1, 0x61]).unwrap_err();
+        assert_eq!(err.reason(), Reason::BadType);
+    }
+
+    #[test]
+    fn test_renamed_fields() {
+        let value = Renamed { user_id: 7, time_stamp: 9 };
+        let bytes = ::to_bytes(&value).unwrap();
+
+        // {"userId": 7, "ts": 9}
+        assert_eq!(bytes, &[0x82, 0xa6, 0x75, 0x73, 0x65, 0x72, 0x49, 0x64, 0x07, 0xa2, 0x74, 0x73, 0x09]);
+        test_through(value);
+
+        // {"user_id": 7, "time_stamp": 9} uses the Rust names, which aren't
+        // what is on the wire, so they aren't found
+        let bytes = [0x82, 0xa7, 0x75, 0x73, 0x65, 0x72, 0x5f, 0x69, 0x64, 0x07,
+                     0xaa, 0x74, 0x69, 0x6d, 0x65, 0x5f, 0x73, 0x74, 0x61, 0x6d, 0x70, 0x09];
+        let err = ::from_bytes::<Renamed>(&bytes).unwrap_err();
+        assert_eq!(err.reason(), Reason::NoField);
+    }
+
+    #[test]
+    fn test_error_variant() {
+        // T::C with a str where its first i8 goes
+        let err = ::from_bytes::<T>(&[0x93, 0x02, 0xa1, 0x78, 0x01]).unwrap_err();
+        assert_eq!(err.reason(), Reason::BadType);
+        assert_eq!(err.path(), "<variant 2 C>");
+
+        // and T::D, in a struct field of the wrong type
+        let bytes = [0x91, 0x92, 0x03, 0x82, 0xa1, 0x61, 0xc2, 0xa1, 0x62, 0xa1, 0x78];
+        let err = ::from_bytes::<Vec<T>>(&bytes).unwrap_err();
+        assert_eq!(err.path(), "[0]<variant 3 D>.a");
+
+        // an index past the last variant has no name to show
+        let err = ::from_bytes::<T>(&[0x92, 0x07, 0xc0]).unwrap_err();
+        assert!(format!("{}", err).ends_with(" at <variant 7>"));
+    }
+
+    #[test]
+    fn test_self_check() {
+        let value = Sample { id: 7, ratio: 0.5, tag: *b"abcd", ok: true };
+        assert_eq!(::to_bytes_checked(&value, SerializerConfig::new()).unwrap(), ::to_bytes(&value).unwrap());
+
+        // corrupted output, as if the encoder had a bug
+        let mut bytes = ::to_bytes(&value).unwrap();
+        assert!(::self_check(&bytes).is_ok());
+
+        // a map header claiming one more entry than there is
+        bytes[0] += 1;
+        assert_eq!(::self_check(&bytes).unwrap_err().reason(), Reason::SelfCheckFailed);
+        bytes[0] -= 1;
+
+        // a stray byte after the value
+        bytes.push(0xc0);
+        assert_eq!(::self_check(&bytes).unwrap_err().reason(), Reason::SelfCheckFailed);
+
+        // a str that isn't UTF-8
+        assert_eq!(::self_check(&[0xa1, 0xff]).unwrap_err().reason(), Reason::SelfCheckFailed);
+        assert_eq!(::self_check(&[0xc1]).unwrap_err().reason(), Reason::SelfCheckFailed);
+    }
+
+    #[test]
+    fn test_phantom_data() {
+        let value = Tagged { id: 5, marker: PhantomData };
+
+        // {"id": 5, "marker": nil}
+        let bytes = ::to_bytes(&value).unwrap();
+        assert_eq!(bytes, &[0x82, 0xa2, 0x69, 0x64, 0x05, 0xa6, 0x6d, 0x61, 0x72, 0x6b, 0x65, 0x72, 0xc0]);
+        test_through(value);
+        test_through(PhantomData::<u32>);
+
+        // compact structs leave the nil marker out, and it decodes when read
+        // as compact output
+        let config = SerializerConfig { compact_structs: true, ..SerializerConfig::new() };
+        let bytes = ::to_bytes_with_config(&Tagged { id: 5, marker: PhantomData }, config).unwrap();
+        assert_eq!(bytes, &[0x81, 0xa2, 0x69, 0x64, 0x05]);
+        let compact = DeserializerConfig { compact_structs: true, ..DeserializerConfig::new() };
+        let value: Tagged = ::from_bytes_with_config(&bytes, compact).unwrap();
+        assert_eq!(value, Tagged { id: 5, marker: PhantomData });
+
+        let value: Result<Tagged, Error> = ::from_bytes(&bytes);
+        assert_eq!(value.unwrap_err().reason(), Reason::NoField);
+
+        // a field that can't be nil is still missing
+        let value: Result<Tagged, Error> = ::from_bytes_with_config(&[0x81, 0xa6, 0x6d, 0x61, 0x72, 0x6b, 0x65, 0x72, 0xc0], compact);
+        assert_eq!(value.unwrap_err().reason(), Reason::NoField);
+    }
+
+    #[test]
+    fn test_missing_fields() {
+        // {"id": 1, "extra": 2}, where only the Option may be left out
+        let value: Annotated = ::from_bytes(&[0x82, 0xa2, 0x69, 0x64, 0x01, 0xa5, 0x65, 0x78, 0x74, 0x72, 0x61, 0x02]).unwrap();
+        assert_eq!(value, Annotated { id: 1, note: None, extra: ::Value::UInt(2) });
+
+        // {"id": 1}, whose Value is missing rather than nil
+        let bytes = [0x81, 0xa2, 0x69, 0x64, 0x01];
+        let value: Result<Annotated, Error> = ::from_bytes(&bytes);
+        assert_eq!(value.unwrap_err().reason(), Reason::NoField);
+
+        // unless the input is compact output, where it was left out for it
+        let compact = DeserializerConfig { compact_structs: true, ..DeserializerConfig::new() };
+        let value: Annotated = ::from_bytes_with_config(&bytes, compact).unwrap();
+        assert_eq!(value, Annotated { id: 1, note: None, extra: ::Value::Nil });
+    }
+
+    #[test]
+    fn test_shared_pointers() {
+        let value = Arc::new(format!("shared"));
+        assert_eq!(::to_bytes(&value).unwrap(), ::to_bytes("shared").unwrap());
+        test_through(value);
+
+        let value = Rc::new(vec![1u32, 2, 70000]);
+        assert_eq!(::to_bytes(&value).unwrap(), ::to_bytes(&vec![1u32, 2, 70000]).unwrap());
+        test_through(value);
+
+        test_through(vec![Arc::new(1u8), Arc::new(2)]);
+    }
+
+    #[test]
+    fn test_boxed_slice() {
+        let value: Box<[u32]> = vec![1, 300, 70000].into_boxed_slice();
+        assert_eq!(::to_bytes(&value).unwrap(), ::to_bytes(&vec![1u32, 300, 70000]).unwrap());
+        test_through(value);
+        test_through(Vec::<u32>::new().into_boxed_slice());
+
+        let value: Box<str> = format!("Hello World!").into_boxed_str();
+        assert_eq!(::to_bytes(&value).unwrap(), ::to_bytes("Hello World!").unwrap());
+        test_through(value);
+    }
+
+    #[test]
+    fn test_unit_in_tuple() {
+        let bytes = ::to_bytes(&(1u8, (), 2u8)).unwrap();
+        assert_eq!(bytes, &[0x93, 0x01, 0xc0, 0x02]);
+        test_through((1u8, (), 2u8));
+        test_through(vec![(), (), ()]);
+
+        // the unit still takes one element under the other encodings
+        let config = SerializerConfig { unit_encoding: UnitEncoding::EmptyArray, ..SerializerConfig::new() };
+        let bytes = ::to_bytes_with_config(&(1u8, (), 2u8), config).unwrap();
+        assert_eq!(bytes, &[0x93, 0x01, 0x90, 0x02]);
+
+        let config = DeserializerConfig { unit_encoding: UnitEncoding::EmptyArray, ..DeserializerConfig::new() };
+        let value: (u8, (), u8) = ::from_bytes_with_config(bytes.as_slice(), config).unwrap();
+        assert_eq!(value, (1, (), 2));
+    }
+
+    #[test]
+    fn test_option_vec() {
+        test_through(Some(vec![1u32, 2, 3]));
+        test_through(Some(Vec::<u32>::new()));
+        test_through(None::<Vec<u32>>);
+    }
+
+    #[test]
+    fn test_vec_option() {
+        let value = vec![Some(1u32), None, Some(300)];
+        assert_eq!(::to_bytes(&value).unwrap(), &[0x93, 0x01, 0xc0, 0xcd, 0x01, 0x2c]);
+        test_through(value);
+        test_through(vec![None::<u32>; 3]);
+    }
+
+    #[test]
+    fn test_nested_vec() {
+        let value = vec![vec![1u32, 2], vec![3], vec![]];
+        assert_eq!(::to_bytes(&value).unwrap(), &[0x93, 0x92, 0x01, 0x02, 0x91, 0x03, 0x90]);
+        test_through(value);
+
+        // empty inner arrays first and last, with nothing between them
+        test_through(vec![vec![], vec![7u32], vec![]]);
+        test_through(vec![Vec::<u32>::new(); 4]);
+        test_through(Vec::<Vec<u32>>::new());
+
+        // an inner array long enough for an array16 header
+        let value = vec![(0..20).collect::<Vec<u32>>(), vec![]];
+        assert_eq!(&::to_bytes(&value).unwrap()[..4], &[0x92, 0xdc, 0x00, 0x14]);
+        test_through(value);
+
+        test_through(vec![vec![vec![1u8], vec![]], vec![]]);
+    }
+
+    #[test]
+    fn test_option_option() {
+        test_through(Some(Some(format!("Hello"))));
+        test_through(None::<Option<String>>);
+
+        // Some(None) is written as nil, and nil is read back as the outer None
+        assert_eq!(::to_bytes(&Some(None::<String>)).unwrap(), &[0xc0]);
+        let value: Option<Option<String>> = ::from_bytes(&[0xc0]).unwrap();
+        assert_eq!(value, None);
+    }
+
+    fn test_unit_encoding(encoding: UnitEncoding, expected: &[u8]) {
+        let config = SerializerConfig { unit_encoding: encoding, ..SerializerConfig::new() };
+        let bytes = ::to_bytes_with_config(Unit, config).unwrap();
+        assert_eq!(bytes, expected);
+
+        // None is nil whatever the unit encoding
+        let none: Option<u8> = None;
+        assert_eq!(::to_bytes_with_config(none, config).unwrap(), &[0xc0]);
+
+        let config = DeserializerConfig { unit_encoding: encoding, ..DeserializerConfig::new() };
+        let value: Unit = ::from_bytes_with_config(bytes.as_slice(), config).unwrap();
+        assert_eq!(value, Unit);
+
+        let value: () = ::from_bytes_with_config(bytes.as_slice(), config).unwrap();
+        assert_eq!(value, ());
+
+        // nil is accepted too
+        let value: Unit = ::from_bytes_with_config(&[0xc0], config).unwrap();
+        assert_eq!(value, Unit);
+    }
+
+    #[test]
+    fn test_unit_nil() {
+        test_unit_encoding(UnitEncoding::Nil, &[0xc0]);
+    }
+
+    #[test]
+    fn test_unit_empty_array() {
+        test_unit_encoding(UnitEncoding::EmptyArray, &[0x90]);
+    }
+
+    #[test]
+    fn test_unit_empty_map() {
+        test_unit_encoding(UnitEncoding::EmptyMap, &[0x80]);
+    }
 }
@@ -0,0 +1,43 @@
+//! The marker bytes of the MessagePack format, and the size limits of each
+//! family, for writing or checking encodings by hand.
+//!
+//! Single-byte markers are `u8`s. The fix families, which fold a small value
+//! into the marker, are ranges of markers along with the mask that marks them,
+//! so `FIXARRAY_MASK | 3` starts an array of three.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+pub use defs::{POS_FIXINT, FIXMAP, FIXARRAY, FIXSTR, NEG_FIXINT};
+pub use defs::{FIXMAP_MASK, FIXARRAY_MASK, FIXSTR_MASK};
+pub use defs::{NIL, FALSE, TRUE};
+pub use defs::{BIN8, BIN16, BIN32, EXT8, EXT16, EXT32, FLOAT32, FLOAT64};
+pub use defs::{UINT8, UINT16, UINT32, UINT64, INT8, INT16, INT32, INT64};
+pub use defs::{FIXEXT1, FIXEXT2, FIXEXT4, FIXEXT8, FIXEXT16};
+pub use defs::{STR8, STR16, STR32, ARRAY16, ARRAY32, MAP16, MAP32};
+
+pub use defs::{FIXINT_MAX, FIXINT_MIN};
+pub use defs::{MAX_FIXMAP, MAX_MAP16, MAX_MAP32};
+pub use defs::{MAX_FIXSTR, MAX_STR8, MAX_STR16, MAX_STR32};
+pub use defs::{MAX_FIXARRAY, MAX_ARRAY16, MAX_ARRAY32};
+pub use defs::{MAX_BIN8, MAX_BIN16, MAX_BIN32};
+pub use defs::{MAX_EXT8, MAX_EXT16, MAX_EXT32};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn markers_test() {
+        assert_eq!(::to_bytes(200u8).unwrap(), &[UINT8, 200]);
+        assert_eq!(::to_bytes(-100i8).unwrap(), &[INT8, -100i8 as u8]);
+        assert_eq!(::to_bytes(()).unwrap(), &[NIL]);
+        assert_eq!(::to_bytes((true, false)).unwrap(), &[FIXARRAY_MASK | 2, TRUE, FALSE]);
+        assert_eq!(::to_bytes("hi").unwrap(), &[FIXSTR_MASK | 2, b'h', b'i']);
+
+        assert!(FIXSTR.contains(&(FIXSTR_MASK | MAX_FIXSTR as u8)));
+        assert!(POS_FIXINT.contains(&FIXINT_MAX));
+        assert!(NEG_FIXINT.contains(&(FIXINT_MIN as u8)));
+    }
+}
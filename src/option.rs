@@ -0,0 +1,54 @@
+//! Telling an absent field apart from one that is present but nil.
+//!
+//! serde decodes nil into `None` for any `Option`, so an `Option<Option<T>>`
+//! field comes out as `None` both when its key is missing and when its value is
+//! nil. Pointing the field's `deserialize_with` at `deserialize` here, along
+//! with `#[serde(default)]`, keeps the two apart:
+//!
+//! - an absent key gives `None`, from the default
+//! - a nil value gives `Some(None)`
+//! - any other value gives `Some(Some(v))`
+//!
+//! No helper is needed to serialize such a field. Adding
+//! `#[serde(skip_serializing_if = "Option::is_none")]` leaves the key out for
+//! `None`, and `Some(None)` is written as nil.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use serde;
+
+/// Deserialize a present field as `Some`, for use with `#[serde(deserialize_with)]`.
+pub fn deserialize<T, D>(deserializer: &mut D) -> Result<Option<Option<T>>, D::Error>
+    where T: serde::Deserialize, D: serde::Deserializer {
+    serde::Deserialize::deserialize(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use test_types::Patch;
+
+    #[test]
+    fn absent_test() {
+        let value: Patch = ::from_bytes(&[0x80]).unwrap();
+        assert_eq!(value.value, None);
+        assert_eq!(::to_bytes(&value).unwrap(), &[0x80]);
+    }
+
+    #[test]
+    fn nil_test() {
+        let bytes = [0x81, 0xa5, 0x76, 0x61, 0x6c, 0x75, 0x65, 0xc0];
+        let value: Patch = ::from_bytes(&bytes).unwrap();
+        assert_eq!(value.value, Some(None));
+        assert_eq!(::to_bytes(&value).unwrap(), &bytes);
+    }
+
+    #[test]
+    fn value_test() {
+        let bytes = [0x81, 0xa5, 0x76, 0x61, 0x6c, 0x75, 0x65, 0x05];
+        let value: Patch = ::from_bytes(&bytes).unwrap();
+        assert_eq!(value.value, Some(Some(5)));
+        assert_eq!(::to_bytes(&value).unwrap(), &bytes);
+    }
+}
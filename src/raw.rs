@@ -0,0 +1,84 @@
+//! Deferred decoding of single values.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::vec::Vec;
+
+use serde;
+
+use bytes::Bytes;
+use error::Error;
+
+/// Newtype struct name that the Serializer and Deserializer treat as a request
+/// to copy encoded bytes verbatim.
+pub const TOKEN: &'static str = "__corepack_RawMessage";
+
+/// The encoded bytes of one complete value, captured without decoding it.
+///
+/// As a field in a larger message, a RawMessage lets the rest be decoded while
+/// this part is forwarded or decoded later. Serializing it writes the bytes
+/// back out unchanged. Outside of corepack it serializes as a newtype around
+/// a byte array.
+///
+/// A proxy that re-encodes what it decodes, with the same config, can use a
+/// RawMessage for every part it doesn't look at. Those parts are then copied
+/// through as bytes instead of being decoded and encoded again. Nothing picks
+/// these parts out automatically; the proxy's own types say which they are.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RawMessage(Vec<u8>);
+
+struct RawMessageVisitor;
+
+impl RawMessage {
+    /// Wrap already-encoded bytes. They must hold exactly one value, or any
+    /// message this is serialized into will be corrupt.
+    pub fn new(bytes: Vec<u8>) -> RawMessage {
+        RawMessage(bytes)
+    }
+
+    /// Get the encoded bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+
+    /// Take the encoded bytes.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    /// Decode the captured value.
+    pub fn decode<V>(&self) -> Result<V, Error>
+        where V: serde::Deserialize {
+        ::from_bytes(self.0.as_slice())
+    }
+}
+
+impl serde::Serialize for RawMessage {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer {
+        serializer.serialize_newtype_struct(TOKEN, Bytes(self.0.as_slice()))
+    }
+}
+
+impl serde::de::Visitor for RawMessageVisitor {
+    type Value = RawMessage;
+
+    fn visit_bytes<E>(&mut self, v: &[u8]) -> Result<RawMessage, E>
+        where E: serde::de::Error {
+        Ok(RawMessage(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(&mut self, v: Vec<u8>) -> Result<RawMessage, E>
+        where E: serde::de::Error {
+        Ok(RawMessage(v))
+    }
+}
+
+impl serde::Deserialize for RawMessage {
+    fn deserialize<D>(deserializer: &mut D) -> Result<RawMessage, D::Error>
+        where D: serde::Deserializer {
+        deserializer.deserialize_newtype_struct(TOKEN, RawMessageVisitor)
+    }
+}
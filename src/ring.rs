@@ -0,0 +1,248 @@
+//! Decoding values as their bytes arrive, out of a fixed ring buffer.
+//!
+//! Bytes that turn up a few at a time, say from a UART interrupt, are pushed
+//! into a `RingDeserializer` over storage the caller provides, such as a
+//! static array. Each call to `next_value` decodes one value if all of its
+//! bytes are in, or fails with `Reason::NeedMore` and leaves the buffer alone
+//! if they aren't. Nothing is allocated beyond what the decoded type needs.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use serde;
+
+use de::{Deserializer, DeserializerConfig};
+use error::{Error, Reason};
+
+/// A ring buffer of received bytes, decoded a value at a time.
+pub struct RingDeserializer<'a> {
+    storage: &'a mut [u8],
+    // index of the oldest byte, and how many bytes are buffered
+    head: usize,
+    len: usize,
+    config: DeserializerConfig
+}
+
+impl<'a> RingDeserializer<'a> {
+    /// Create an empty ring that buffers bytes in storage.
+    pub fn new(storage: &'a mut [u8]) -> RingDeserializer<'a> {
+        RingDeserializer::with_config(storage, DeserializerConfig::new())
+    }
+
+    /// Create an empty ring that buffers bytes in storage, given configuration
+    /// for decoding.
+    pub fn with_config(storage: &'a mut [u8], config: DeserializerConfig) -> RingDeserializer<'a> {
+        RingDeserializer {
+            storage: storage,
+            head: 0,
+            len: 0,
+            config: config
+        }
+    }
+
+    /// The number of bytes buffered.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether no bytes are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Throw away everything buffered.
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Add a received byte. Fails with `Reason::LimitExceeded` if the ring is
+    /// full, in which case the byte is dropped.
+    pub fn push(&mut self, byte: u8) -> Result<(), Error> {
+        if self.len == self.storage.len() {
+            return Err(Error::simple(Reason::LimitExceeded));
+        }
+
+        let tail = (self.head + self.len) % self.storage.len();
+        self.storage[tail] = byte;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Decode the next value, and drop its bytes from the ring.
+    ///
+    /// Fails with `Reason::NeedMore` if only part of the value has arrived, and
+    /// drops nothing. Any other error drops the bytes read up to it, so the
+    /// stream can carry on past bad input. A value too big for the ring can
+    /// never be decoded, so if the ring is full and still holds only part of
+    /// one, this fails with `Reason::LimitExceeded` instead, and the ring has
+    /// to be cleared to carry on.
+    pub fn next_value<V>(&mut self) -> Result<V, Error>
+        where V: serde::Deserialize {
+        let (storage, head, len) = (&*self.storage, self.head, self.len);
+
+        // find where the value ends first, so only whole values are decoded
+        let mut position = 0;
+        let skipped = {
+            let mut de = Deserializer::with_config(|buf: &mut [u8]| read_ring(storage, head, len, &mut position, buf),
+                                                   self.config);
+            de.skip()
+        };
+
+        match skipped {
+            Ok(()) => {}
+            Err(ref e) if e.reason() == Reason::EndOfStream => {
+                if len == self.storage.len() {
+                    return Err(Error::new(Reason::LimitExceeded, format!("Value longer than the ring's {} bytes", len)));
+                }
+                return Err(Error::simple(Reason::NeedMore));
+            }
+            Err(e) => {
+                self.consume(position);
+                return Err(e);
+            }
+        }
+
+        let size = position;
+        let mut position = 0;
+        let result = {
+            let mut de = Deserializer::with_config(|buf: &mut [u8]| read_ring(storage, head, len, &mut position, buf),
+                                                   self.config);
+            <V as serde::Deserialize>::deserialize(&mut de)
+        };
+
+        self.consume(size);
+        result
+    }
+
+    fn consume(&mut self, size: usize) {
+        if size > 0 {
+            self.head = (self.head + size) % self.storage.len();
+            self.len -= size;
+        }
+    }
+}
+
+// fill buf from the bytes position past head, failing if too few are buffered
+fn read_ring(storage: &[u8], head: usize, len: usize, position: &mut usize, buf: &mut [u8]) -> Result<(), Error> {
+    if buf.len() > len - *position {
+        return Err(Error::simple(Reason::EndOfStream));
+    }
+
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = storage[(head + *position + i) % storage.len()];
+    }
+    *position += buf.len();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::String;
+
+    use error::Reason;
+
+    use super::RingDeserializer;
+
+    #[test]
+    fn byte_at_a_time_test() {
+        // 1, [2, -300], 70000
+        let bytes = [0x01, 0x92, 0x02, 0xd1, 0xfe, 0xd4, 0xce, 0x00, 0x01, 0x11, 0x70];
+
+        let mut storage = [0; 16];
+        let mut ring = RingDeserializer::new(&mut storage);
+
+        let (result, allocations) = ::test_alloc::count(|| {
+            let mut ends = [0; 3];
+            let mut found = 0;
+            let mut first = 0u32;
+            let mut second = (0u8, 0i16);
+            let mut third = 0u32;
+
+            for (i, &byte) in bytes.iter().enumerate() {
+                ring.push(byte).unwrap();
+
+                let result = match found {
+                    0 => ring.next_value().map(|v| first = v),
+                    1 => ring.next_value().map(|v| second = v),
+                    _ => ring.next_value().map(|v| third = v)
+                };
+
+                match result {
+                    Ok(()) => {
+                        ends[found] = i;
+                        found += 1;
+                    }
+                    Err(e) => assert_eq!(e.reason(), Reason::NeedMore)
+                }
+            }
+
+            (ends, first, second, third)
+        });
+
+        assert_eq!(result, ([0, 5, 10], 1, (2, -300), 70000));
+        assert_eq!(allocations, 0);
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn wrap_around_test() {
+        // shorter than two values, so they keep wrapping past the end
+        let mut storage = [0; 8];
+        let mut ring = RingDeserializer::new(&mut storage);
+
+        for n in 0..20u32 {
+            let value = 0x01000000 + n;
+            let bytes = [0xce, (value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8];
+
+            for &byte in bytes.iter() {
+                assert_eq!(ring.next_value::<u32>().unwrap_err().reason(), Reason::NeedMore);
+                ring.push(byte).unwrap();
+            }
+
+            assert_eq!(ring.next_value::<u32>().unwrap(), value);
+        }
+
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn full_test() {
+        let mut storage = [0; 4];
+        let mut ring = RingDeserializer::new(&mut storage);
+
+        // a str of 4 bytes needs 5 in the ring
+        for &byte in [0xa4, 0x61, 0x62, 0x63].iter() {
+            ring.push(byte).unwrap();
+        }
+        assert_eq!(ring.push(0x64).unwrap_err().reason(), Reason::LimitExceeded);
+        assert_eq!(ring.next_value::<String>().unwrap_err().reason(), Reason::LimitExceeded);
+        assert_eq!(ring.len(), 4);
+
+        ring.clear();
+        ring.push(0x07).unwrap();
+        assert_eq!(ring.next_value::<u8>().unwrap(), 7);
+    }
+
+    #[test]
+    fn bad_input_test() {
+        let mut storage = [0; 4];
+        let mut ring = RingDeserializer::new(&mut storage);
+
+        // a reserved marker is dropped, and decoding picks up after it
+        ring.push(0xc1).unwrap();
+        ring.push(0x05).unwrap();
+        assert_eq!(ring.next_value::<u8>().unwrap_err().reason(), Reason::BadType);
+        assert_eq!(ring.next_value::<u8>().unwrap(), 5);
+
+        // so is a whole value of the wrong type
+        ring.push(0xa1).unwrap();
+        ring.push(0x61).unwrap();
+        ring.push(0x06).unwrap();
+        assert!(ring.next_value::<u8>().is_err());
+        assert_eq!(ring.next_value::<u8>().unwrap(), 6);
+    }
+}
@@ -2,42 +2,615 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::mem;
 use std::result;
 
-use collections::{Vec, String};
-
-use byteorder::{ByteOrder, BigEndian, LittleEndian};
+use alloc::vec::Vec;
+use alloc::string::String;
 
 use serde;
 
 use defs::*;
+use erased::ErasedSerialize;
 use error::*;
 
 pub type Result = result::Result<(), Error>;
 
+type Header = [u8; U32_BYTES + 1];
+
 /// The corepack Serializer. Contains a closure that receives byte buffers as
 /// the output is created.
+///
+/// An error leaves the Serializer partway through the value it was writing,
+/// with the output so far incomplete and any unknown-length collections still
+/// open and held back. It can't be used for anything more after that; start
+/// again with a new one.
 pub struct Serializer<F: FnMut(&[u8]) -> Result> {
-    output: F
+    output: F,
+    // set while serializing a RawMessage, so its bytes are copied verbatim
+    raw: bool,
+    // set while serializing a Fixed, until its integer has been written
+    fixed: bool,
+    // output held back until every open unknown-length collection has ended
+    buffer: Vec<u8>,
+    // headers of those collections, as (offset into buffer, header, header
+    // length), spliced in when the buffer is flushed
+    headers: Vec<(usize, Header, usize)>,
+    // number of unknown-length collections still open
+    pending: usize,
+    // set when buffer is the final output, so headers are spliced into it
+    // rather than written out
+    direct: bool,
+    // values started, and the elements their headers promised, for checking
+    // that each map value is written as exactly one value
+    values: usize,
+    owed: usize,
+    // bytes written into or moved within buffer, for checking that nesting
+    // doesn't add copies
+    #[cfg(test)]
+    copied: usize,
+    config: SerializerConfig
+}
+
+/// How unit values and unit structs are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnitEncoding {
+    /// As nil. This is the default.
+    Nil,
+    /// As an empty array.
+    EmptyArray,
+    /// As an empty map.
+    EmptyMap
+}
+
+/// How enum variants are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnumEncoding {
+    /// corepack's own encoding. Unit variants are their variant index, and
+    /// other variants are an array of the index followed by the newtype value,
+    /// tuple elements or struct. This is the default.
+    Index,
+    /// The encoding used by rmp-serde: always a two element array of the
+    /// variant index and an array of the variant's values. Unit variants have
+    /// an empty array, newtype variants a one element array, and struct
+    /// variants an array of their field values without the names.
+    Rmp,
+    /// The externally tagged form serde_json uses, keyed by variant name.
+    /// Unit variants are their name as a str, and other variants a map of one
+    /// entry from their name to the newtype value, an array of the tuple
+    /// elements, or a map of the struct's fields.
+    ExternallyTagged
+}
+
+/// Options for how a Serializer writes its output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerializerConfig {
+    /// How unit values and unit structs are encoded.
+    pub unit_encoding: UnitEncoding,
+    /// How enum variants are encoded.
+    pub enum_encoding: EnumEncoding,
+    /// Leave struct fields whose value is nil, such as a `None`, out of the
+    /// struct's map entirely, without a `skip_serializing_if` on each one. The
+    /// fields of struct variants are left out the same way. The map's length
+    /// is then only known once every field has been seen, so it is written
+    /// like one of unknown length.
+    pub compact_structs: bool,
+    /// The most bytes the output function is given at once. Longer pieces of
+    /// output, like the body of a large str or bin, are split up to fit. A
+    /// limit of zero is treated as one.
+    pub max_chunk: Option<usize>,
+    /// Write every `f32` as a float 64, for readers that can't handle float
+    /// 32. The value is widened exactly, so it decodes back unchanged.
+    pub always_f64: bool
+}
+
+/// The header of an ext value with size bytes of data, and its length.
+pub(crate) fn ext_header(ty: i8, size: usize) -> result::Result<([u8; U32_BYTES + 2], usize), Error> {
+    let mut buf = [0; U32_BYTES + 2];
+
+    // there is no fixext for an empty payload, so it falls through to ext8
+    let len = match size {
+        1 => { buf[0] = FIXEXT1; 1 }
+        2 => { buf[0] = FIXEXT2; 1 }
+        4 => { buf[0] = FIXEXT4; 1 }
+        8 => { buf[0] = FIXEXT8; 1 }
+        16 => { buf[0] = FIXEXT16; 1 }
+        size if size <= MAX_EXT8 => {
+            buf[0] = EXT8;
+            buf[1] = size as u8;
+            2
+        }
+        size if size <= MAX_EXT16 => {
+            buf[0] = EXT16;
+            buf[1..U16_BYTES + 1].copy_from_slice(&(size as u16).to_be_bytes());
+            U16_BYTES + 1
+        }
+        size if size <= MAX_EXT32 => {
+            buf[0] = EXT32;
+            buf[1..U32_BYTES + 1].copy_from_slice(&(size as u32).to_be_bytes());
+            U32_BYTES + 1
+        }
+        _ => return Err(Error::simple(Reason::TooBig))
+    };
+
+    buf[len] = ty as u8;
+
+    Ok((buf, len + 1))
+}
+
+impl UnitEncoding {
+    pub(crate) fn marker(&self) -> u8 {
+        match *self {
+            UnitEncoding::Nil => NIL,
+            UnitEncoding::EmptyArray => FIXARRAY_MASK,
+            UnitEncoding::EmptyMap => FIXMAP_MASK
+        }
+    }
+}
+
+impl SerializerConfig {
+    /// Create the default configuration.
+    pub const fn new() -> SerializerConfig {
+        SerializerConfig {
+            unit_encoding: UnitEncoding::Nil,
+            enum_encoding: EnumEncoding::Index,
+            compact_structs: false,
+            max_chunk: None,
+            always_f64: false
+        }
+    }
+}
+
+impl Default for SerializerConfig {
+    fn default() -> SerializerConfig {
+        SerializerConfig::new()
+    }
+}
+
+fn array_header(size: usize) -> result::Result<(Header, usize), Error> {
+    let mut buf = [0; U32_BYTES + 1];
+
+    if size <= MAX_FIXARRAY {
+        buf[0] = size as u8 | FIXARRAY_MASK;
+        Ok((buf, 1))
+    } else if size <= MAX_ARRAY16 {
+        buf[0] = ARRAY16;
+        buf[1..U16_BYTES + 1].copy_from_slice(&(size as u16).to_be_bytes());
+        Ok((buf, U16_BYTES + 1))
+    } else if size <= MAX_ARRAY32 {
+        buf[0] = ARRAY32;
+        buf[1..].copy_from_slice(&(size as u32).to_be_bytes());
+        Ok((buf, U32_BYTES + 1))
+    } else {
+        Err(Error::new(Reason::TooBig, format!("array of {} elements is over the array32 limit of {}", size, MAX_ARRAY32)))
+    }
+}
+
+fn map_header(size: usize) -> result::Result<(Header, usize), Error> {
+    let mut buf = [0; U32_BYTES + 1];
+
+    if size <= MAX_FIXMAP {
+        buf[0] = size as u8 | FIXMAP_MASK;
+        Ok((buf, 1))
+    } else if size <= MAX_MAP16 {
+        buf[0] = MAP16;
+        buf[1..U16_BYTES + 1].copy_from_slice(&(size as u16).to_be_bytes());
+        Ok((buf, U16_BYTES + 1))
+    } else if size <= MAX_MAP32 {
+        buf[0] = MAP32;
+        buf[1..].copy_from_slice(&(size as u32).to_be_bytes());
+        Ok((buf, U32_BYTES + 1))
+    } else {
+        Err(Error::new(Reason::TooBig, format!("map of {} entries is over the map32 limit of {}", size, MAX_MAP32)))
+    }
+}
+
+fn str_header(size: usize) -> result::Result<(Header, usize), Error> {
+    let mut buf = [0; U32_BYTES + 1];
+
+    if size <= MAX_FIXSTR {
+        buf[0] = size as u8 | FIXSTR_MASK;
+        Ok((buf, 1))
+    } else if size <= MAX_STR8 {
+        buf[0] = STR8;
+        buf[1] = size as u8;
+        Ok((buf, 2))
+    } else if size <= MAX_STR16 {
+        buf[0] = STR16;
+        buf[1..U16_BYTES + 1].copy_from_slice(&(size as u16).to_be_bytes());
+        Ok((buf, U16_BYTES + 1))
+    } else if size <= MAX_STR32 {
+        buf[0] = STR32;
+        buf[1..].copy_from_slice(&(size as u32).to_be_bytes());
+        Ok((buf, U32_BYTES + 1))
+    } else {
+        Err(Error::new(Reason::TooBig, format!("str of {} bytes is over the str32 limit of {}", size, MAX_STR32)))
+    }
+}
+
+fn bin_header(size: usize) -> result::Result<(Header, usize), Error> {
+    let mut buf = [0; U32_BYTES + 1];
+
+    // there is no fixbin
+    if size <= MAX_BIN8 {
+        buf[0] = BIN8;
+        buf[1] = size as u8;
+        Ok((buf, 2))
+    } else if size <= MAX_BIN16 {
+        buf[0] = BIN16;
+        buf[1..U16_BYTES + 1].copy_from_slice(&(size as u16).to_be_bytes());
+        Ok((buf, U16_BYTES + 1))
+    } else if size <= MAX_BIN32 {
+        buf[0] = BIN32;
+        buf[1..].copy_from_slice(&(size as u32).to_be_bytes());
+        Ok((buf, U32_BYTES + 1))
+    } else {
+        Err(Error::new(Reason::TooBig, format!("bin of {} bytes is over the bin32 limit of {}", size, MAX_BIN32)))
+    }
 }
 
 impl<F: FnMut(&[u8]) -> Result> Serializer<F> {
     /// Create a new Serializer given an output function.
     pub const fn new(output: F) -> Serializer<F> {
+        Serializer::with_config(output, SerializerConfig::new())
+    }
+
+    /// Create a new Serializer given an output function and configuration.
+    pub const fn with_config(output: F, config: SerializerConfig) -> Serializer<F> {
         Serializer {
-            output: output
+            output: output,
+            raw: false,
+            fixed: false,
+            buffer: Vec::new(),
+            headers: Vec::new(),
+            pending: 0,
+            direct: false,
+            values: 0,
+            owed: 0,
+            #[cfg(test)]
+            copied: 0,
+            config: config
         }
     }
 
+    /// Start an array that is written one element at a time through the
+    /// returned handle, which can be passed between functions as a message is
+    /// built. Given a length, the header is written now and exactly that many
+    /// elements must be pushed. Without one, the array is held back until it
+    /// ends.
+    ///
+    /// The array has to be finished with `end`. Dropping the handle without it
+    /// leaves the Serializer partway through the array, and anything written
+    /// after that is held back or misplaced.
+    pub fn begin_array(&mut self, len: Option<usize>) -> result::Result<ArrayBuilder<F>, Error> {
+        let state = try!(serde::Serializer::serialize_seq(self, len));
+        Ok(ArrayBuilder { ser: self, len: len, count: 0, state: state })
+    }
+
+    /// Start a map that is written one entry at a time through the returned
+    /// handle. The length works as for begin_array, counting entries, and the
+    /// map has to be finished with `end` the same way.
+    pub fn begin_map(&mut self, len: Option<usize>) -> result::Result<MapBuilder<F>, Error> {
+        let state = try!(serde::Serializer::serialize_map(self, len));
+        Ok(MapBuilder { ser: self, len: len, count: 0, state: state })
+    }
+
+    // output the start of a value, which a Fixed no longer applies past
+    fn output_value(&mut self, buf: &[u8]) -> Result {
+        self.values += 1;
+        self.fixed = false;
+        self.output(buf)
+    }
+
     fn output(&mut self, buf: &[u8]) -> Result {
-        self.output.call_mut((buf,))
+        if self.direct || self.pending > 0 {
+            self.buffer.extend_from_slice(buf);
+            #[cfg(test)]
+            {
+                self.copied += buf.len();
+            }
+            Ok(())
+        } else {
+            self.emit(buf)
+        }
+    }
+
+    // hand bytes to the output function, no more than max_chunk at a time
+    fn emit(&mut self, buf: &[u8]) -> Result {
+        match self.config.max_chunk {
+            Some(max) if buf.len() > max => {
+                for chunk in buf.chunks(::std::cmp::max(max, 1)) {
+                    try!((self.output)(chunk));
+                }
+                Ok(())
+            }
+            _ => (self.output)(buf)
+        }
+    }
+
+    fn take_fixed(&mut self) -> bool {
+        mem::replace(&mut self.fixed, false)
+    }
+
+    /// Write the header of an array of size elements, in the smallest format
+    /// that fits, and nothing else. The elements must follow.
+    pub fn write_array_len(&mut self, size: usize) -> Result {
+        let (header, len) = try!(array_header(size));
+        self.owed = self.owed.wrapping_add(size);
+        self.output_value(&header[..len])
+    }
+
+    /// Write the header of a map of size entries. The keys and values must
+    /// follow, alternating.
+    pub fn write_map_len(&mut self, size: usize) -> Result {
+        let (header, len) = try!(map_header(size));
+        self.owed = self.owed.wrapping_add(size.wrapping_mul(2));
+        self.output_value(&header[..len])
+    }
+
+    /// Write the header of a str of size bytes. The bytes must follow, with
+    /// write_raw, and be valid UTF-8.
+    pub fn write_str_len(&mut self, size: usize) -> Result {
+        let (header, len) = try!(str_header(size));
+        self.output_value(&header[..len])
+    }
+
+    /// Write the header of a bin of size bytes. The bytes must follow, with
+    /// write_raw.
+    pub fn write_bin_len(&mut self, size: usize) -> Result {
+        let (header, len) = try!(bin_header(size));
+        self.output_value(&header[..len])
+    }
+
+    /// Write bytes to the output as they are. Anything but the body promised
+    /// by a header, or whole encoded values, makes the output invalid.
+    pub fn write_raw(&mut self, bytes: &[u8]) -> Result {
+        self.output(bytes)
+    }
+
+    /// Write a type-erased value with this Serializer's configuration, its
+    /// bytes going into the output like those of any other value.
+    pub fn serialize_erased(&mut self, value: &dyn ErasedSerialize) -> Result {
+        let config = self.config;
+        self.values += 1;
+
+        let mut output = |buf: &[u8]| self.output(buf);
+        let mut ser = Serializer::with_config(&mut output as &mut dyn FnMut(&[u8]) -> Result, config);
+        value.erased_serialize(&mut ser)
+    }
+
+    fn begin_unsized(&mut self) -> (usize, usize) {
+        self.values += 1;
+        self.fixed = false;
+
+        // reserve a slot for the header, filled in once the size is known
+        self.headers.push((self.buffer.len(), [0; U32_BYTES + 1], 0));
+        self.pending += 1;
+
+        (0, self.headers.len() - 1)
+    }
+
+    fn end_unsized(&mut self, slot: usize, header: (Header, usize)) -> Result {
+        self.headers[slot].1 = header.0;
+        self.headers[slot].2 = header.1;
+        self.pending -= 1;
+
+        if self.pending == 0 {
+            self.flush()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn flush(&mut self) -> Result {
+        if self.direct {
+            self.splice_headers();
+            return Ok(());
+        }
+
+        // every byte is copied once into the buffer and once out of it, no
+        // matter how deeply the unknown-length collections were nested
+        let mut buffer = mem::replace(&mut self.buffer, Vec::new());
+        let mut headers = mem::replace(&mut self.headers, Vec::new());
+        let mut position = 0;
+
+        for &(offset, ref header, len) in headers.iter() {
+            if offset > position {
+                try!(self.emit(&buffer[position..offset]));
+                position = offset;
+            }
+
+            try!(self.emit(&header[..len]));
+        }
+
+        if buffer.len() > position {
+            try!(self.emit(&buffer[position..]));
+        }
+
+        // keep the allocations around for the next unknown-length collection
+        buffer.clear();
+        headers.clear();
+        self.buffer = buffer;
+        self.headers = headers;
+
+        Ok(())
+    }
+
+    // write the headers into the buffer where they belong, working from the
+    // back so that each byte after them is moved once, however deep the
+    // collections were nested
+    fn splice_headers(&mut self) {
+        let total = self.headers.iter().fold(0, |total, &(_, _, len)| total + len);
+        let mut end = self.buffer.len();
+        let mut shift = total;
+        self.buffer.resize(end + total, 0);
+
+        for &(offset, ref header, len) in self.headers.iter().rev() {
+            self.buffer.copy_within(offset..end, offset + shift);
+            shift -= len;
+            self.buffer[offset + shift..offset + shift + len].copy_from_slice(&header[..len]);
+
+            #[cfg(test)]
+            {
+                self.copied += end - offset + len;
+            }
+
+            end = offset;
+        }
+
+        self.headers.clear();
+    }
+}
+
+/// An array being written by a Serializer, from begin_array.
+#[must_use = "the array is only finished by calling end"]
+pub struct ArrayBuilder<'a, F: 'a + FnMut(&[u8]) -> Result> {
+    ser: &'a mut Serializer<F>,
+    len: Option<usize>,
+    count: usize,
+    state: Option<(usize, usize)>
+}
+
+/// A map being written by a Serializer, from begin_map.
+#[must_use = "the map is only finished by calling end"]
+pub struct MapBuilder<'a, F: 'a + FnMut(&[u8]) -> Result> {
+    ser: &'a mut Serializer<F>,
+    len: Option<usize>,
+    count: usize,
+    state: Option<(usize, usize)>
+}
+
+impl<'a, F: FnMut(&[u8]) -> Result> ArrayBuilder<'a, F> {
+    /// Write the next element.
+    pub fn push<T>(&mut self, value: T) -> Result
+        where T: serde::Serialize {
+        try!(check_more(self.len, self.count));
+        self.count += 1;
+        serde::Serializer::serialize_seq_elt(self.ser, &mut self.state, value)
+    }
+
+    /// Finish the array.
+    pub fn end(self) -> Result {
+        try!(check_done(self.len, self.count));
+        serde::Serializer::serialize_seq_end(self.ser, self.state)
+    }
+}
+
+impl<'a, F: FnMut(&[u8]) -> Result> MapBuilder<'a, F> {
+    /// Write the next key and its value.
+    pub fn entry<K, V>(&mut self, key: K, value: V) -> Result
+        where K: serde::Serialize, V: serde::Serialize {
+        try!(check_more(self.len, self.count));
+        self.count += 1;
+        try!(serde::Serializer::serialize_map_key(self.ser, &mut self.state, key));
+        serde::Serializer::serialize_map_value(self.ser, &mut self.state, value)
+    }
+
+    /// Finish the map.
+    pub fn end(self) -> Result {
+        try!(check_done(self.len, self.count));
+        serde::Serializer::serialize_map_end(self.ser, self.state)
+    }
+}
+
+// a known length has already gone out in the header, so the items written
+// must match it
+fn check_more(len: Option<usize>, count: usize) -> Result {
+    match len {
+        Some(len) if count == len => Err(Error::new(Reason::BadLength, format!("More than the {} items given as the length", len))),
+        _ => Ok(())
+    }
+}
+
+fn check_done(len: Option<usize>, count: usize) -> Result {
+    match len {
+        Some(len) if count < len => Err(Error::new(Reason::BadLength, format!("Only {} of the {} items given as the length", count, len))),
+        _ => Ok(())
+    }
+}
+
+/// Serialize an iterator as an array, without collecting it into a Vec first.
+/// Each item is written out as it is produced.
+///
+/// An iterator whose size hint gives its exact length, like any
+/// `ExactSizeIterator`, has that length written up front and is streamed
+/// straight to the output. Any other is written like a sequence of unknown
+/// length, which a Serializer with an output function buffers until the end.
+/// Fails if an exact size hint turns out to be wrong.
+pub fn serialize_seq_from_iter<S, I>(serializer: &mut S, iter: I) -> result::Result<(), S::Error>
+    where S: serde::Serializer, I: IntoIterator, I::Item: serde::Serialize {
+    let iter = iter.into_iter();
+    let len = match iter.size_hint() {
+        (lower, Some(upper)) if lower == upper => Some(lower),
+        _ => None
+    };
+
+    let mut state = try!(serializer.serialize_seq(len));
+    let mut count = 0;
+
+    for item in iter {
+        if Some(count) == len {
+            return Err(serde::ser::Error::invalid_value("More items than the iterator's size hint"));
+        }
+
+        try!(serializer.serialize_seq_elt(&mut state, item));
+        count += 1;
+    }
+
+    match len {
+        Some(len) if count != len => Err(serde::ser::Error::invalid_value("Fewer items than the iterator's size hint")),
+        _ => serializer.serialize_seq_end(state)
+    }
+}
+
+/// Serialize a map of known length from an iterator of pairs, such as borrowed
+/// `(&K, &V)`, without collecting it into an owned map first. Each pair is
+/// written out as it is produced. Fails if the iterator does not yield exactly
+/// len pairs.
+pub fn serialize_map_from_iter<S, I, K, V>(serializer: &mut S, len: usize, iter: I) -> result::Result<(), S::Error>
+    where S: serde::Serializer, I: IntoIterator<Item = (K, V)>, K: serde::Serialize, V: serde::Serialize {
+    let mut state = try!(serializer.serialize_map(Some(len)));
+    let mut count = 0;
+
+    for (key, value) in iter {
+        if count == len {
+            return Err(serde::ser::Error::invalid_value("More map entries than expected"));
+        }
+
+        try!(serializer.serialize_map_key(&mut state, key));
+        try!(serializer.serialize_map_value(&mut state, value));
+        count += 1;
+    }
+
+    if count != len {
+        return Err(serde::ser::Error::invalid_value("Fewer map entries than expected"));
+    }
+
+    serializer.serialize_map_end(state)
+}
+
+fn discard(_: &[u8]) -> Result {
+    Ok(())
+}
+
+impl Serializer<fn(&[u8]) -> Result> {
+    /// Create a Serializer that writes into a Vec of its own.
+    pub(crate) fn to_vec(config: SerializerConfig) -> Serializer<fn(&[u8]) -> Result> {
+        let mut ser = Serializer::with_config(discard as fn(&[u8]) -> Result, config);
+        ser.direct = true;
+        ser
+    }
+
+    /// Take the output of a Serializer created with to_vec.
+    pub(crate) fn into_vec(self) -> Vec<u8> {
+        self.buffer
     }
 }
 
 impl<F: FnMut(&[u8]) -> Result> serde::Serializer for Serializer<F> {
     type Error = Error;
 
-    type SeqState = Option<(usize, Vec<u8>)>;
+    type SeqState = Option<(usize, usize)>;
     type TupleState = Self::SeqState;
     type TupleStructState = Self::SeqState;
     type TupleVariantState = Self::TupleState;
@@ -48,45 +621,41 @@ impl<F: FnMut(&[u8]) -> Result> serde::Serializer for Serializer<F> {
 
     fn serialize_bool(&mut self, v: bool) -> Result {
         if v {
-            self.output(&[TRUE])
+            self.output_value(&[TRUE])
         } else {
-            self.output(&[FALSE])
+            self.output_value(&[FALSE])
         }
     }
 
     fn serialize_i64(&mut self, value: i64) -> Result {
-        if value >= FIXINT_MIN as i64 && value <= FIXINT_MAX as i64 {
-            let mut buf = [0; U16_BYTES];
-            LittleEndian::write_i16(&mut buf, value as i16);
-            self.output(&buf[..1])
-        } else if value >= i8::min_value() as i64 && value <= i8::max_value() as i64 {
-            let mut buf = [0; U16_BYTES];
-            LittleEndian::write_i16(&mut buf, value as i16);
-            self.output(&[INT8, buf[0]])
-        } else if value >= 0 && value <= u8::max_value() as i64 {
-            let mut buf = [0; U16_BYTES];
-            LittleEndian::write_i16(&mut buf, value as i16);
-            self.output(&[UINT8, buf[0]])
-        } else if value >= i16::min_value() as i64 && value <= i16::max_value() as i64 {
+        if self.take_fixed() {
+            let mut buf = [INT64; U64_BYTES + 1];
+            buf[1..].copy_from_slice(&value.to_be_bytes());
+            return self.output_value(&buf);
+        }
+
+        // a value that isn't negative is written the same whatever type it
+        // came from, so it takes the unsigned ladder
+        if value >= 0 {
+            return self.serialize_u64(value as u64);
+        }
+
+        if value >= FIXINT_MIN as i64 {
+            self.output_value(&[value as u8])
+        } else if value >= i8::min_value() as i64 {
+            self.output_value(&[INT8, value as u8])
+        } else if value >= i16::min_value() as i64 {
             let mut buf = [INT16; U16_BYTES + 1];
-            BigEndian::write_i16(&mut buf[1..], value as i16);
-            self.output(&buf)
-        } else if value >= 0 && value <= u16::max_value() as i64 {
-            let mut buf = [UINT16; U16_BYTES + 1];
-            BigEndian::write_u16(&mut buf[1..], value as u16);
-            self.output(&buf)
-        } else if value >= i32::min_value() as i64 && value <= i32::max_value() as i64 {
+            buf[1..].copy_from_slice(&(value as i16).to_be_bytes());
+            self.output_value(&buf)
+        } else if value >= i32::min_value() as i64 {
             let mut buf = [INT32; U32_BYTES + 1];
-            BigEndian::write_i32(&mut buf[1..], value as i32);
-            self.output(&buf)
-        } else if value >= 0 && value <= u32::max_value() as i64 {
-            let mut buf = [UINT32; U16_BYTES + 1];
-            BigEndian::write_u32(&mut buf[1..], value as u32);
-            self.output(&buf)
+            buf[1..].copy_from_slice(&(value as i32).to_be_bytes());
+            self.output_value(&buf)
         } else {
             let mut buf = [INT64; U64_BYTES + 1];
-            BigEndian::write_i64(&mut buf[1..], value);
-            self.output(&buf)
+            buf[1..].copy_from_slice(&value.to_be_bytes());
+            self.output_value(&buf)
         }
     }
 
@@ -95,34 +664,56 @@ impl<F: FnMut(&[u8]) -> Result> serde::Serializer for Serializer<F> {
     }
 
     fn serialize_i8(&mut self, value: i8) -> Result {
+        if self.take_fixed() {
+            return self.output_value(&[INT8, value as u8]);
+        }
+
         self.serialize_i64(value as i64)
     }
 
     fn serialize_i16(&mut self, value: i16) -> Result {
+        if self.take_fixed() {
+            let mut buf = [INT16; U16_BYTES + 1];
+            buf[1..].copy_from_slice(&value.to_be_bytes());
+            return self.output_value(&buf);
+        }
+
         self.serialize_i64(value as i64)
     }
 
     fn serialize_i32(&mut self, value: i32) -> Result {
+        if self.take_fixed() {
+            let mut buf = [INT32; U32_BYTES + 1];
+            buf[1..].copy_from_slice(&value.to_be_bytes());
+            return self.output_value(&buf);
+        }
+
         self.serialize_i64(value as i64)
     }
 
     fn serialize_u64(&mut self, value: u64) -> Result {
+        if self.take_fixed() {
+            let mut buf = [UINT64; U64_BYTES + 1];
+            buf[1..].copy_from_slice(&value.to_be_bytes());
+            return self.output_value(&buf);
+        }
+
         if value <= FIXINT_MAX as u64 {
-            self.output(&[value as u8])
+            self.output_value(&[value as u8])
         } else if value <= u8::max_value() as u64 {
-            self.output(&[UINT8, value as u8])
+            self.output_value(&[UINT8, value as u8])
         } else if value <= u16::max_value() as u64 {
             let mut buf = [UINT16; U16_BYTES + 1];
-            BigEndian::write_u16(&mut buf[1..], value as u16);
-            self.output(&buf)
+            buf[1..].copy_from_slice(&(value as u16).to_be_bytes());
+            self.output_value(&buf)
         } else if value <= u32::max_value() as u64 {
             let mut buf = [UINT32; U32_BYTES + 1];
-            BigEndian::write_u32(&mut buf[1..], value as u32);
-            self.output(&buf)
+            buf[1..].copy_from_slice(&(value as u32).to_be_bytes());
+            self.output_value(&buf)
         } else {
             let mut buf = [UINT64; U64_BYTES + 1];
-            BigEndian::write_u64(&mut buf[1..], value);
-            self.output(&buf)
+            buf[1..].copy_from_slice(&value.to_be_bytes());
+            self.output_value(&buf)
         }
     }
 
@@ -131,45 +722,52 @@ impl<F: FnMut(&[u8]) -> Result> serde::Serializer for Serializer<F> {
     }
 
     fn serialize_u8(&mut self, value: u8) -> Result {
+        if self.take_fixed() {
+            return self.output_value(&[UINT8, value]);
+        }
+
         self.serialize_u64(value as u64)
     }
 
     fn serialize_u16(&mut self, value: u16) -> Result {
+        if self.take_fixed() {
+            let mut buf = [UINT16; U16_BYTES + 1];
+            buf[1..].copy_from_slice(&value.to_be_bytes());
+            return self.output_value(&buf);
+        }
+
         self.serialize_u64(value as u64)
     }
 
     fn serialize_u32(&mut self, value: u32) -> Result {
+        if self.take_fixed() {
+            let mut buf = [UINT32; U32_BYTES + 1];
+            buf[1..].copy_from_slice(&value.to_be_bytes());
+            return self.output_value(&buf);
+        }
+
         self.serialize_u64(value as u64)
     }
 
     fn serialize_f32(&mut self, value: f32) -> Result {
+        if self.config.always_f64 {
+            return self.serialize_f64(value as f64);
+        }
+
         let mut buf = [FLOAT32; U32_BYTES + 1];
-        BigEndian::write_f32(&mut buf[1..], value);
-        self.output(&buf)
+        buf[1..].copy_from_slice(&value.to_be_bytes());
+        self.output_value(&buf)
     }
 
     fn serialize_f64(&mut self, value: f64) -> Result {
         let mut buf = [FLOAT64; U64_BYTES + 1];
-        BigEndian::write_f64(&mut buf[1..], value);
-        self.output(&buf)
+        buf[1..].copy_from_slice(&value.to_be_bytes());
+        self.output_value(&buf)
     }
 
     fn serialize_str(&mut self, value: &str) -> Result {
-        if value.len() <= MAX_FIXSTR {
-            try!(self.output(&[value.len() as u8 | FIXSTR_MASK]));
-        } else if value.len() <= MAX_STR8 {
-            try!(self.output(&[STR8, value.len() as u8]));
-        } else if value.len() <= MAX_STR16 {
-            let mut buf = [STR16; U16_BYTES + 1];
-            BigEndian::write_u16(&mut buf[1..], value.len() as u16);
-            try!(self.output(&buf));
-        } else if value.len() <= MAX_STR32 {
-            let mut buf = [STR32; U32_BYTES + 1];
-            BigEndian::write_u32(&mut buf[1..], value.len() as u32);
-            try!(self.output(&buf));
-        } else {
-            return Err(Error::simple(Reason::TooBig));
-        }
+        let (header, len) = try!(str_header(value.len()));
+        try!(self.output_value(&header[..len]));
 
         self.output(value.as_bytes())
     }
@@ -182,33 +780,74 @@ impl<F: FnMut(&[u8]) -> Result> serde::Serializer for Serializer<F> {
     }
 
     fn serialize_unit(&mut self) -> Result {
-        self.output(&[NIL])
+        let marker = self.config.unit_encoding.marker();
+        self.output_value(&[marker])
     }
 
     fn serialize_unit_struct(&mut self, _: &'static str) -> Result {
         self.serialize_unit()
     }
 
-    fn serialize_unit_variant(&mut self, _: &'static str, index: usize, _: &'static str) -> Result {
+    fn serialize_unit_variant(&mut self, _: &'static str, index: usize, variant: &'static str) -> Result {
+        if self.config.enum_encoding == EnumEncoding::ExternallyTagged {
+            return self.serialize_str(variant);
+        }
+
+        if self.config.enum_encoding == EnumEncoding::Rmp {
+            try!(self.write_array_len(2));
+            try!(self.serialize_usize(index));
+            return self.write_array_len(0);
+        }
+
         self.serialize_usize(index)
     }
 
     fn serialize_newtype_struct<T>(&mut self, name: &'static str, value: T) -> Result
         where T: serde::Serialize {
+        if name == ::raw::TOKEN {
+            // the value is a Bytes holding an already encoded value
+            self.raw = true;
+            let result = value.serialize(self);
+            self.raw = false;
+            return result;
+        }
+
+        if name == ::fixed::TOKEN {
+            // the value is an integer, to be written at its full width
+            self.fixed = true;
+            let result = value.serialize(self);
+            self.fixed = false;
+            return result;
+        }
+
         let mut state = try!(self.serialize_tuple_struct(name, 1));
         try!(self.serialize_tuple_struct_elt(&mut state, value));
         self.serialize_tuple_struct_end(state)
     }
 
-    fn serialize_newtype_variant<T>(&mut self, name: &'static str, variant_index: usize, variant: &'static str, value: T) -> Result
+    fn serialize_newtype_variant<T>(&mut self, _: &'static str, variant_index: usize, variant: &'static str, value: T) -> Result
         where T: serde::Serialize {
-        let mut state = try!(self.serialize_tuple_variant(name, variant_index, variant, 1));
-        try!(self.serialize_tuple_variant_elt(&mut state, value));
-        self.serialize_tuple_variant_end(state)
+        if self.config.enum_encoding == EnumEncoding::ExternallyTagged {
+            try!(self.write_map_len(1));
+            try!(self.serialize_str(variant));
+            return value.serialize(self);
+        }
+
+        if self.config.enum_encoding == EnumEncoding::Rmp {
+            try!(self.write_array_len(2));
+            try!(self.serialize_usize(variant_index));
+            try!(self.write_array_len(1));
+            return value.serialize(self);
+        }
+
+        // the value goes right after the index, without an array of its own
+        try!(self.write_array_len(2));
+        try!(self.serialize_usize(variant_index));
+        value.serialize(self)
     }
 
     fn serialize_none(&mut self) -> Result {
-        self.serialize_unit()
+        self.output_value(&[NIL])
     }
 
     fn serialize_some<V>(&mut self, value: V) -> Result
@@ -219,25 +858,12 @@ impl<F: FnMut(&[u8]) -> Result> serde::Serializer for Serializer<F> {
     fn serialize_seq(&mut self, len: Option<usize>) -> result::Result<Self::SeqState, Error> {
         if let Some(size) = len {
             // output the size now
-
-            if size <= MAX_FIXARRAY {
-                try!(self.output(&[size as u8 | FIXARRAY_MASK]));
-            } else if size <= MAX_ARRAY16 {
-                let mut buf = [ARRAY16; U16_BYTES + 1];
-                BigEndian::write_u16(&mut buf[1..], size as u16);
-                try!(self.output(&buf));
-            } else if size <= MAX_ARRAY32 {
-                let mut buf = [ARRAY32; U32_BYTES + 1];
-                BigEndian::write_u32(&mut buf[1..], size as u32);
-                try!(self.output(&buf));
-            } else {
-                return Err(Error::simple(Reason::TooBig));
-            }
+            try!(self.write_array_len(size));
 
             // No state needed
             Ok(None)
         } else {
-            Ok(Some((0, vec![])))
+            Ok(Some(self.begin_unsized()))
         }
     }
 
@@ -247,37 +873,18 @@ impl<F: FnMut(&[u8]) -> Result> serde::Serializer for Serializer<F> {
 
     fn serialize_seq_elt<T>(&mut self, state: &mut Self::SeqState, value: T) -> Result
         where T: serde::Serialize {
-        if let &mut Some((ref mut size, ref mut buffer)) = state {
-            let mut target = Serializer::new(move |bytes| {
-                buffer.extend_from_slice(bytes);
-                Ok(())
-            });
-
+        if let &mut Some((ref mut size, _)) = state {
             *size += 1;
-
-            value.serialize(&mut target)
-        } else {
-            value.serialize(self)
         }
+
+        value.serialize(self)
     }
 
     fn serialize_seq_end(&mut self, state: Self::SeqState) -> Result {
-        if let Some((size, buffer)) = state {
-            if size <= MAX_FIXARRAY {
-                try!(self.output(&[size as u8 | FIXARRAY_MASK]));
-            } else if size <= MAX_ARRAY16 {
-                let mut buf = [ARRAY16; U16_BYTES + 1];
-                BigEndian::write_u16(&mut buf[1..], size as u16);
-                try!(self.output(&buf));
-            } else if size <= MAX_ARRAY32 {
-                let mut buf = [ARRAY32; U32_BYTES + 1];
-                BigEndian::write_u32(&mut buf[1..], size as u32);
-                try!(self.output(&buf));
-            } else {
-                return Err(Error::simple(Reason::TooBig));
-            }
-
-            self.output(buffer.as_slice())
+        if let Some((size, slot)) = state {
+            let header = try!(array_header(size));
+            self.owed = self.owed.wrapping_add(size);
+            self.end_unsized(slot, header)
         } else {
             Ok(())
         }
@@ -309,7 +916,19 @@ impl<F: FnMut(&[u8]) -> Result> serde::Serializer for Serializer<F> {
         self.serialize_tuple_end(state)
     }
 
-    fn serialize_tuple_variant(&mut self, _: &'static str, index: usize, _: &'static str, len: usize) -> result::Result<Self::TupleVariantState, Error> {
+    fn serialize_tuple_variant(&mut self, _: &'static str, index: usize, variant: &'static str, len: usize) -> result::Result<Self::TupleVariantState, Error> {
+        if self.config.enum_encoding == EnumEncoding::ExternallyTagged {
+            try!(self.write_map_len(1));
+            try!(self.serialize_str(variant));
+            return self.serialize_tuple(len);
+        }
+
+        if self.config.enum_encoding == EnumEncoding::Rmp {
+            try!(self.write_array_len(2));
+            try!(self.serialize_usize(index));
+            return self.serialize_tuple(len);
+        }
+
         let mut state = try!(self.serialize_tuple(len + 1));
         // serialize the variant index as an extra element at the front
         try!(self.serialize_tuple_elt(&mut state, index));
@@ -328,64 +947,79 @@ impl<F: FnMut(&[u8]) -> Result> serde::Serializer for Serializer<F> {
 
     fn serialize_map(&mut self, len: Option<usize>) -> result::Result<Self::MapState, Error> {
         if let Some(size) = len {
-            if size <= MAX_FIXMAP {
-                try!(self.output(&[size as u8 | FIXMAP_MASK]));
-            } else if size <= MAX_MAP16 {
-                let mut buf = [MAP16; U16_BYTES + 1];
-                BigEndian::write_u16(&mut buf[1..], size as u16);
-                try!(self.output(&buf));
-            } else if size <= MAX_MAP32 {
-                let mut buf = [MAP32; U32_BYTES + 1];
-                BigEndian::write_u32(&mut buf[1..], size as u32);
-                try!(self.output(&buf));
-            } else {
-                return Err(Error::simple(Reason::TooBig));
-            }
+            try!(self.write_map_len(size));
 
             Ok(None)
         } else {
-            Ok(Some((0, vec![])))
+            Ok(Some(self.begin_unsized()))
         }
     }
 
     fn serialize_map_key<T>(&mut self, state: &mut Self::MapState, key: T) -> Result
         where T: serde::Serialize {
-        self.serialize_seq_elt(state, key)
+        // maps count entries, not keys and values
+        if let &mut Some((ref mut size, _)) = state {
+            *size += 1;
+        }
+
+        key.serialize(self)
     }
 
-    fn serialize_map_value<T>(&mut self, state: &mut Self::MapState, value: T) -> Result
+    fn serialize_map_value<T>(&mut self, _: &mut Self::MapState, value: T) -> Result
         where T: serde::Serialize {
-        self.serialize_seq_elt(state, value)
+        let (values, owed) = (self.values, self.owed);
+        try!(value.serialize(self));
+
+        // whatever a value holds is owed by its own header, so one more value
+        // has to have started than were owed
+        let written = self.values.wrapping_sub(values).wrapping_sub(self.owed.wrapping_sub(owed));
+        debug_assert!(written == 1, "A map value was written as {} values", written);
+        Ok(())
     }
 
     fn serialize_map_end(&mut self, state: Self::MapState) -> Result {
-        if let Some((size, buffer)) = state {
-            if size <= MAX_FIXMAP {
-                try!(self.output(&[size as u8 | FIXMAP_MASK]));
-            } else if size <= MAX_MAP16 {
-                let mut buf = [MAP16; U16_BYTES + 1];
-                BigEndian::write_u16(&mut buf[1..], size as u16);
-                try!(self.output(&buf));
-            } else if size <= MAX_MAP32 {
-                let mut buf = [MAP32; U32_BYTES + 1];
-                BigEndian::write_u32(&mut buf[1..], size as u32);
-                try!(self.output(&buf));
-            } else {
-                return Err(Error::simple(Reason::TooBig));
-            }
-
-            self.output(buffer.as_slice())
+        if let Some((size, slot)) = state {
+            let header = try!(map_header(size));
+            self.owed = self.owed.wrapping_add(size.wrapping_mul(2));
+            self.end_unsized(slot, header)
         } else {
             Ok(())
         }
     }
 
     fn serialize_struct(&mut self, _: &'static str, len: usize) -> result::Result<Self::MapState, Error> {
+        if self.config.compact_structs {
+            return self.serialize_map(None);
+        }
+
         self.serialize_map(Some(len))
     }
 
     fn serialize_struct_elt<V>(&mut self, state: &mut Self::MapState, key: &'static str, value: V) -> Result
         where V: serde::Serialize {
+        if let &mut Some((ref mut size, _)) = state {
+            if self.config.compact_structs {
+                // everything is going into the buffer, so a field that turns
+                // out to be nil can be cut back out of it
+                let start = self.buffer.len();
+                try!(key.serialize(self));
+                let (value_start, headers) = (self.buffer.len(), self.headers.len());
+                try!(value.serialize(self));
+
+                // a collection of unknown length holding just a nil puts only
+                // the nil in the buffer, and its header aside
+                if self.headers.len() == headers && self.buffer.len() == value_start + 1 &&
+                    self.buffer[value_start] == NIL {
+                    self.buffer.truncate(start);
+                    self.values -= 2;
+                } else {
+                    *size += 1;
+                }
+
+                return Ok(());
+            }
+        }
+
         try!(self.serialize_map_key(state, key));
         self.serialize_map_value(state, value)
     }
@@ -394,60 +1028,260 @@ impl<F: FnMut(&[u8]) -> Result> serde::Serializer for Serializer<F> {
         self.serialize_map_end(state)
     }
 
-    fn serialize_struct_variant(&mut self, name: &'static str, index: usize, _: &'static str, len: usize) -> result::Result<Self::MapState, Error> {
-        // encode a struct variant as a tuple of the variant index plus the struct itself
-        let mut state = try!(self.serialize_tuple(2));
-
-        // state in this case should statically be None, so only check in debug builds
-        debug_assert!(state.is_none(), "Tuple state was not None");
-
-        // that means we can just throw recreate it later
+    fn serialize_struct_variant(&mut self, name: &'static str, index: usize, variant: &'static str, len: usize) -> result::Result<Self::MapState, Error> {
+        if self.config.enum_encoding == EnumEncoding::ExternallyTagged {
+            // a map of one entry, from the variant's name to the struct
+            try!(self.write_map_len(1));
+            try!(self.serialize_str(variant));
+            return self.serialize_struct(name, len);
+        }
 
-        try!(self.serialize_tuple_elt(&mut state, index));
+        if self.config.enum_encoding == EnumEncoding::Rmp {
+            // the fields go in an array, and their names are left out
+            try!(self.write_array_len(2));
+            try!(self.serialize_usize(index));
+            try!(self.write_array_len(len));
+            return Ok(None);
+        }
 
-        // messagepack uses pascal-style arrays for objects, so we can just keep encoding things
-        // and get the same result as if we called serialize_elt. This is a bit of a hack, though.
+        // encode a struct variant as a two element array of the variant index
+        // plus the struct itself. The array's header is complete up front, so
+        // only the struct's state needs carrying to the end, and struct
+        // variants nest like any other value.
+        try!(self.write_array_len(2));
+        try!(self.serialize_usize(index));
 
         self.serialize_struct(name, len)
     }
 
     fn serialize_struct_variant_elt<V>(&mut self, state: &mut Self::MapState, key: &'static str, value: V) -> Result
         where V: serde::Serialize {
+        if self.config.enum_encoding == EnumEncoding::Rmp {
+            return value.serialize(self);
+        }
+
         self.serialize_struct_elt(state, key, value)
     }
 
     fn serialize_struct_variant_end(&mut self, state: Self::MapState) -> Result {
-        try!(self.serialize_struct_end(state));
+        if self.config.enum_encoding == EnumEncoding::Rmp {
+            return Ok(());
+        }
 
-        // end the tuple here as well, re-creating the state
-        // we asserted earlier that the state in this case should be None, since this
-        // is a fixed-sized sequence
-        self.serialize_tuple_end(None)
+        self.serialize_struct_end(state)
     }
 
     fn serialize_bytes(&mut self, value: &[u8]) -> Result {
-        if value.len() <= MAX_BIN8 {
-            try!(self.output(&[BIN8, value.len() as u8]));
-        } else if value.len() <= MAX_BIN16 {
-            let mut buf = [BIN16; U16_BYTES + 1];
-            BigEndian::write_u16(&mut buf[1..], value.len() as u16);
-            try!(self.output(&buf));
-        } else if value.len() <= MAX_BIN32 {
-            let mut buf = [BIN32; U32_BYTES + 1];
-            BigEndian::write_u32(&mut buf[1..], value.len() as u32);
-            try!(self.output(&buf));
-        } else {
-            return Err(Error::simple(Reason::TooBig));
+        if self.raw {
+            self.raw = false;
+            return self.output_value(value);
         }
 
+        let (header, len) = try!(bin_header(value.len()));
+        try!(self.output_value(&header[..len]));
+
         self.output(value)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use collections::{Vec, String};
-    use collections::btree_map::BTreeMap;
+    use std::cell::RefCell;
+
+    use alloc::vec::Vec;
+    use alloc::string::String;
+    use alloc::collections::btree_map::BTreeMap;
+
+    use serde::{Serialize, Serializer};
+
+    use error::Reason;
+    use test_types::{Sparse, Patch, Listing, Wrapper};
+    use super::{SerializerConfig, EnumEncoding, serialize_map_from_iter, str_header, bin_header, map_header};
+
+    fn write_header<F>(map: &mut super::MapBuilder<F>) -> super::Result
+        where F: FnMut(&[u8]) -> super::Result {
+        try!(map.entry("version", 3));
+        map.entry("kind", "list")
+    }
+
+    fn write_items<F>(map: &mut super::MapBuilder<F>, items: &[u32]) -> super::Result
+        where F: FnMut(&[u8]) -> super::Result {
+        map.entry("items", items)
+    }
+
+    // what one call to a header helper writes
+    fn header<W>(write: W) -> Vec<u8>
+        where W: FnOnce(&mut ::Serializer<&mut dyn FnMut(&[u8]) -> super::Result>) -> super::Result {
+        let mut bytes = vec![];
+
+        {
+            let mut output = |buf: &[u8]| {
+                bytes.extend_from_slice(buf);
+                Ok(())
+            };
+            let mut ser = ::Serializer::new(&mut output as &mut dyn FnMut(&[u8]) -> super::Result);
+            write(&mut ser).unwrap();
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn write_len_test() {
+        assert_eq!(header(|ser| ser.write_array_len(0)), &[0x90]);
+        assert_eq!(header(|ser| ser.write_array_len(15)), &[0x9f]);
+        assert_eq!(header(|ser| ser.write_array_len(16)), &[0xdc, 0x00, 0x10]);
+        assert_eq!(header(|ser| ser.write_array_len(65535)), &[0xdc, 0xff, 0xff]);
+        assert_eq!(header(|ser| ser.write_array_len(65536)), &[0xdd, 0x00, 0x01, 0x00, 0x00]);
+
+        assert_eq!(header(|ser| ser.write_map_len(15)), &[0x8f]);
+        assert_eq!(header(|ser| ser.write_map_len(16)), &[0xde, 0x00, 0x10]);
+        assert_eq!(header(|ser| ser.write_map_len(65536)), &[0xdf, 0x00, 0x01, 0x00, 0x00]);
+
+        assert_eq!(header(|ser| ser.write_str_len(31)), &[0xbf]);
+        assert_eq!(header(|ser| ser.write_str_len(32)), &[0xd9, 0x20]);
+        assert_eq!(header(|ser| ser.write_str_len(256)), &[0xda, 0x01, 0x00]);
+        assert_eq!(header(|ser| ser.write_str_len(65536)), &[0xdb, 0x00, 0x01, 0x00, 0x00]);
+
+        assert_eq!(header(|ser| ser.write_bin_len(0)), &[0xc4, 0x00]);
+        assert_eq!(header(|ser| ser.write_bin_len(255)), &[0xc4, 0xff]);
+        assert_eq!(header(|ser| ser.write_bin_len(256)), &[0xc5, 0x01, 0x00]);
+        assert_eq!(header(|ser| ser.write_bin_len(65536)), &[0xc6, 0x00, 0x01, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn write_raw_test() {
+        // ["hi", bin [1, 2], 3], with the bodies written by hand
+        let bytes = header(|ser| {
+            try!(ser.write_array_len(3));
+            try!(ser.write_str_len(2));
+            try!(ser.write_raw(b"hi"));
+            try!(ser.write_bin_len(2));
+            try!(ser.write_raw(&[1, 2]));
+            3u8.serialize(ser)
+        });
+
+        assert_eq!(bytes, &[0x93, 0xa2, 0x68, 0x69, 0xc4, 0x02, 0x01, 0x02, 0x03]);
+
+        let value: (String, ::ByteBuf, u8) = ::from_bytes(&bytes).unwrap();
+        assert_eq!(value, ("hi".into(), ::ByteBuf::from(vec![1, 2]), 3));
+    }
+
+    #[test]
+    fn builder_test() {
+        for &len in &[Some(3), None] {
+            let mut bytes = vec![];
+
+            {
+                let mut ser = ::Serializer::new(|buf: &[u8]| {
+                    bytes.extend_from_slice(buf);
+                    Ok(())
+                });
+
+                let mut map = ser.begin_map(len).unwrap();
+                write_header(&mut map).unwrap();
+                write_items(&mut map, &[1, 2, 3]).unwrap();
+                map.end().unwrap();
+            }
+
+            assert_eq!(bytes, &[0x83,
+                                0xa7, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x03,
+                                0xa4, 0x6b, 0x69, 0x6e, 0x64, 0xa4, 0x6c, 0x69, 0x73, 0x74,
+                                0xa5, 0x69, 0x74, 0x65, 0x6d, 0x73, 0x93, 0x01, 0x02, 0x03]);
+
+            let listing: Listing = ::from_bytes(&bytes).unwrap();
+            assert_eq!(listing, Listing { version: 3, kind: "list".into(), items: vec![1, 2, 3] });
+        }
+
+        let mut bytes = vec![];
+
+        {
+            let mut ser = ::Serializer::new(|buf: &[u8]| {
+                bytes.extend_from_slice(buf);
+                Ok(())
+            });
+
+            let mut array = ser.begin_array(None).unwrap();
+            array.push("a").unwrap();
+            array.push(2).unwrap();
+            array.end().unwrap();
+        }
+
+        let value: (String, u8) = ::from_bytes(&bytes).unwrap();
+        assert_eq!(value, (format!("a"), 2));
+    }
+
+    #[test]
+    fn builder_length_test() {
+        let mut ser = ::Serializer::new(|_: &[u8]| Ok(()));
+
+        let mut map = ser.begin_map(Some(1)).unwrap();
+        map.entry("a", 1).unwrap();
+        assert_eq!(map.entry("b", 2).unwrap_err().reason(), Reason::BadLength);
+
+        let mut array = ser.begin_array(Some(2)).unwrap();
+        array.push(1).unwrap();
+        assert_eq!(array.end().unwrap_err().reason(), Reason::BadLength);
+    }
+
+    // serializes without telling the serializer its length up front
+    struct Unsized<T>(Vec<T>);
+
+    struct UnsizedMap<K, V>(BTreeMap<K, V>);
+
+    impl<T: Serialize> Serialize for Unsized<T> {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+            where S: Serializer {
+            let mut state = try!(serializer.serialize_seq(None));
+            for item in self.0.iter() {
+                try!(serializer.serialize_seq_elt(&mut state, item));
+            }
+            serializer.serialize_seq_end(state)
+        }
+    }
+
+    impl<K: Serialize + Ord, V: Serialize> Serialize for UnsizedMap<K, V> {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+            where S: Serializer {
+            let mut state = try!(serializer.serialize_map(None));
+            for (key, value) in self.0.iter() {
+                try!(serializer.serialize_map_key(&mut state, key));
+                try!(serializer.serialize_map_value(&mut state, value));
+            }
+            serializer.serialize_map_end(state)
+        }
+    }
+
+    // a key that can only be serialized by reference
+    struct Key(u8);
+
+    // a value computed as it is written, logging each time it is
+    struct Lazy<'a>(u32, &'a RefCell<Vec<u32>>);
+
+    impl<'a> Serialize for Lazy<'a> {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+            where S: Serializer {
+            self.1.borrow_mut().push(self.0);
+            serializer.serialize_u32(self.0 * 10)
+        }
+    }
+
+    // a map borrowed from a slice of pairs, with the length it claims to have
+    struct PairMap<'a>(&'a [(Key, &'a str)], usize);
+
+    impl Serialize for Key {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+            where S: Serializer {
+            serializer.serialize_u8(self.0)
+        }
+    }
+
+    impl<'a> Serialize for PairMap<'a> {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+            where S: Serializer {
+            serialize_map_from_iter(serializer, self.1, self.0.iter().map(|&(ref k, ref v)| (k, v)))
+        }
+    }
 
     #[test]
     fn positive_fixint_test() {
@@ -460,12 +1294,40 @@ mod test {
         assert_eq!(::to_bytes(v).unwrap(), &[0xfb]);
     }
 
+    #[test]
+    fn i64_min_test() {
+        let bytes = ::to_bytes(i64::min_value()).unwrap();
+        assert_eq!(bytes, &[0xd3, 0x80, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(::from_bytes::<i64>(&bytes).unwrap(), i64::min_value());
+
+        // one above still needs all 64 bits
+        assert_eq!(::to_bytes(i64::min_value() + 1).unwrap(), &[0xd3, 0x80, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(::to_bytes(i32::min_value()).unwrap(), &[0xd2, 0x80, 0, 0, 0]);
+    }
+
     #[test]
     fn uint8_test() {
         let v: u8 = 154;
         assert_eq!(::to_bytes(v).unwrap(), &[0xcc, 0x9a]);
     }
 
+    #[test]
+    fn float32_nan_test() {
+        use std::f32;
+        assert_eq!(::to_bytes(f32::NAN).unwrap(), &[0xca, 0x7f, 0xc0, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn float64_nan_test() {
+        use std::f64;
+        assert_eq!(::to_bytes(f64::NAN).unwrap(), &[0xcb, 0x7f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(::to_bytes(-f64::NAN).unwrap(), &[0xcb, 0xff, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+        // signaling NaNs are written bit for bit, not canonicalized
+        let signaling = f64::from_bits(0x7ff0_0000_0000_0001);
+        assert_eq!(::to_bytes(signaling).unwrap(), &[0xcb, 0x7f, 0xf0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]);
+    }
+
     #[test]
     fn fixstr_test() {
         let s: &str = "Hello World!";
@@ -498,11 +1360,312 @@ mod test {
         assert_eq!(::to_bytes(v).unwrap(), &[0xdc,
                                              0x00, 0x11,
                                              0xfb,  0x10,  0x65,  0xd0, 0xd3,  0xcc, 0xb8,
-                                             0x59,  0x3e,  0xd1, 0xff, 0x17,  0xd0, 0xdf,  0xd1, 0x01, 0x30,
+                                             0x59,  0x3e,  0xd1, 0xff, 0x17,  0xd0, 0xdf,  0xcd, 0x01, 0x30,
                                              0x4c, 0x5a, 0x17, 0x6c, 0x2d,
                                              0xfd, 0x02]);
     }
 
+    #[test]
+    fn cross_type_test() {
+        // the same value is written the same, whichever type it arrives as
+        let expected: &[u8] = &[0x64];
+        assert_eq!(::to_bytes(100i8).unwrap(), expected);
+        assert_eq!(::to_bytes(100i16).unwrap(), expected);
+        assert_eq!(::to_bytes(100i32).unwrap(), expected);
+        assert_eq!(::to_bytes(100i64).unwrap(), expected);
+        assert_eq!(::to_bytes(100isize).unwrap(), expected);
+        assert_eq!(::to_bytes(100u8).unwrap(), expected);
+        assert_eq!(::to_bytes(100u16).unwrap(), expected);
+        assert_eq!(::to_bytes(100u32).unwrap(), expected);
+        assert_eq!(::to_bytes(100u64).unwrap(), expected);
+        assert_eq!(::to_bytes(100usize).unwrap(), expected);
+
+        let expected: &[u8] = &[0xcc, 0xc8];
+        assert_eq!(::to_bytes(200i16).unwrap(), expected);
+        assert_eq!(::to_bytes(200i64).unwrap(), expected);
+        assert_eq!(::to_bytes(200u8).unwrap(), expected);
+        assert_eq!(::to_bytes(200u64).unwrap(), expected);
+
+        let expected: &[u8] = &[0xcd, 0x01, 0x2c];
+        assert_eq!(::to_bytes(300i16).unwrap(), expected);
+        assert_eq!(::to_bytes(300i32).unwrap(), expected);
+        assert_eq!(::to_bytes(300i64).unwrap(), expected);
+        assert_eq!(::to_bytes(300u16).unwrap(), expected);
+        assert_eq!(::to_bytes(300u64).unwrap(), expected);
+
+        let expected: &[u8] = &[0xce, 0x00, 0x01, 0x11, 0x70];
+        assert_eq!(::to_bytes(70000i32).unwrap(), expected);
+        assert_eq!(::to_bytes(70000i64).unwrap(), expected);
+        assert_eq!(::to_bytes(70000u32).unwrap(), expected);
+        assert_eq!(::to_bytes(70000u64).unwrap(), expected);
+
+        // past i32, which once overran a two byte buffer
+        let expected: &[u8] = &[0xce, 0xb2, 0xd0, 0x5e, 0x00];
+        assert_eq!(::to_bytes(3000000000i64).unwrap(), expected);
+        assert_eq!(::to_bytes(3000000000u32).unwrap(), expected);
+        assert_eq!(::to_bytes(3000000000u64).unwrap(), expected);
+
+        let expected: &[u8] = &[0xcf, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(::to_bytes(0x100000000i64).unwrap(), expected);
+        assert_eq!(::to_bytes(0x100000000u64).unwrap(), expected);
+    }
+
+    // serialize through an output closure, which splices in the headers of
+    // unknown-length collections as it writes them out, and through to_bytes,
+    // which splices them into its own buffer, and check that both agree
+    fn both<V: Serialize>(value: V) -> Vec<u8> {
+        both_with(value, SerializerConfig::new())
+    }
+
+    fn both_with<V: Serialize>(value: V, config: SerializerConfig) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        {
+            let mut ser = ::Serializer::with_config(|buf: &[u8]| {
+                bytes.extend_from_slice(buf);
+                Ok(())
+            }, config);
+
+            value.serialize(&mut ser).unwrap();
+        }
+
+        assert_eq!(::to_bytes_with_config(&value, config).unwrap(), bytes);
+
+        bytes
+    }
+
+    // the data only lives as long as this call
+    fn serialize_borrowed(count: u16) -> Vec<u8> {
+        let items: Vec<u16> = (0..count).collect();
+        let name = format!("{} items", count);
+
+        let inner = Wrapper { name: &name[..1], items: &items[..1], nested: None };
+        let outer = Wrapper { name: &name, items: &items, nested: Some(&inner) };
+
+        both(&outer)
+    }
+
+    #[test]
+    fn borrowed_test() {
+        assert_eq!(serialize_borrowed(2), &[0x83,
+                                           0xa4, 0x6e, 0x61, 0x6d, 0x65, 0xa7, 0x32, 0x20, 0x69, 0x74, 0x65, 0x6d, 0x73,
+                                           0xa5, 0x69, 0x74, 0x65, 0x6d, 0x73, 0x92, 0x00, 0x01,
+                                           0xa6, 0x6e, 0x65, 0x73, 0x74, 0x65, 0x64, 0x83,
+                                           0xa4, 0x6e, 0x61, 0x6d, 0x65, 0xa1, 0x32,
+                                           0xa5, 0x69, 0x74, 0x65, 0x6d, 0x73, 0x91, 0x00,
+                                           0xa6, 0x6e, 0x65, 0x73, 0x74, 0x65, 0x64, 0xc0]);
+    }
+
+    #[test]
+    fn sorted_map_test() {
+        // there is no canonical mode that sorts keys; map entries go out in the
+        // order they are given, so a BTreeMap's come out sorted already
+        let mut map = BTreeMap::new();
+        map.insert("b", 2);
+        map.insert("c", 3);
+        map.insert("a", 1);
+
+        let expected = [0x83, 0xa1, 0x61, 0x01, 0xa1, 0x62, 0x02, 0xa1, 0x63, 0x03];
+        assert_eq!(both(&map), &expected);
+        assert_eq!(both(UnsizedMap(map)), &expected);
+    }
+
+    #[test]
+    fn lazy_map_test() {
+        let log = RefCell::new(vec![]);
+        let mut map = BTreeMap::new();
+        map.insert("a", Lazy(1, &log));
+        map.insert("b", Lazy(2, &log));
+        map.insert("c", Lazy(3, &log));
+
+        let expected = [0x83, 0xa1, 0x61, 0x0a, 0xa1, 0x62, 0x14, 0xa1, 0x63, 0x1e];
+
+        assert_eq!(::to_bytes(&map).unwrap(), &expected);
+        assert_eq!(log.replace(vec![]), vec![1, 2, 3]);
+
+        // through an output function, where unknown lengths are buffered
+        let mut bytes = vec![];
+        let mut unsized_bytes = vec![];
+
+        {
+            let mut ser = ::Serializer::new(|buf: &[u8]| {
+                bytes.extend_from_slice(buf);
+                Ok(())
+            });
+            map.serialize(&mut ser).unwrap();
+        }
+        assert_eq!(log.replace(vec![]), vec![1, 2, 3]);
+
+        let map = UnsizedMap(map);
+        {
+            let mut ser = ::Serializer::new(|buf: &[u8]| {
+                unsized_bytes.extend_from_slice(buf);
+                Ok(())
+            });
+            map.serialize(&mut ser).unwrap();
+        }
+        assert_eq!(log.replace(vec![]), vec![1, 2, 3]);
+
+        assert_eq!(bytes, &expected);
+        assert_eq!(unsized_bytes, &expected);
+    }
+
+    // logs each item as the iterator produces it, and 0 for each piece of output
+    fn iter_log<I>(iter: I) -> (Vec<u8>, Vec<u32>)
+        where I: Iterator<Item = u32> {
+        let log = RefCell::new(vec![]);
+        let mut bytes = vec![];
+
+        {
+            let mut ser = ::Serializer::new(|buf: &[u8]| {
+                log.borrow_mut().push(0);
+                bytes.extend_from_slice(buf);
+                Ok(())
+            });
+
+            let items = iter.map(|n| {
+                log.borrow_mut().push(n);
+                n
+            });
+            super::serialize_seq_from_iter(&mut ser, items).unwrap();
+        }
+
+        (bytes, log.into_inner())
+    }
+
+    #[test]
+    fn seq_from_iter_test() {
+        // an exact size goes out as each item is produced
+        let (bytes, log) = iter_log(1..4);
+        assert_eq!(bytes, &[0x93, 0x01, 0x02, 0x03]);
+        assert_eq!(log, &[0, 1, 0, 2, 0, 3, 0]);
+
+        // filtered, the length isn't known until the end
+        let (bytes, log) = iter_log((1..7).filter(|n| n % 2 == 0));
+        assert_eq!(bytes, &[0x93, 0x02, 0x04, 0x06]);
+        assert_eq!(&log[..3], &[2, 4, 6]);
+        assert!(log[3..].iter().all(|&n| n == 0));
+
+        let mut ser = ::Serializer::to_vec(SerializerConfig::new());
+        super::serialize_seq_from_iter(&mut ser, (0..20u32).filter(|n| n % 3 == 0)).unwrap();
+        assert_eq!(ser.into_vec(), &[0x97, 0x00, 0x03, 0x06, 0x09, 0x0c, 0x0f, 0x12]);
+    }
+
+    // claims one more item than it has
+    struct Liar(u32);
+
+    impl Iterator for Liar {
+        type Item = u32;
+
+        fn next(&mut self) -> Option<u32> {
+            if self.0 == 0 {
+                None
+            } else {
+                self.0 -= 1;
+                Some(self.0)
+            }
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            (self.0 as usize + 1, Some(self.0 as usize + 1))
+        }
+    }
+
+    #[test]
+    fn seq_from_iter_length_test() {
+        let mut ser = ::Serializer::to_vec(SerializerConfig::new());
+        assert_eq!(super::serialize_seq_from_iter(&mut ser, Liar(2)).unwrap_err().reason(), Reason::BadValue);
+    }
+
+    #[test]
+    fn unsized_array_test() {
+        let v = Unsized(vec![5u8, 8, 20, 231]);
+        assert_eq!(both(v), &[0x94, 0x05, 0x08, 0x14, 0xcc, 0xe7]);
+
+        let v = Unsized((0..16).collect::<Vec<u8>>());
+        let bytes = both(v);
+        assert_eq!(&bytes[..3], &[0xdc, 0x00, 0x10]);
+        assert_eq!(bytes.len(), 3 + 16);
+
+        let v = Unsized((0..0x10000).map(|_| 0u8).collect::<Vec<u8>>());
+        let bytes = both(v);
+        assert_eq!(&bytes[..5], &[0xdd, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(bytes.len(), 5 + 0x10000);
+    }
+
+    #[test]
+    fn unsized_nested_test() {
+        let v = Unsized(vec![Unsized(vec![Unsized(vec![Unsized(vec![Unsized(vec![7u8])])])])]);
+        assert_eq!(both(v), &[0x91, 0x91, 0x91, 0x91, 0x91, 0x07]);
+
+        let v = Unsized(vec![Unsized(vec![1u8, 2]), Unsized(vec![]), Unsized(vec![3])]);
+        assert_eq!(both(v), &[0x93, 0x92, 0x01, 0x02, 0x90, 0x91, 0x03]);
+
+        // known-length collections inside unknown-length ones and vice versa
+        let v = Unsized(vec![vec![Unsized(vec![1u8])], vec![]]);
+        assert_eq!(both(v), &[0x92, 0x91, 0x91, 0x01, 0x90]);
+        let v = vec![Unsized(vec![vec![1u8, 2]])];
+        assert_eq!(both(v), &[0x91, 0x91, 0x92, 0x01, 0x02]);
+    }
+
+    // bytes held back by a Serializer writing through an output closure
+    fn copied<V: Serialize>(value: V) -> usize {
+        let mut ser = ::Serializer::new(|_: &[u8]| Ok(()));
+        value.serialize(&mut ser).unwrap();
+        ser.copied
+    }
+
+    // bytes written or moved by a Serializer writing into its own Vec
+    fn copied_to_vec<V: Serialize>(value: V) -> usize {
+        let mut ser = ::Serializer::to_vec(SerializerConfig::new());
+        value.serialize(&mut ser).unwrap();
+        ser.copied
+    }
+
+    #[test]
+    fn unsized_copies_test() {
+        let items: Vec<u8> = (0..1000).map(|n| (n % 100) as u8).collect();
+        let nested = Unsized(vec![Unsized(vec![Unsized(vec![Unsized(vec![Unsized(items.clone())])])])]);
+        assert_eq!(both(&nested).len(), 4 + 3 + 1000);
+
+        // each element is copied into the buffer once, however deep it is
+        assert_eq!(copied(Unsized(items)), 1000);
+        assert_eq!(copied(&nested), 1000);
+
+        // and moved once more when the headers are spliced in, along with
+        // writing the headers themselves
+        assert_eq!(copied_to_vec(Unsized(items.clone())), 1000 + 1000 + 3);
+        assert_eq!(copied_to_vec(&nested), 1000 + 1000 + 4 + 3);
+    }
+
+    #[test]
+    fn unsized_splice_test() {
+        // output before, between and after unknown-length collections, at
+        // several depths, comes out the same as through an output closure
+        let v = vec![Unsized(vec![]), Unsized(vec![Unsized(vec![1u8, 2]), Unsized(vec![])]), Unsized(vec![Unsized(vec![3])])];
+        assert_eq!(both(v), &[0x93, 0x90, 0x92, 0x92, 0x01, 0x02, 0x90, 0x91, 0x91, 0x03]);
+
+        let mut inner = BTreeMap::new();
+        inner.insert(1u8, Unsized((0..300).map(|n| n as u16).collect::<Vec<u16>>()));
+        let mut outer = BTreeMap::new();
+        outer.insert("x", UnsizedMap(inner));
+        let bytes = both(UnsizedMap(outer));
+        assert_eq!(&bytes[..8], &[0x81, 0xa1, 0x78, 0x81, 0x01, 0xdc, 0x01, 0x2c]);
+        assert_eq!(bytes.len(), 8 + 128 + 2 * 128 + 3 * 44);
+    }
+
+    #[test]
+    fn unsized_map_test() {
+        let mut map: BTreeMap<String, usize> = BTreeMap::new();
+        map.insert("one".into(), 1);
+        map.insert("two".into(), 2);
+        map.insert("three".into(), 3);
+        assert_eq!(both(UnsizedMap(map)), &[0x83,
+                                                           0xa3, 0x6f, 0x6e, 0x65,  0x01,
+                                                           0xa5, 0x74, 0x68, 0x72, 0x65, 0x65,  0x03,
+                                                           0xa3, 0x74, 0x77, 0x6f,  0x02]);
+    }
+
     #[test]
     fn fixmap_test() {
         let mut map: BTreeMap<String, usize> = BTreeMap::new();
@@ -514,4 +1677,256 @@ mod test {
                                                0xa5, 0x74, 0x68, 0x72, 0x65, 0x65,  0x03,
                                                0xa3, 0x74, 0x77, 0x6f,  0x02]);
     }
+
+    #[test]
+    fn compact_structs_test() {
+        let config = SerializerConfig { compact_structs: true, ..SerializerConfig::new() };
+        let value = Sparse { a: Some(1), b: None, c: Some(2), d: None, e: Some(3) };
+
+        assert_eq!(both_with(&value, config), &[0x83,
+                                                0xa1, 0x61, 0x01,
+                                                0xa1, 0x63, 0x02,
+                                                0xa1, 0x65, 0x03]);
+
+        // without the option, every field is there
+        assert_eq!(both(&value)[0], 0x85);
+
+        let decoded: Sparse = ::from_bytes(both_with(&value, config).as_slice()).unwrap();
+        assert_eq!(decoded, value);
+
+        let empty = Sparse { a: None, b: None, c: None, d: None, e: None };
+        assert_eq!(both_with(&empty, config), &[0x80]);
+
+        // nested in something of unknown length
+        assert_eq!(both_with(Unsized(vec![value]), config), &[0x91, 0x83,
+                                                              0xa1, 0x61, 0x01,
+                                                              0xa1, 0x63, 0x02,
+                                                              0xa1, 0x65, 0x03]);
+    }
+
+    // a struct with a field that holds nothing but a nil
+    struct NilList;
+
+    impl Serialize for NilList {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+            where S: Serializer {
+            let mut state = try!(serializer.serialize_struct("NilList", 2));
+            try!(serializer.serialize_struct_elt(&mut state, "a", Unsized(vec![None::<u8>])));
+            try!(serializer.serialize_struct_elt(&mut state, "b", None::<u8>));
+            serializer.serialize_struct_end(state)
+        }
+    }
+
+    #[test]
+    fn compact_nil_list_test() {
+        let config = SerializerConfig { compact_structs: true, ..SerializerConfig::new() };
+
+        // the list is kept, though it writes the same byte as a nil field
+        assert_eq!(both_with(NilList, config), &[0x81, 0xa1, 0x61, 0x91, 0xc0]);
+        assert_eq!(both_with(Unsized(vec![NilList, NilList]), config), &[0x92,
+                                                                         0x81, 0xa1, 0x61, 0x91, 0xc0,
+                                                                         0x81, 0xa1, 0x61, 0x91, 0xc0]);
+    }
+
+    #[test]
+    fn compact_struct_variant_test() {
+        let config = SerializerConfig { compact_structs: true, ..SerializerConfig::new() };
+
+        // [1, {"size": 4}], with name left out
+        let value = Patch::Set { name: None, size: Some(4) };
+        let bytes = both_with(&value, config);
+        assert_eq!(bytes, &[0x92, 0x01, 0x81, 0xa4, 0x73, 0x69, 0x7a, 0x65, 0x04]);
+        assert_eq!(::from_bytes::<Patch>(&bytes).unwrap(), value);
+
+        let value = Patch::Set { name: None, size: None };
+        assert_eq!(both_with(&value, config), &[0x92, 0x01, 0x80]);
+
+        // the same under the externally tagged encoding
+        let config = SerializerConfig { enum_encoding: EnumEncoding::ExternallyTagged, ..config };
+        assert_eq!(both_with(&value, config), &[0x81, 0xa3, 0x53, 0x65, 0x74, 0x80]);
+    }
+
+    // the sizes of the pieces the output function was given
+    fn chunks_with<V: Serialize>(value: V, config: SerializerConfig) -> (Vec<u8>, Vec<usize>) {
+        let mut bytes = vec![];
+        let mut sizes = vec![];
+
+        {
+            let mut ser = ::Serializer::with_config(|buf: &[u8]| {
+                bytes.extend_from_slice(buf);
+                sizes.push(buf.len());
+                Ok(())
+            }, config);
+
+            value.serialize(&mut ser).unwrap();
+        }
+
+        (bytes, sizes)
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn str32_limit_test() {
+        // too big to build in a test, so just the header
+        let (header, len) = str_header(0xffff_ffff).unwrap();
+        assert_eq!(&header[..len], &[0xdb, 0xff, 0xff, 0xff, 0xff]);
+
+        let error = str_header(0x1_0000_0000).unwrap_err();
+        assert_eq!(error.reason(), Reason::TooBig);
+        assert_eq!(format!("{}", error),
+                   "Overflowing value: str of 4294967296 bytes is over the str32 limit of 4294967295");
+
+        let error = bin_header(0x1_0000_0000).unwrap_err();
+        assert_eq!(error.reason(), Reason::TooBig);
+        assert!(format!("{}", error).contains("bin32"));
+    }
+
+    // a seq whose length hint is far more than it could hold
+    struct Boundless;
+
+    impl Serialize for Boundless {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+            where S: Serializer {
+            let state = try!(serializer.serialize_seq(Some(usize::max_value())));
+            serializer.serialize_seq_end(state)
+        }
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn array32_limit_test() {
+        let error = ::to_bytes(Boundless).unwrap_err();
+        assert_eq!(error.reason(), Reason::TooBig);
+        assert_eq!(format!("{}", error),
+                   format!("Overflowing value: array of {} elements is over the array32 limit of 4294967295",
+                           usize::max_value()));
+
+        let error = map_header(0x1_0000_0000).unwrap_err();
+        assert_eq!(error.reason(), Reason::TooBig);
+        assert!(format!("{}", error).contains("map32"));
+    }
+
+    // a map claiming one entry more than map32 holds
+    struct HugeMap;
+
+    impl Serialize for HugeMap {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+            where S: Serializer {
+            let state = try!(serializer.serialize_map(Some(0x1_0000_0000)));
+            serializer.serialize_map_end(state)
+        }
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn map32_limit_test() {
+        let error = ::to_bytes(HugeMap).unwrap_err();
+        assert_eq!(error.reason(), Reason::TooBig);
+        assert_eq!(format!("{}", error),
+                   "Overflowing value: map of 4294967296 entries is over the map32 limit of 4294967295");
+
+        // a map of unknown length is checked when it ends, without wrapping
+        let mut output = |_: &[u8]| Ok(());
+        let mut ser = ::Serializer::new(&mut output as &mut dyn FnMut(&[u8]) -> super::Result);
+        let mut state = ser.serialize_map(None).unwrap();
+        if let Some((ref mut size, _)) = state {
+            *size = 0x1_0000_0000;
+        }
+
+        let error = ser.serialize_map_end(state).unwrap_err();
+        assert_eq!(format!("{}", error),
+                   "Overflowing value: map of 4294967296 entries is over the map32 limit of 4294967295");
+    }
+
+    // a broken Serialize impl, writing count values
+    struct Values(u8);
+
+    impl Serialize for Values {
+        fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+            where S: Serializer {
+            for n in 0..self.0 {
+                try!(serializer.serialize_u8(n));
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "A map value was written as 0 values")]
+    fn empty_map_value_test() {
+        let mut map = BTreeMap::new();
+        map.insert("a", Values(0));
+        let _ = ::to_bytes(&map);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "A map value was written as 2 values")]
+    fn double_map_value_test() {
+        let mut map = BTreeMap::new();
+        map.insert("a", Values(2));
+        let _ = both(UnsizedMap(map));
+    }
+
+    #[test]
+    fn map_value_test() {
+        let mut map = BTreeMap::new();
+        map.insert("a", Values(1));
+        assert_eq!(::to_bytes(&map).unwrap(), &[0x81, 0xa1, 0x61, 0x00]);
+
+        // values holding others, of known and unknown length, are one value
+        let mut map = BTreeMap::new();
+        map.insert("a", Unsized(vec![vec![1u8], vec![]]));
+        map.insert("b", Unsized(vec![]));
+        assert_eq!(both(UnsizedMap(map)), &[0x82, 0xa1, 0x61, 0x92, 0x91, 0x01, 0x90, 0xa1, 0x62, 0x90]);
+
+        let config = SerializerConfig { compact_structs: true, ..SerializerConfig::new() };
+        let mut map = BTreeMap::new();
+        map.insert("s", Sparse { a: None, b: Some(1), c: None, d: None, e: None });
+        assert_eq!(both_with(&map, config), &[0x81, 0xa1, 0x73, 0x81, 0xa1, 0x62, 0x01]);
+    }
+
+    #[test]
+    fn always_f64_test() {
+        let config = SerializerConfig { always_f64: true, ..SerializerConfig::new() };
+        let bytes = ::to_bytes_with_config(1.5f32, config).unwrap();
+        assert_eq!(bytes, &[0xcb, 0x3f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(::from_bytes::<f32>(&bytes).unwrap(), 1.5);
+
+        // f64 and the default are unaffected
+        assert_eq!(::to_bytes_with_config(1.5f64, config).unwrap(), bytes);
+        assert_eq!(::to_bytes(1.5f32).unwrap(), &[0xca, 0x3f, 0xc0, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn max_chunk_test() {
+        let config = SerializerConfig { max_chunk: Some(16), ..SerializerConfig::new() };
+        let text: String = (0..100).map(|i| (b'a' + i % 26) as char).collect();
+
+        let (bytes, sizes) = chunks_with(text.as_str(), config);
+        assert!(sizes.iter().all(|&size| size <= 16));
+        assert_eq!(bytes, ::to_bytes(text.as_str()).unwrap());
+
+        // held back output is split up too when it is flushed
+        let value = Unsized(vec![text.clone(), text.clone()]);
+        let (bytes, sizes) = chunks_with(&value, config);
+        assert!(sizes.iter().all(|&size| size <= 16));
+        assert_eq!(bytes, both(&value));
+
+        // without a limit the body goes out in one piece
+        let (_, sizes) = chunks_with(text.as_str(), SerializerConfig::new());
+        assert!(sizes.contains(&100));
+    }
+
+    #[test]
+    fn map_from_iter_test() {
+        let pairs = [(Key(1), "a"), (Key(2), "bc")];
+        assert_eq!(both(PairMap(&pairs, 2)), &[0x82,
+                                                0x01, 0xa1, 0x61,
+                                                0x02, 0xa2, 0x62, 0x63]);
+
+        assert_eq!(::to_bytes(PairMap(&pairs, 1)).unwrap_err().reason(), Reason::BadValue);
+        assert_eq!(::to_bytes(PairMap(&pairs, 3)).unwrap_err().reason(), Reason::BadValue);
+    }
 }
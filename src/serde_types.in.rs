@@ -11,4 +11,209 @@ mod test_types {
         C(i8, i8),
         D { a: isize, b: String },
     }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub enum Event {
+        Start,
+        Stop,
+        Unknown,
+        Pause(u32),
+        Resize { w: u16, h: u16 },
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub enum Wide {
+        Eight(u8, u8, u8, u8, u8, u8, u8, u8),
+        Fourteen(u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8),
+        Fifteen(u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8, u8),
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub enum Fields {
+        Zero {},
+        One { a: u8 },
+        Three { a: u8, b: String, c: bool },
+    }
+
+    // an older version of Event
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub enum OldEvent {
+        Start,
+        Stop,
+        Unknown,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub enum Outer {
+        Wrap { inner: Inner, n: u8 },
+        Pair(Inner, Inner),
+        Empty,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub enum Inner {
+        Leaf { a: i8, b: String },
+        Nested { t: T },
+        Unit,
+    }
+
+    #[derive(PartialEq, Debug, Serialize, Deserialize)]
+    pub struct Envelope {
+        pub kind: String,
+        pub body: ::RawMessage,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct TypedEnvelope {
+        pub kind: String,
+        pub body: T,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Unit;
+
+    #[derive(PartialEq, Debug, Serialize, Deserialize)]
+    pub struct Sample {
+        pub id: u32,
+        pub ratio: f64,
+        pub tag: [u8; 4],
+        pub ok: bool,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Sparse {
+        pub a: Option<u8>,
+        pub b: Option<u8>,
+        pub c: Option<u8>,
+        pub d: Option<u8>,
+        pub e: Option<u8>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub enum Patch {
+        Clear,
+        Set { name: Option<String>, size: Option<u32> },
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Compact {
+        pub compact: bool,
+        pub schema: u8,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Patch {
+        #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "::option::deserialize")]
+        pub value: Option<Option<u8>>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Settings {
+        pub config: Config,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Config {
+        pub servers: Vec<Server>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Server {
+        pub host: String,
+        pub port: u16,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Packet {
+        pub id: u8,
+        pub payload: Vec<u8>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct KeyPair {
+        pub id: u8,
+        #[serde(serialize_with = "::array::serialize", deserialize_with = "::array::deserialize")]
+        pub key: [u8; 64],
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Table {
+        #[serde(serialize_with = "::array::serialize", deserialize_with = "::array::deserialize")]
+        pub values: [u32; 16],
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+    pub enum Priority {
+        Low = 10,
+        High = 20,
+        Never = -1,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Job {
+        pub id: u8,
+        #[serde(serialize_with = "::discriminant::serialize", deserialize_with = "::discriminant::deserialize")]
+        pub priority: Priority,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Timeout {
+        #[serde(serialize_with = "::duration::as_millis::serialize", deserialize_with = "::duration::as_millis::deserialize")]
+        pub after: ::std::time::Duration,
+        #[serde(serialize_with = "::duration::as_secs::serialize", deserialize_with = "::duration::as_secs::deserialize")]
+        pub every: ::std::time::Duration,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct BinPacket {
+        pub id: u8,
+        #[serde(serialize_with = "::bytes::serialize", deserialize_with = "::bytes::deserialize")]
+        pub payload: Vec<u8>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Listing {
+        pub version: u32,
+        pub kind: String,
+        pub items: Vec<u32>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Nested {
+        pub a: Leaf,
+        pub c: u8,
+    }
+
+    #[derive(Serialize)]
+    pub struct Wrapper<'a, T: 'a> {
+        pub name: &'a str,
+        pub items: &'a [T],
+        pub nested: Option<&'a Wrapper<'a, T>>,
+    }
+
+    #[derive(PartialEq, Debug, Serialize, Deserialize)]
+    pub struct Annotated {
+        pub id: u8,
+        pub note: Option<String>,
+        pub extra: ::Value,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Tagged {
+        pub id: u8,
+        pub marker: ::std::marker::PhantomData<u32>,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    pub struct Leaf {
+        pub b: u8,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct Renamed {
+        pub user_id: u32,
+        #[serde(rename = "ts")]
+        pub time_stamp: u64,
+    }
 }
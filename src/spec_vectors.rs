@@ -0,0 +1,153 @@
+//! Byte-exact checks against the examples in the MessagePack specification.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Each vector is encoded and checked against the spec's bytes, then decoded
+// back. corepack always picks the smallest format that fits, as the spec
+// recommends, so these are the only valid encodings it can produce.
+//
+// Where corepack goes beyond the spec:
+//
+// - structs are maps keyed by field name, not arrays of field values
+// - enums use a variant index, see EnumEncoding
+// - Vec<u8> is an array of integers; only Bytes and ByteBuf use bin
+// - ext values are read and written as Value::Ext, and application types can
+//   have a handler registered to decode them
+
+use std::fmt::Debug;
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::collections::btree_map::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+
+use bytes::{Bytes, ByteBuf};
+use test_types::Compact;
+use value::Value;
+
+fn vector<V>(value: V, expected: &[u8])
+    where V: Serialize + Deserialize + PartialEq + Debug {
+    assert_eq!(::to_bytes(&value).unwrap(), expected);
+
+    let actual: V = ::from_bytes(expected).unwrap();
+    assert_eq!(actual, value);
+}
+
+#[test]
+fn spec_example_test() {
+    // the example from the front page of msgpack.org
+    vector(Compact { compact: true, schema: 0 },
+           &[0x82,
+             0xa7, 0x63, 0x6f, 0x6d, 0x70, 0x61, 0x63, 0x74, 0xc3,
+             0xa6, 0x73, 0x63, 0x68, 0x65, 0x6d, 0x61, 0x00]);
+}
+
+#[test]
+fn nil_test() {
+    vector((), &[0xc0]);
+    vector(None::<u8>, &[0xc0]);
+}
+
+#[test]
+fn bool_test() {
+    vector(false, &[0xc2]);
+    vector(true, &[0xc3]);
+}
+
+#[test]
+fn uint_test() {
+    vector(0u8, &[0x00]);
+    vector(0x7fu8, &[0x7f]);
+    vector(0x80u8, &[0xcc, 0x80]);
+    vector(0x100u16, &[0xcd, 0x01, 0x00]);
+    vector(0x10000u32, &[0xce, 0x00, 0x01, 0x00, 0x00]);
+    vector(0x100000000u64, &[0xcf, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]);
+}
+
+#[test]
+fn int_test() {
+    vector(-1i8, &[0xff]);
+    vector(-32i8, &[0xe0]);
+    vector(-33i8, &[0xd0, 0xdf]);
+    vector(-129i16, &[0xd1, 0xff, 0x7f]);
+    vector(-32769i32, &[0xd2, 0xff, 0xff, 0x7f, 0xff]);
+    vector(-2147483649i64, &[0xd3, 0xff, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff]);
+}
+
+#[test]
+fn float_test() {
+    vector(1.5f32, &[0xca, 0x3f, 0xc0, 0x00, 0x00]);
+    vector(1.5f64, &[0xcb, 0x3f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+}
+
+#[test]
+fn str_test() {
+    vector(String::new(), &[0xa0]);
+    vector(String::from("a"), &[0xa1, 0x61]);
+
+    let long: String = (0..32).map(|_| 'a').collect();
+    let mut expected = vec![0xd9, 0x20];
+    expected.extend_from_slice(long.as_bytes());
+    vector(long, &expected);
+}
+
+#[test]
+fn bin_test() {
+    assert_eq!(::to_bytes(Bytes(&[])).unwrap(), &[0xc4, 0x00]);
+    vector(ByteBuf(vec![0x01, 0x02]), &[0xc4, 0x02, 0x01, 0x02]);
+}
+
+#[test]
+fn array_test() {
+    vector(Vec::<u8>::new(), &[0x90]);
+    vector(vec![1u8, 2, 3], &[0x93, 0x01, 0x02, 0x03]);
+
+    let long: Vec<u8> = (0..16).collect();
+    let mut expected = vec![0xdc, 0x00, 0x10];
+    expected.extend_from_slice(&long);
+    vector(long, &expected);
+}
+
+#[test]
+fn map_test() {
+    vector(BTreeMap::<u8, u8>::new(), &[0x80]);
+
+    let mut map = BTreeMap::new();
+    map.insert(1u8, true);
+    vector(map, &[0x81, 0x01, 0xc3]);
+
+    let map: BTreeMap<u8, u8> = (0..16).map(|i| (i, i)).collect();
+    let mut expected = vec![0xde, 0x00, 0x10];
+    for i in 0..16 {
+        expected.push(i);
+        expected.push(i);
+    }
+    vector(map, &expected);
+}
+
+#[test]
+fn ext_test() {
+    // fixext for data of 1, 2, 4, 8 or 16 bytes
+    vector(Value::Ext(1, vec![0x2a]), &[0xd4, 0x01, 0x2a]);
+    vector(Value::Ext(1, vec![0x01, 0x02]), &[0xd5, 0x01, 0x01, 0x02]);
+    vector(Value::Ext(1, vec![0x00; 4]), &[0xd6, 0x01, 0x00, 0x00, 0x00, 0x00]);
+    vector(Value::Ext(1, vec![0x00; 8]), &[0xd7, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+    let mut expected = vec![0xd8, 0x01];
+    expected.extend_from_slice(&[0x00; 16]);
+    vector(Value::Ext(1, vec![0x00; 16]), &expected);
+
+    // ext 8 for any other size up to 255, empty included
+    vector(Value::Ext(1, Vec::new()), &[0xc7, 0x00, 0x01]);
+    vector(Value::Ext(1, vec![0x01, 0x02, 0x03]), &[0xc7, 0x03, 0x01, 0x01, 0x02, 0x03]);
+
+    let mut expected = vec![0xc8, 0x01, 0x00, 0x01];
+    expected.extend_from_slice(&[0x00; 256]);
+    vector(Value::Ext(1, vec![0x00; 256]), &expected);
+
+    // the spec's timestamp 32, type -1, for 1970-01-01T00:00:01Z
+    vector(Value::Ext(-1, vec![0x00, 0x00, 0x00, 0x01]), &[0xd6, 0xff, 0x00, 0x00, 0x00, 0x01]);
+}
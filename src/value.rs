@@ -0,0 +1,410 @@
+//! A dynamically typed MessagePack value.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::vec::Vec;
+use alloc::string::String;
+
+use serde;
+
+use bytes::{Bytes, ByteBuf};
+use ser::ext_header;
+
+/// Newtype struct name a Value deserializes through, so the Deserializer can
+/// hand it ext values whole.
+pub const TOKEN: &'static str = "__corepack_Value";
+
+/// Newtype struct name the Deserializer's ext values are read back through.
+const EXT_TOKEN: &'static str = "__corepack_Value_Ext";
+
+/// Any MessagePack value, for data whose shape is not known ahead of time.
+///
+/// Integers keep the signedness they were encoded with, floats their width,
+/// and maps their entries in encoded order, duplicates and all.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// nil.
+    Nil,
+    /// true or false.
+    Bool(bool),
+    /// A signed integer.
+    Int(i64),
+    /// An unsigned integer.
+    UInt(u64),
+    /// A float32.
+    F32(f32),
+    /// A float64.
+    F64(f64),
+    /// A str.
+    String(String),
+    /// A bin.
+    Binary(Vec<u8>),
+    /// An array.
+    Array(Vec<Value>),
+    /// A map, as key and value pairs.
+    Map(Vec<(Value, Value)>),
+    /// An ext value, as its type and data.
+    Ext(i8, Vec<u8>)
+}
+
+struct ValueVisitor;
+
+struct ExtVisitor;
+
+impl serde::Serialize for Value {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer {
+        match *self {
+            Value::Nil => serializer.serialize_none(),
+            Value::Bool(v) => serializer.serialize_bool(v),
+            Value::Int(v) => serializer.serialize_i64(v),
+            Value::UInt(v) => serializer.serialize_u64(v),
+            Value::F32(v) => serializer.serialize_f32(v),
+            Value::F64(v) => serializer.serialize_f64(v),
+            Value::String(ref v) => serializer.serialize_str(v),
+            Value::Binary(ref v) => serializer.serialize_bytes(v),
+            Value::Array(ref v) => {
+                let mut state = try!(serializer.serialize_seq(Some(v.len())));
+                for item in v.iter() {
+                    try!(serializer.serialize_seq_elt(&mut state, item));
+                }
+                serializer.serialize_seq_end(state)
+            }
+            Value::Map(ref v) => {
+                let mut state = try!(serializer.serialize_map(Some(v.len())));
+                for &(ref key, ref value) in v.iter() {
+                    try!(serializer.serialize_map_key(&mut state, key));
+                    try!(serializer.serialize_map_value(&mut state, value));
+                }
+                serializer.serialize_map_end(state)
+            }
+            Value::Ext(ty, ref data) => {
+                // written out whole, the same way a RawMessage is
+                let (header, len) = try!(ext_header(ty, data.len()).map_err(|_| {
+                    <S::Error as serde::ser::Error>::invalid_value("Ext data too long")
+                }));
+                let mut encoded = Vec::with_capacity(len + data.len());
+                encoded.extend_from_slice(&header[..len]);
+                encoded.extend_from_slice(data);
+
+                serializer.serialize_newtype_struct(::raw::TOKEN, Bytes(encoded.as_slice()))
+            }
+        }
+    }
+}
+
+impl serde::de::Visitor for ValueVisitor {
+    type Value = Value;
+
+    fn visit_bool<E>(&mut self, v: bool) -> Result<Value, E>
+        where E: serde::de::Error {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i64<E>(&mut self, v: i64) -> Result<Value, E>
+        where E: serde::de::Error {
+        Ok(Value::Int(v))
+    }
+
+    fn visit_u64<E>(&mut self, v: u64) -> Result<Value, E>
+        where E: serde::de::Error {
+        Ok(Value::UInt(v))
+    }
+
+    fn visit_f32<E>(&mut self, v: f32) -> Result<Value, E>
+        where E: serde::de::Error {
+        Ok(Value::F32(v))
+    }
+
+    fn visit_f64<E>(&mut self, v: f64) -> Result<Value, E>
+        where E: serde::de::Error {
+        Ok(Value::F64(v))
+    }
+
+    fn visit_str<E>(&mut self, v: &str) -> Result<Value, E>
+        where E: serde::de::Error {
+        Ok(Value::String(v.into()))
+    }
+
+    fn visit_string<E>(&mut self, v: String) -> Result<Value, E>
+        where E: serde::de::Error {
+        Ok(Value::String(v))
+    }
+
+    fn visit_bytes<E>(&mut self, v: &[u8]) -> Result<Value, E>
+        where E: serde::de::Error {
+        Ok(Value::Binary(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(&mut self, v: Vec<u8>) -> Result<Value, E>
+        where E: serde::de::Error {
+        Ok(Value::Binary(v))
+    }
+
+    fn visit_unit<E>(&mut self) -> Result<Value, E>
+        where E: serde::de::Error {
+        Ok(Value::Nil)
+    }
+
+    fn visit_none<E>(&mut self) -> Result<Value, E>
+        where E: serde::de::Error {
+        Ok(Value::Nil)
+    }
+
+    fn visit_some<D>(&mut self, deserializer: &mut D) -> Result<Value, D::Error>
+        where D: serde::Deserializer {
+        serde::Deserialize::deserialize(deserializer)
+    }
+
+    fn visit_newtype_struct<D>(&mut self, deserializer: &mut D) -> Result<Value, D::Error>
+        where D: serde::Deserializer {
+        // only corepack's ext values come through here; anything else is
+        // passed back through as an ordinary value
+        deserializer.deserialize_newtype_struct(EXT_TOKEN, ExtVisitor)
+    }
+
+    fn visit_seq<V>(&mut self, mut visitor: V) -> Result<Value, V::Error>
+        where V: serde::de::SeqVisitor {
+        let mut values = Vec::with_capacity(visitor.size_hint().0);
+        while let Some(value) = try!(visitor.visit()) {
+            values.push(value);
+        }
+        try!(visitor.end());
+
+        Ok(Value::Array(values))
+    }
+
+    fn visit_map<V>(&mut self, mut visitor: V) -> Result<Value, V::Error>
+        where V: serde::de::MapVisitor {
+        let mut entries = Vec::with_capacity(visitor.size_hint().0);
+        while let Some(key) = try!(visitor.visit_key()) {
+            let value = try!(visitor.visit_value());
+            entries.push((key, value));
+        }
+        try!(visitor.end());
+
+        Ok(Value::Map(entries))
+    }
+}
+
+impl serde::de::Visitor for ExtVisitor {
+    type Value = Value;
+
+    fn visit_seq<V>(&mut self, mut visitor: V) -> Result<Value, V::Error>
+        where V: serde::de::SeqVisitor {
+        let ty: Option<i8> = try!(visitor.visit());
+        let data: Option<ByteBuf> = try!(visitor.visit());
+        try!(visitor.end());
+
+        match (ty, data) {
+            (Some(ty), Some(data)) => Ok(Value::Ext(ty, data.0)),
+            _ => Err(serde::de::Error::end_of_stream())
+        }
+    }
+
+    fn visit_newtype_struct<D>(&mut self, deserializer: &mut D) -> Result<Value, D::Error>
+        where D: serde::Deserializer {
+        deserializer.deserialize(ValueVisitor)
+    }
+}
+
+impl serde::Deserialize for Value {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Value, D::Error>
+        where D: serde::Deserializer {
+        deserializer.deserialize_newtype_struct(TOKEN, ValueVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+
+    use error::{Error, Reason};
+    use de::DeserializerConfig;
+    use super::Value;
+
+    fn round_trip(value: Value, expected: &[u8]) {
+        let bytes = ::to_bytes(&value).unwrap();
+        assert_eq!(bytes, expected);
+
+        let actual: Value = ::from_bytes(bytes.as_slice()).unwrap();
+        assert_eq!(actual, value);
+    }
+
+    #[test]
+    fn scalar_test() {
+        round_trip(Value::Nil, &[0xc0]);
+        round_trip(Value::Bool(true), &[0xc3]);
+        round_trip(Value::UInt(200), &[0xcc, 0xc8]);
+        round_trip(Value::Int(-2), &[0xfe]);
+        round_trip(Value::F32(1.5), &[0xca, 0x3f, 0xc0, 0x00, 0x00]);
+        round_trip(Value::F64(1.5), &[0xcb, 0x3f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        round_trip(Value::String("hi".into()), &[0xa2, 0x68, 0x69]);
+        round_trip(Value::Binary(vec![1, 2]), &[0xc4, 0x02, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn collection_test() {
+        round_trip(Value::Array(vec![Value::UInt(1), Value::Nil, Value::String("a".into())]),
+                   &[0x93, 0x01, 0xc0, 0xa1, 0x61]);
+        round_trip(Value::Map(vec![(Value::String("k".into()), Value::Array(Vec::new())),
+                                   (Value::UInt(1), Value::Bool(false))]),
+                   &[0x82, 0xa1, 0x6b, 0x90, 0x01, 0xc2]);
+    }
+
+    #[test]
+    fn heterogeneous_vec_test() {
+        // [1, "two", [3, 4], {"k": 5}]
+        let bytes = [0x94, 0x01, 0xa3, 0x74, 0x77, 0x6f, 0x92, 0x03, 0x04, 0x81, 0xa1, 0x6b, 0x05];
+
+        let values: Vec<Value> = ::from_bytes(&bytes).unwrap();
+        assert_eq!(values, vec![Value::UInt(1),
+                                Value::String("two".into()),
+                                Value::Array(vec![Value::UInt(3), Value::UInt(4)]),
+                                Value::Map(vec![(Value::String("k".into()), Value::UInt(5))])]);
+
+        assert_eq!(::to_bytes(&values).unwrap(), &bytes);
+    }
+
+    #[test]
+    fn ext_test() {
+        round_trip(Value::Ext(5, vec![0xaa]), &[0xd4, 0x05, 0xaa]);
+        round_trip(Value::Ext(-1, vec![0; 3]), &[0xc7, 0x03, 0xff, 0x00, 0x00, 0x00]);
+
+        // nested ext values are kept whole too
+        round_trip(Value::Array(vec![Value::Ext(1, vec![1, 2])]), &[0x91, 0xd5, 0x01, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn empty_ext_test() {
+        round_trip(Value::Ext(7, Vec::new()), &[0xc7, 0x00, 0x07]);
+
+        // followed by another value, to catch reading into the next one
+        round_trip(Value::Array(vec![Value::Ext(7, Vec::new()), Value::UInt(1)]),
+                   &[0x92, 0xc7, 0x00, 0x07, 0x01]);
+
+        // and through a handler, which gets an empty slice
+        let mut config = DeserializerConfig::new();
+        config.register_ext(7, decode_empty).unwrap();
+        let value: (Option<u8>, u8) = ::from_bytes_with_config(&[0x92, 0xc7, 0x00, 0x07, 0x01], config).unwrap();
+        assert_eq!(value, (None, 1));
+    }
+
+    fn decode_empty(data: &[u8]) -> Result<Value, Error> {
+        if data.is_empty() {
+            Ok(Value::Nil)
+        } else {
+            Err(Error::simple(Reason::BadLength))
+        }
+    }
+
+    // a made-up point type, two big-endian u16s
+    fn decode_point(data: &[u8]) -> Result<Value, Error> {
+        if data.len() != 4 {
+            return Err(Error::simple(Reason::BadLength));
+        }
+
+        let x = (data[0] as u64) << 8 | data[1] as u64;
+        let y = (data[2] as u64) << 8 | data[3] as u64;
+        Ok(Value::Array(vec![Value::UInt(x), Value::UInt(y)]))
+    }
+
+    #[test]
+    fn ext_registry_test() {
+        let mut config = DeserializerConfig::new();
+        config.register_ext(42, decode_point).unwrap();
+
+        // ["p", ext 42 (1, 2), ext 7 (0xff)]
+        let bytes = [0x93, 0xa1, 0x70,
+                     0xd6, 0x2a, 0x00, 0x01, 0x00, 0x02,
+                     0xd4, 0x07, 0xff];
+
+        let value: Value = ::from_bytes_with_config(&bytes, config).unwrap();
+        assert_eq!(value, Value::Array(vec![Value::String("p".into()),
+                                            Value::Array(vec![Value::UInt(1), Value::UInt(2)]),
+                                            Value::Ext(7, vec![0xff])]));
+
+        // the handler's output decodes into any matching type
+        let value: (char, (u16, u16), Value) = ::from_bytes_with_config(&bytes, config).unwrap();
+        assert_eq!(value, ('p', (1, 2), Value::Ext(7, vec![0xff])));
+
+        // without the handler, the ext is kept as is
+        let value: Value = ::from_bytes(&bytes).unwrap();
+        assert_eq!(value, Value::Array(vec![Value::String("p".into()),
+                                            Value::Ext(42, vec![0x00, 0x01, 0x00, 0x02]),
+                                            Value::Ext(7, vec![0xff])]));
+
+        // configs compare by the handlers registered, not the order
+        let mut other = DeserializerConfig::new();
+        assert!(other != config);
+        other.register_ext(7, decode_empty).unwrap();
+        other.register_ext(42, decode_point).unwrap();
+        config.register_ext(7, decode_empty).unwrap();
+        assert_eq!(other, config);
+        other.register_ext(7, decode_wrapped).unwrap();
+        assert!(other != config);
+    }
+
+    // a made-up wrapper type, holding an ext 7 of its own
+    fn decode_wrapped(_: &[u8]) -> Result<Value, Error> {
+        Ok(Value::Array(vec![Value::Ext(7, Vec::new())]))
+    }
+
+    // never gets anywhere, always turning into another of itself
+    fn decode_itself(_: &[u8]) -> Result<Value, Error> {
+        Ok(Value::Ext(9, Vec::new()))
+    }
+
+    #[test]
+    fn ext_handler_config_test() {
+        let point = [0xd6, 0x2a, 0x00, 0x01, 0x00, 0x02];
+        let mut config = DeserializerConfig::new();
+        config.register_ext(42, decode_point).unwrap();
+
+        // the handler's output is read under the same strictness
+        let value: (f32, f32) = ::from_bytes_with_config(&point, config).unwrap();
+        assert_eq!(value, (1.0, 2.0));
+        let strict = DeserializerConfig { strict_types: true, ..config };
+        let value: Result<(f32, f32), Error> = ::from_bytes_with_config(&point, strict);
+        assert_eq!(value.unwrap_err().reason(), Reason::BadType);
+
+        // and counts against the same budget, the 4 bytes of ext data and
+        // then the 2 elements of the array
+        let limited = DeserializerConfig { max_total_len: Some(6), ..config };
+        let value: (u16, u16) = ::from_bytes_with_config(&point, limited).unwrap();
+        assert_eq!(value, (1, 2));
+        let limited = DeserializerConfig { max_total_len: Some(5), ..config };
+        let value: Result<(u16, u16), Error> = ::from_bytes_with_config(&point, limited);
+        assert_eq!(value.unwrap_err().reason(), Reason::LimitExceeded);
+
+        // ext values inside it are handled by the registry too
+        config.register_ext(7, decode_empty).unwrap();
+        config.register_ext(8, decode_wrapped).unwrap();
+        let value: (Option<u8>,) = ::from_bytes_with_config(&[0xd4, 0x08, 0x00], config).unwrap();
+        assert_eq!(value, (None,));
+
+        // other ext values are still something, with a registry or without
+        let value: Option<Value> = ::from_bytes_with_config(&[0xd4, 0x05, 0xaa], config).unwrap();
+        assert_eq!(value, Some(Value::Ext(5, vec![0xaa])));
+        let value: Option<Value> = ::from_bytes(&[0xd4, 0x05, 0xaa]).unwrap();
+        assert_eq!(value, Some(Value::Ext(5, vec![0xaa])));
+    }
+
+    #[test]
+    fn ext_handler_error_test() {
+        let mut config = DeserializerConfig::new();
+        config.register_ext(42, decode_point).unwrap();
+
+        let value: Result<Value, Error> = ::from_bytes_with_config(&[0xd4, 0x2a, 0x00], config);
+        assert_eq!(value.unwrap_err().reason(), Reason::BadLength);
+
+        // a handler that keeps going through the registry is cut off
+        config.register_ext(9, decode_itself).unwrap();
+        let value: Result<Value, Error> = ::from_bytes_with_config(&[0xd4, 0x09, 0x00], config);
+        assert_eq!(value.unwrap_err().reason(), Reason::LimitExceeded);
+        let value: Result<Option<Value>, Error> = ::from_bytes_with_config(&[0xd4, 0x09, 0x00], config);
+        assert_eq!(value.unwrap_err().reason(), Reason::LimitExceeded);
+    }
+}